@@ -0,0 +1,1013 @@
+//! Assembler for the Octo language, the assembly language of the Octo
+//! CHIP 8 / SUPER-CHIP / XO-CHIP development environment.
+//!
+//! Supported : labels (': name'), ':const', ':alias', ':macro', ':calc'
+//! (Octo expressions, evaluated right to left), ':org', ':byte', ':call',
+//! ':unpack', ':next', the 'if ... then', 'if ... begin ... else ... end'
+//! conditionals, the 'loop ... while ... again' loops and every CHIP 8,
+//! SUPER-CHIP and XO-CHIP statement. ':breakpoint' and ':monitor' are
+//! accepted and ignored.
+//!
+//! As in Octo, the program starts with a jump to the 'main' label, left out
+//! when 'main' is the very first thing in the program.
+
+use crate::disasm::Instruction;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+/// Address where the programs are loaded.
+const ORIGIN: usize = 0x200;
+
+/// Maximum number of macro expansions, catching the recursive macros.
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// An assembly error, at the given 1-based line and column of the source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/// An assembled program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    /// The ROM content, to be loaded at 0x200.
+    pub rom: Vec<u8>,
+    /// The address of every label of the program.
+    pub symbols: BTreeMap<String, u16>,
+}
+
+/// Assemble the given Octo source code.
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    let mut assembler = Assembler::new(tokenize(source));
+    assembler.run()?;
+    assembler.finish()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            line: self.line,
+            column: self.column,
+            message,
+        }
+    }
+}
+
+/// Split the source in whitespace-separated tokens, dropping the comments.
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let mut chars = line.char_indices().peekable();
+        while let Some(&(start, c)) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+                continue;
+            }
+            if c == '#' {
+                break;
+            }
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token {
+                text: line[start..end].to_string(),
+                line: index + 1,
+                column: line[..start].chars().count() + 1,
+            });
+        }
+    }
+    tokens
+}
+
+/// Parse an Octo numeric literal : decimal, '0x' hexadecimal or '0b'
+/// binary, optionally negative.
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<f64>().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Return the index of the given 'vX' register name.
+fn parse_register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(c), None) | (Some('V'), Some(c), None) => c.to_digit(16).map(|r| r as u8),
+        _ => None,
+    }
+}
+
+/// The way a forward reference to a label is patched once it is known.
+#[derive(Copy, Clone, Debug)]
+enum FixupKind {
+    /// The 12 bits address of a 2 bytes long instruction.
+    Address,
+    /// The 16 bits address of the long I load.
+    Long,
+    /// The low nibble of the high byte of the address, below a constant one.
+    UnpackHigh,
+    /// The low byte of the address.
+    UnpackLow,
+}
+
+struct Fixup {
+    address: usize,
+    kind: FixupKind,
+    token: Token,
+}
+
+/// An open control structure.
+enum Block {
+    /// 'if ... begin', with the address of the jump over its body.
+    If { jump: usize, token: Token },
+    /// 'else', with the address of the jump over its body.
+    Else { jump: usize, token: Token },
+    /// 'loop', with its start address and the jumps out of the loop of its
+    /// 'while' statements.
+    Loop { start: usize, exits: Vec<usize>, token: Token },
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Assembler {
+    tokens: Vec<Token>,
+    pos: usize,
+    memory: Vec<u8>,
+    here: usize,
+    /// End of the assembled program, exclusive.
+    end: usize,
+    /// Whether the program starts with a jump to 'main'.
+    main_jump: bool,
+    labels: HashMap<String, u16>,
+    consts: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<Block>,
+    expansions: usize,
+}
+
+impl Assembler {
+    fn new(tokens: Vec<Token>) -> Assembler {
+        Assembler {
+            tokens,
+            pos: 0,
+            memory: vec![0; 0x10000],
+            // room for the jump to 'main'
+            here: ORIGIN + 2,
+            end: ORIGIN + 2,
+            main_jump: true,
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => {
+                let (line, column) = match self.tokens.last() {
+                    Some(last) => (last.line, last.column + last.text.chars().count()),
+                    None => (1, 1),
+                };
+                Err(AsmError {
+                    line,
+                    column,
+                    message: "unexpected end of file".to_string(),
+                })
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|token| &token.text[..])
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(token.error(format!("expected '{}', found '{}'", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    fn emit_byte(&mut self, token: &Token, byte: u8) -> Result<(), AsmError> {
+        if self.here >= self.memory.len() {
+            return Err(token.error("the program doesn't fit in the 64 KiB of memory".to_string()));
+        }
+        self.memory[self.here] = byte;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn emit(&mut self, token: &Token, instruction: Instruction) -> Result<(), AsmError> {
+        for word in instruction.encode() {
+            self.emit_byte(token, (word >> 8) as u8)?;
+            self.emit_byte(token, word as u8)?;
+        }
+        Ok(())
+    }
+
+    fn write_word(&mut self, address: usize, word: u16) {
+        self.memory[address] = (word >> 8) as u8;
+        self.memory[address + 1] = word as u8;
+    }
+
+    fn read_word(&self, address: usize) -> u16 {
+        (self.memory[address] as u16) << 8 | self.memory[address + 1] as u16
+    }
+
+    /// Point the jump at the given address to 'target'.
+    fn patch_jump(&mut self, token: &Token, address: usize, target: usize) -> Result<(), AsmError> {
+        if target > 0xFFF {
+            return Err(token.error(format!("the jump target {:#06X} is out of the 12 bits range", target)));
+        }
+        self.write_word(address, 0x1000 | target as u16);
+        Ok(())
+    }
+
+    /// Return the value of a label or constant name, if defined.
+    fn lookup(&self, name: &str) -> Option<f64> {
+        match self.consts.get(name) {
+            Some(&value) => Some(value),
+            None => self.labels.get(name).map(|&address| address as f64),
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        self.register_of(&token)
+    }
+
+    fn register_of(&self, token: &Token) -> Result<u8, AsmError> {
+        match parse_register(&token.text).or_else(|| self.aliases.get(&token.text).cloned()) {
+            Some(register) => Ok(register),
+            None => Err(token.error(format!("expected a register, found '{}'", token.text))),
+        }
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        parse_register(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    /// Read a value : a number, a defined name or a '{ expression }'.
+    fn value(&mut self) -> Result<(Token, f64), AsmError> {
+        let token = self.next()?;
+        if token.text == "{" {
+            let value = self.calc_block()?;
+            return Ok((token, value));
+        }
+        let value = match parse_number(&token.text).or_else(|| self.lookup(&token.text)) {
+            Some(value) => value,
+            None => return Err(token.error(format!("undefined name '{}'", token.text))),
+        };
+        Ok((token, value))
+    }
+
+    /// Read a value and check it fits in the given range.
+    fn value_in(&mut self, min: i64, max: i64) -> Result<i64, AsmError> {
+        let (token, value) = self.value()?;
+        let value = value.floor() as i64;
+        if value < min || value > max {
+            return Err(token.error(format!("the value {} is out of the range {}..={}", value, min, max)));
+        }
+        Ok(value)
+    }
+
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        Ok(self.value_in(0, 15)? as u8)
+    }
+
+    /// Read a byte value, the negative values being two's complement.
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        Ok(self.value_in(-128, 255)? as u8)
+    }
+
+    /// Read an address, possibly a label defined further down, and return
+    /// it or 0 after recording a fixup of the given kind at 'here'.
+    fn address(&mut self, kind: FixupKind) -> Result<u16, AsmError> {
+        let token = match self.tokens.get(self.pos) {
+            Some(token) => token.clone(),
+            None => return Err(self.next().unwrap_err()),
+        };
+        let defined = token.text == "{" || parse_number(&token.text).is_some() || self.lookup(&token.text).is_some();
+        if defined {
+            let max = match kind {
+                FixupKind::Address | FixupKind::UnpackHigh => 0xFFF,
+                FixupKind::Long | FixupKind::UnpackLow => 0xFFFF,
+            };
+            return Ok(self.value_in(0, max)? as u16);
+        }
+        self.pos += 1;
+        if self.is_register(&token.text) || token.text.starts_with(':') {
+            return Err(token.error(format!("expected an address, found '{}'", token.text)));
+        }
+        self.fixups.push(Fixup {
+            address: self.here,
+            kind,
+            token,
+        });
+        Ok(0)
+    }
+
+    /// Read the name of a new label or constant.
+    fn new_name(&mut self) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if parse_number(&token.text).is_some() || self.is_register(&token.text) || token.text == "{" {
+            return Err(token.error(format!("'{}' is not a valid name", token.text)));
+        }
+        if self.labels.contains_key(&token.text) || self.consts.contains_key(&token.text) {
+            return Err(token.error(format!("the name '{}' is already defined", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn define_label(&mut self, token: &Token, address: usize) -> Result<(), AsmError> {
+        if address > 0xFFFF {
+            return Err(token.error("the label is past the end of the memory".to_string()));
+        }
+        self.labels.insert(token.text.clone(), address as u16);
+        Ok(())
+    }
+
+    /// Read the tokens of a '{ ... }' block whose opening brace was read,
+    /// nested blocks included.
+    fn braced(&mut self) -> Result<Vec<Token>, AsmError> {
+        let mut depth = 1;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next()?;
+            match &token.text[..] {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(tokens);
+                    }
+                }
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    /// Evaluate a '{ expression }' whose opening brace was read.
+    fn calc_block(&mut self) -> Result<f64, AsmError> {
+        let open = self.tokens[self.pos - 1].clone();
+        let tokens = self.braced()?;
+        if tokens.is_empty() {
+            return Err(open.error("empty expression".to_string()));
+        }
+        let mut pos = 0;
+        let value = self.calc_expr(&tokens, &mut pos)?;
+        match tokens.get(pos) {
+            Some(token) => Err(token.error(format!("unexpected '{}' in the expression", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    /// Octo expressions : the binary operators have no precedence and are
+    /// evaluated right to left, unless parenthesized.
+    fn calc_expr(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, AsmError> {
+        let left = self.calc_term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(token) if token.text != ")" => token.clone(),
+            _ => return Ok(left),
+        };
+        *pos += 1;
+        let right = self.calc_expr(tokens, pos)?;
+        let bool_value = |b: bool| if b { 1.0 } else { 0.0 };
+        let int = |v: f64| v as i64;
+        Ok(match &op.text[..] {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (int(left) & int(right)) as f64,
+            "|" => (int(left) | int(right)) as f64,
+            "^" => (int(left) ^ int(right)) as f64,
+            "<<" => (int(left) << (int(right) & 63)) as f64,
+            ">>" => (int(left) >> (int(right) & 63)) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => bool_value(left < right),
+            ">" => bool_value(left > right),
+            "<=" => bool_value(left <= right),
+            ">=" => bool_value(left >= right),
+            "==" => bool_value(left == right),
+            "!=" => bool_value(left != right),
+            _ => return Err(op.error(format!("unknown operator '{}'", op.text))),
+        })
+    }
+
+    fn calc_term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, AsmError> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token,
+            None => {
+                let last = &tokens[tokens.len() - 1];
+                return Err(last.error("missing operand at the end of the expression".to_string()));
+            }
+        };
+        *pos += 1;
+        let unary: Option<fn(f64) -> f64> = match &token.text[..] {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| if v == 0.0 { 1.0 } else { 0.0 }),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(unary) = unary {
+            return Ok(unary(self.calc_term(tokens, pos)?));
+        }
+        match &token.text[..] {
+            "(" => {
+                let value = self.calc_expr(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(close) if close.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(token.error("unclosed parenthesis".to_string())),
+                }
+            }
+            "@" => {
+                let address = self.calc_term(tokens, pos)? as i64;
+                match self.memory.get(address as usize) {
+                    Some(&byte) if address >= 0 => Ok(byte as f64),
+                    _ => Err(token.error(format!("the address {} is out of the memory", address))),
+                }
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            text => match parse_number(text).or_else(|| self.lookup(text)) {
+                Some(value) => Ok(value),
+                None => Err(token.error(format!("undefined name '{}'", text))),
+            },
+        }
+    }
+
+    /// Assemble a condition, so that the next instruction is executed when
+    /// it holds, or when it doesn't if 'negated'.
+    fn condition(&mut self, negated: bool) -> Result<(), AsmError> {
+        use crate::disasm::Instruction::*;
+
+        let x = self.register()?;
+        let op = self.next()?;
+        match &op.text[..] {
+            // the skip instructions skip when the condition fails
+            "key" => return self.emit(&op, if negated { SkipKeyPressed(x) } else { SkipKeyReleased(x) }),
+            "-key" => return self.emit(&op, if negated { SkipKeyReleased(x) } else { SkipKeyPressed(x) }),
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {}
+            _ => return Err(op.error(format!("unknown comparison '{}'", op.text))),
+        }
+        let rhs = match self.peek() {
+            Some(text) if self.is_register(text) => Ok(self.register()?),
+            _ => Err(self.byte()?),
+        };
+        let equal = match &op.text[..] {
+            "==" => !negated,
+            "!=" => negated,
+            _ => {
+                // compare through VF, holding the borrow of a subtraction :
+                // 'x < y' and 'x >= y' from x - y, 'x > y' and 'x <= y' from y - x
+                let subtract_from_x = op.text == "<" || op.text == ">=";
+                let instructions = match (rhs, subtract_from_x) {
+                    (Ok(y), true) => [Move(0xF, x), Sub(0xF, y)],
+                    (Ok(y), false) => [Move(0xF, y), Sub(0xF, x)],
+                    (Err(n), true) => [LoadImm(0xF, n), SubReverse(0xF, x)],
+                    (Err(n), false) => [LoadImm(0xF, n), Sub(0xF, x)],
+                };
+                for instruction in &instructions {
+                    self.emit(&op, *instruction)?;
+                }
+                // VF is 0 on a borrow, i.e. when '<' or '>' holds
+                let strict = op.text == "<" || op.text == ">";
+                let holds_on_zero = strict != negated;
+                return self.emit(&op, if holds_on_zero { SkipNeImm(0xF, 0) } else { SkipEqImm(0xF, 0) });
+            }
+        };
+        let instruction = match (rhs, equal) {
+            (Ok(y), true) => SkipNeReg(x, y),
+            (Ok(y), false) => SkipEqReg(x, y),
+            (Err(n), true) => SkipNeImm(x, n),
+            (Err(n), false) => SkipEqImm(x, n),
+        };
+        self.emit(&op, instruction)
+    }
+
+    /// Assemble the whole token stream.
+    fn run(&mut self) -> Result<(), AsmError> {
+        while self.pos < self.tokens.len() {
+            let token = self.next()?;
+            self.statement(token)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        use crate::disasm::Instruction::*;
+
+        match &token.text[..] {
+            ":" => {
+                let name = self.new_name()?;
+                if name.text == "main" && self.main_jump && self.here == ORIGIN + 2 && self.end == ORIGIN + 2 {
+                    // 'main' comes first, no need to jump to it
+                    self.main_jump = false;
+                    self.here = ORIGIN;
+                    self.end = ORIGIN;
+                }
+                let here = self.here;
+                self.define_label(&name, here)?;
+            }
+            ":next" => {
+                let name = self.new_name()?;
+                let here = self.here + 1;
+                self.define_label(&name, here)?;
+            }
+            ":const" => {
+                let name = self.new_name()?;
+                let (_, value) = self.value()?;
+                self.consts.insert(name.text, value);
+            }
+            ":calc" => {
+                let name = self.new_name()?;
+                self.expect("{")?;
+                let value = self.calc_block()?;
+                self.consts.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.register()?;
+                self.aliases.insert(name.text, register);
+            }
+            ":macro" => {
+                let name = self.next()?;
+                let mut args = Vec::new();
+                loop {
+                    let arg = self.next()?;
+                    if arg.text == "{" {
+                        break;
+                    }
+                    args.push(arg.text);
+                }
+                let body = self.braced()?;
+                self.macros.insert(name.text, Macro { args, body });
+            }
+            ":org" => {
+                let address = self.value_in(0, 0xFFFF)? as usize;
+                if address < ORIGIN {
+                    return Err(token.error(format!("the address {:#05X} is below the program space", address)));
+                }
+                self.here = address;
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit_byte(&token, byte)?;
+            }
+            ":call" => {
+                let address = self.address(FixupKind::Address)?;
+                self.emit(&token, Call(address))?;
+            }
+            ":unpack" => {
+                let nibble = self.nibble()?;
+                let fixups = self.fixups.len();
+                let address = self.address(FixupKind::UnpackHigh)?;
+                self.emit(&token, LoadImm(0, nibble << 4 | (address >> 8) as u8))?;
+                if self.fixups.len() > fixups {
+                    // the same label for the low byte
+                    let label = self.fixups[fixups].token.clone();
+                    self.fixups.push(Fixup {
+                        address: self.here,
+                        kind: FixupKind::UnpackLow,
+                        token: label,
+                    });
+                }
+                self.emit(&token, LoadImm(1, address as u8))?;
+            }
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.value()?;
+            }
+            ";" | "return" => self.emit(&token, Return)?,
+            "clear" => self.emit(&token, Clear)?,
+            "exit" => self.emit(&token, Exit)?,
+            "lores" => self.emit(&token, Lores)?,
+            "hires" => self.emit(&token, Hires)?,
+            "scroll-left" => self.emit(&token, ScrollLeft)?,
+            "scroll-right" => self.emit(&token, ScrollRight)?,
+            "audio" => self.emit(&token, Audio)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(&token, ScrollDown(n))?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(&token, ScrollUp(n))?;
+            }
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(&token, Plane(n))?;
+            }
+            "jump" => {
+                let address = self.address(FixupKind::Address)?;
+                self.emit(&token, Jump(address))?;
+            }
+            "jump0" => {
+                let address = self.address(FixupKind::Address)?;
+                self.emit(&token, JumpOffset(address))?;
+            }
+            "native" => {
+                let address = self.address(FixupKind::Address)?;
+                self.emit(&token, Sys(address))?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(&token, Draw(x, y, n))?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let instruction = if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    if token.text == "save" { SaveRange(x, y) } else { LoadRange(x, y) }
+                } else if token.text == "save" {
+                    Store(x)
+                } else {
+                    Load(x)
+                };
+                self.emit(&token, instruction)?;
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(&token, SaveFlags(x))?;
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(&token, LoadFlags(x))?;
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(&token, Bcd(x))?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let instruction = match &token.text[..] {
+                    "delay" => SetDelay(x),
+                    "buzzer" => SetSound(x),
+                    _ => Pitch(x),
+                };
+                self.emit(&token, instruction)?;
+            }
+            "i" => {
+                let op = self.next()?;
+                match &op.text[..] {
+                    "+=" => {
+                        let x = self.register()?;
+                        self.emit(&token, AddI(x))?;
+                    }
+                    ":=" => match self.peek() {
+                        Some("hex") | Some("bighex") => {
+                            let big = self.next()?.text == "bighex";
+                            let x = self.register()?;
+                            self.emit(&token, if big { BigFont(x) } else { Font(x) })?;
+                        }
+                        Some("long") => {
+                            self.next()?;
+                            // the fixup points at the operand, after the F000 word
+                            self.here += 2;
+                            let address = self.address(FixupKind::Long);
+                            self.here -= 2;
+                            self.emit(&token, LoadILong(address?))?;
+                        }
+                        _ => {
+                            let address = self.address(FixupKind::Address)?;
+                            self.emit(&token, LoadI(address))?;
+                        }
+                    },
+                    _ => return Err(op.error(format!("expected ':=' or '+=', found '{}'", op.text))),
+                }
+            }
+            "if" => {
+                let next = self.tokens.get(self.pos).cloned();
+                let end = self.tokens[self.pos..].iter().position(|t| t.text == "then" || t.text == "begin");
+                let begin = end.is_some_and(|end| self.tokens[self.pos + end].text == "begin");
+                self.condition(begin)?;
+                let keyword = self.next()?;
+                match &keyword.text[..] {
+                    "then" => {}
+                    "begin" => {
+                        let jump = self.here;
+                        self.emit(&keyword, Jump(0))?;
+                        self.blocks.push(Block::If { jump, token: next.unwrap_or(token) });
+                    }
+                    _ => return Err(keyword.error(format!("expected 'then' or 'begin', found '{}'", keyword.text))),
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) => {
+                    let over = self.here;
+                    self.emit(&token, Jump(0))?;
+                    let here = self.here;
+                    self.patch_jump(&token, jump, here)?;
+                    self.blocks.push(Block::Else { jump: over, token });
+                }
+                _ => return Err(token.error("'else' without a matching 'if ... begin'".to_string())),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If { jump, .. }) | Some(Block::Else { jump, .. }) => {
+                    let here = self.here;
+                    self.patch_jump(&token, jump, here)?;
+                }
+                _ => return Err(token.error("'end' without a matching 'if ... begin'".to_string())),
+            },
+            "loop" => {
+                let start = self.here;
+                self.blocks.push(Block::Loop { start, exits: Vec::new(), token });
+            }
+            "while" => {
+                self.condition(true)?;
+                let exit = self.here;
+                self.emit(&token, Jump(0))?;
+                let exits = self.blocks.iter_mut().rev().find_map(|block| match *block {
+                    Block::Loop { ref mut exits, .. } => Some(exits),
+                    _ => None,
+                });
+                match exits {
+                    Some(exits) => exits.push(exit),
+                    None => return Err(token.error("'while' outside of a loop".to_string())),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop { start, exits, .. }) => {
+                    let again = self.here;
+                    self.emit(&token, Jump(0))?;
+                    self.patch_jump(&token, again, start)?;
+                    let here = self.here;
+                    for exit in exits {
+                        self.patch_jump(&token, exit, here)?;
+                    }
+                }
+                _ => return Err(token.error("'again' without a matching 'loop'".to_string())),
+            },
+            text if self.is_register(text) => self.register_statement(token)?,
+            text if self.macros.contains_key(text) => self.expand(token)?,
+            text if parse_number(text).is_some() || self.consts.contains_key(text) => {
+                self.pos -= 1;
+                let byte = self.byte()?;
+                self.emit_byte(&token, byte)?;
+            }
+            text if text.starts_with(':') || text == "{" || text == "}" => {
+                return Err(token.error(format!("unexpected '{}'", text)));
+            }
+            _ => {
+                // a bare name calls the subroutine
+                self.pos -= 1;
+                let address = self.address(FixupKind::Address)?;
+                self.emit(&token, Call(address))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Assemble a 'vX op ...' statement.
+    fn register_statement(&mut self, token: Token) -> Result<(), AsmError> {
+        use crate::disasm::Instruction::*;
+
+        let x = self.register_of(&token)?;
+        let op = self.next()?;
+        let rhs_is_register = self.peek().is_some_and(|text| self.is_register(text));
+        let instruction = match &op.text[..] {
+            ":=" => match self.peek() {
+                _ if rhs_is_register => Move(x, self.register()?),
+                Some("delay") => {
+                    self.next()?;
+                    GetDelay(x)
+                }
+                Some("key") => {
+                    self.next()?;
+                    WaitKey(x)
+                }
+                Some("random") => {
+                    self.next()?;
+                    Random(x, self.byte()?)
+                }
+                _ => LoadImm(x, self.byte()?),
+            },
+            "+=" if rhs_is_register => Add(x, self.register()?),
+            "+=" => AddImm(x, self.byte()?),
+            "-=" => Sub(x, self.register()?),
+            "=-" => SubReverse(x, self.register()?),
+            "|=" => Or(x, self.register()?),
+            "&=" => And(x, self.register()?),
+            "^=" => Xor(x, self.register()?),
+            ">>=" => ShiftRight(x, self.register()?),
+            "<<=" => ShiftLeft(x, self.register()?),
+            _ => return Err(op.error(format!("unknown operator '{}'", op.text))),
+        };
+        self.emit(&token, instruction)
+    }
+
+    /// Replace the invocation of a macro by its body, with the arguments
+    /// substituted.
+    fn expand(&mut self, token: Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(token.error(format!("too many macro expansions, is '{}' recursive ?", token.text)));
+        }
+        let argc = self.macros[&token.text].args.len();
+        let mut values = HashMap::new();
+        for i in 0..argc {
+            let value = self.next()?;
+            values.insert(self.macros[&token.text].args[i].clone(), value.text);
+        }
+        let body: Vec<Token> = self.macros[&token.text]
+            .body
+            .iter()
+            .map(|t| Token {
+                text: values.get(&t.text).cloned().unwrap_or_else(|| t.text.clone()),
+                ..t.clone()
+            })
+            .collect();
+        let pos = self.pos;
+        self.tokens.splice(pos..pos, body);
+        Ok(())
+    }
+
+    /// Resolve the forward references and return the assembled program.
+    fn finish(mut self) -> Result<Program, AsmError> {
+        if let Some(block) = self.blocks.pop() {
+            let (Block::If { token, .. } | Block::Else { token, .. } | Block::Loop { token, .. }) = block;
+            return Err(token.error("unterminated block".to_string()));
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            let address = match self.labels.get(&fixup.token.text) {
+                Some(&address) => address,
+                None => return Err(fixup.token.error(format!("undefined name '{}'", fixup.token.text))),
+            };
+            let word = self.read_word(fixup.address);
+            match fixup.kind {
+                FixupKind::Address | FixupKind::UnpackHigh if address > 0xFFF => {
+                    return Err(fixup.token.error(format!(
+                        "the address {:#06X} is out of the 12 bits range",
+                        address
+                    )));
+                }
+                FixupKind::Address => self.write_word(fixup.address, word | address),
+                FixupKind::Long => self.write_word(fixup.address, address),
+                FixupKind::UnpackHigh => self.write_word(fixup.address, word | address >> 8),
+                FixupKind::UnpackLow => self.write_word(fixup.address, word | (address & 0xFF)),
+            }
+        }
+        if self.main_jump {
+            match self.labels.get("main") {
+                Some(&main) if main <= 0xFFF => self.write_word(ORIGIN, 0x1000 | main),
+                _ => {
+                    return Err(AsmError {
+                        line: 1,
+                        column: 1,
+                        message: "the program has no 'main' label in the first 4 KiB".to_string(),
+                    })
+                }
+            }
+        }
+        let symbols = self.labels.into_iter().collect();
+        Ok(Program {
+            rom: self.memory[ORIGIN..self.end].to_vec(),
+            symbols,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{disassemble, Syntax};
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            :alias x v1
+            :const speed 2
+            :calc limit { 60 - speed * 2 }
+            :macro bump reg { reg += speed }
+
+            : main
+                x := 0
+                loop
+                    bump x
+                    if x == limit then x := 0
+                    if x > 10 begin
+                        draw
+                    else
+                        i := long data
+                    end
+                again
+
+            : draw  # forward call above
+                sprite x x 0 ;
+            : data
+                0xFF 0x81 :byte { 1 << 3 }
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(
+            program.rom,
+            vec![
+                0x61, 0x00, // x := 0
+                0x71, 0x02, // bump x
+                0x41, 0x38, 0x61, 0x00, // if x == 56 then x := 0
+                0x6F, 0x0A, 0x8F, 0x15, 0x3F, 0x00, // vf := 10 ; vf -= x ; skip unless borrow
+                0x12, 0x14, // jump else
+                0x22, 0x1A, // draw
+                0x12, 0x18, // jump end
+                0xF0, 0x00, 0x02, 0x1E, // i := long data
+                0x12, 0x02, // again
+                0xD1, 0x10, 0x00, 0xEE, // draw
+                0xFF, 0x81, 0x08, // data
+            ]
+        );
+        assert_eq!(program.symbols["draw"], 0x21A);
+        assert_eq!(program.symbols["data"], 0x21E);
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let rom = [0x22, 0x08, 0xA2, 0x0C, 0xF0, 0x00, 0x12, 0x02, 0x60, 0x2A, 0x00, 0xEE, 0xFF, 0x81];
+        let source = disassemble(&rom, 0x200, Syntax::Octo);
+        assert_eq!(assemble(&source).unwrap().rom, rom.to_vec());
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = assemble(": main\n  v0 := 300").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 9));
+        let error = assemble(": main\n\tjump nowhere").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 7));
+        assert_eq!(error.message, "undefined name 'nowhere'");
+        let error = assemble("v0 := 1").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 1));
+        assert!(assemble(": main loop").is_err());
+    }
+}
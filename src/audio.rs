@@ -0,0 +1,369 @@
+use crate::vm::{Vm, DEFAULT_PITCH};
+use std::f32::consts::PI;
+
+/// Size in bytes of the XO-CHIP audio pattern buffer : 128 samples of 1 bit.
+pub const PATTERN_SIZE: usize = 16;
+
+/// The pattern played until a program loads its own with F002 : a plain
+/// square wave of 500 Hz at the default pitch.
+pub const DEFAULT_PATTERN: [u8; PATTERN_SIZE] = [0xF0; PATTERN_SIZE];
+
+/// Return the rate, in bits per second, at which the pattern buffer is
+/// played for the given XO-CHIP pitch : 4000 * 2 ^ ((pitch - 64) / 48).
+pub fn playback_rate(pitch: u8) -> f64 {
+    4000.0 * 2f64.powf((pitch as f64 - 64.0) / 48.0)
+}
+
+/// Sample generator playing an XO-CHIP audio pattern in a loop, independent
+/// from any audio device so that frontends only have to copy its output.
+pub struct PatternPlayer {
+    pattern: [u8; PATTERN_SIZE],
+    pitch: u8,
+    /// Output sample rate, in Hz.
+    sample_rate: u32,
+    /// Position in the pattern, in bits.
+    pub(crate) position: f64,
+}
+
+impl PatternPlayer {
+    /// Create a player of the default pattern and pitch, for the given
+    /// output sample rate.
+    pub fn new(sample_rate: u32) -> PatternPlayer {
+        PatternPlayer {
+            pattern: DEFAULT_PATTERN,
+            pitch: DEFAULT_PITCH,
+            sample_rate,
+            position: 0.0,
+        }
+    }
+
+    /// Change the pattern and pitch played, as set by F002 and FX3A.
+    pub fn set_pattern(&mut self, pattern: [u8; PATTERN_SIZE], pitch: u8) {
+        self.pattern = pattern;
+        self.pitch = pitch;
+    }
+
+    /// Return the next sample, either 'volume' or '-volume'.
+    pub fn next_sample(&mut self, volume: f32) -> f32 {
+        let bit = self.position as usize;
+        let set = self.pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+        let pattern_bits = (PATTERN_SIZE * 8) as f64;
+        self.position += playback_rate(self.pitch) / self.sample_rate as f64;
+        if self.position >= pattern_bits {
+            self.position -= pattern_bits;
+        }
+        if set {
+            volume
+        } else {
+            -volume
+        }
+    }
+
+    /// Fill the given buffer with the next samples.
+    pub fn fill(&mut self, out: &mut [f32], volume: f32) {
+        for sample in out.iter_mut() {
+            *sample = self.next_sample(volume);
+        }
+    }
+}
+
+/// The waveform of the buzzer tone.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+}
+
+impl Waveform {
+    /// Return the waveform of the given name (case insensitive) : "square",
+    /// "sine" or "triangle".
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match &name.to_lowercase()[..] {
+            "square" => Some(Waveform::Square),
+            "sine" => Some(Waveform::Sine),
+            "triangle" => Some(Waveform::Triangle),
+            _ => None,
+        }
+    }
+
+    /// Return the value of the waveform at the given phase, between 0 and 1.
+    fn sample(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+        }
+    }
+}
+
+/// The duration, in seconds, of the fade in and out of the buzzer, short
+/// enough not to be heard but avoiding the clicks of an abrupt start or stop.
+pub const FADE_DURATION: f32 = 0.005;
+
+/// Sample generator of the buzzer sounding while the sound timer is active,
+/// independent from any audio device : frontends only have to copy its
+/// output, and it can be run headlessly.
+///
+/// The buzzer plays a tone of the configured waveform and frequency, or the
+/// XO-CHIP audio pattern once a program changes the pattern or the pitch.
+pub struct Buzzer {
+    pub waveform: Waveform,
+    /// The frequency of the tone, in Hz.
+    pub frequency: f32,
+    /// The output volume, between 0 and 1.
+    pub volume: f32,
+    pattern: PatternPlayer,
+    /// Set when the program plays its own audio pattern.
+    custom_pattern: bool,
+    sample_rate: u32,
+    /// Position in the period of the tone, between 0 and 1.
+    phase: f32,
+    /// The sound timer is active.
+    active: bool,
+    muted: bool,
+    /// The current gain of the fade, between 0 and 1.
+    gain: f32,
+}
+
+impl Buzzer {
+    /// Create a silent buzzer playing a square wave of 500 Hz, the tone of
+    /// the default XO-CHIP pattern, for the given output sample rate.
+    pub fn new(sample_rate: u32) -> Buzzer {
+        Buzzer {
+            waveform: Waveform::Square,
+            frequency: 500.0,
+            volume: 0.25,
+            pattern: PatternPlayer::new(sample_rate),
+            custom_pattern: false,
+            sample_rate,
+            phase: 0.0,
+            active: false,
+            muted: false,
+            gain: 0.0,
+        }
+    }
+
+    /// Change the XO-CHIP pattern and pitch, as set by F002 and FX3A.
+    pub fn set_pattern(&mut self, pattern: [u8; PATTERN_SIZE], pitch: u8) {
+        self.custom_pattern = pattern != DEFAULT_PATTERN || pitch != DEFAULT_PITCH;
+        self.pattern.set_pattern(pattern, pitch);
+    }
+
+    /// Start or stop the sound, following the sound timer.
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    /// Mute or unmute the output ; the sound goes on silently.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Return the next sample.
+    pub fn next_sample(&mut self) -> f32 {
+        // fade in or out
+        let target = if self.active && !self.muted { 1.0 } else { 0.0 };
+        let step = 1.0 / (FADE_DURATION * self.sample_rate as f32).max(1.0);
+        if self.gain < target {
+            self.gain = (self.gain + step).min(target);
+        } else if self.gain > target {
+            self.gain = (self.gain - step).max(target);
+        }
+        if self.gain == 0.0 {
+            // restart the waves from the beginning, for a smooth fade in
+            self.phase = 0.0;
+            self.pattern.position = 0.0;
+            return 0.0;
+        }
+
+        let value = if self.custom_pattern {
+            self.pattern.next_sample(1.0)
+        } else {
+            let value = self.waveform.sample(self.phase);
+            self.phase = (self.phase + self.frequency / self.sample_rate as f32).fract();
+            value
+        };
+        value * self.volume * self.gain
+    }
+
+    /// Fill the given buffer with the next samples.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
+}
+
+/// Recorder of the sound of a virtual machine, rendered frame by frame by a
+/// 'Buzzer' without any audio device, e.g. to compare the sound of runs.
+///
+/// Every 60 Hz frame lasts exactly a 60th of a second : its number of samples
+/// varies by one when the sample rate isn't a multiple of 60, for the
+/// frames not to drift from the samples.
+pub struct SoundRecorder {
+    pub buzzer: Buzzer,
+    sample_rate: u32,
+    /// The number of frames recorded.
+    frames: u64,
+    /// The samples recorded.
+    pub samples: Vec<f32>,
+}
+
+impl SoundRecorder {
+    pub fn new(buzzer: Buzzer, sample_rate: u32) -> SoundRecorder {
+        SoundRecorder {
+            buzzer,
+            sample_rate,
+            frames: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Record the sound of a frame of the virtual machine, the buzzer
+    /// sounding through it if 'sound' is set (see 'FrameReport::sound').
+    pub fn record_frame(&mut self, vm: &Vm, sound: bool) {
+        self.buzzer.set_pattern(vm.audio_pattern, vm.pitch);
+        self.buzzer.set_active(sound);
+        let rate = self.sample_rate as u64;
+        let end = ((self.frames + 1) * rate / 60) as usize;
+        let start = self.samples.len();
+        self.samples.resize(end, 0.0);
+        self.buzzer.fill(&mut self.samples[start..]);
+        self.frames += 1;
+    }
+
+    /// Return the samples recorded as a WAV file, see 'to_wav'.
+    pub fn to_wav(&self) -> Vec<u8> {
+        to_wav(&self.samples, self.sample_rate)
+    }
+}
+
+/// Return the given samples, between -1 and 1, as a mono 16 bits PCM WAV
+/// file.
+pub fn to_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data_size = samples.len() as u32 * 2;
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // bytes per second
+    wav.extend_from_slice(&2u16.to_le_bytes()); // bytes per sample
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    for &sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        wav.extend_from_slice(&value.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playback_rate() {
+        assert!((playback_rate(64) - 4000.0).abs() < 1e-9);
+        assert!((playback_rate(112) - 8000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_pattern_player() {
+        // one pattern bit per sample
+        let mut player = PatternPlayer::new(4000);
+        let mut pattern = [0u8; PATTERN_SIZE];
+        pattern[0] = 0b1010_0000;
+        player.set_pattern(pattern, DEFAULT_PITCH);
+        let mut out = [0.0; 4];
+        player.fill(&mut out, 1.0);
+        assert_eq!(out, [1.0, -1.0, 1.0, -1.0]);
+    }
+
+    /// Return the largest difference between 2 consecutive samples.
+    fn max_step(samples: &[f32]) -> f32 {
+        samples.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_buzzer() {
+        let mut buzzer = Buzzer::new(44100);
+        buzzer.waveform = Waveform::Sine;
+        buzzer.frequency = 441.0;
+        buzzer.volume = 0.5;
+        let mut out = vec![0.0; 4410];
+        buzzer.fill(&mut out);
+        assert!(out.iter().all(|&sample| sample == 0.0));
+
+        // 45 periods starting in 0.1 s, faded in without any click
+        buzzer.set_active(true);
+        buzzer.fill(&mut out);
+        let rising = out.windows(2).filter(|w| w[0] <= 0.0 && w[1] > 0.0).count();
+        assert_eq!(rising, 45);
+        assert!(max_step(&out) < 0.04);
+        assert!((out.iter().cloned().fold(0.0, f32::max) - 0.5).abs() < 1e-3);
+
+        // faded out, even muted
+        buzzer.set_muted(true);
+        buzzer.fill(&mut out);
+        assert!(max_step(&out) < 0.04);
+        assert_eq!(out[4409], 0.0);
+
+        buzzer.set_muted(false);
+        buzzer.waveform = Waveform::Triangle;
+        buzzer.fill(&mut out);
+        assert!(max_step(&out) < 0.04);
+
+        // the program's own pattern, one bit per sample
+        let mut buzzer = Buzzer::new(4000);
+        buzzer.volume = 1.0;
+        buzzer.set_pattern([0xAA; PATTERN_SIZE], DEFAULT_PITCH);
+        buzzer.set_active(true);
+        let mut out = vec![0.0; 40];
+        buzzer.fill(&mut out);
+        assert!(out[20..].iter().all(|&sample| sample.abs() == 1.0));
+        assert_eq!(out[20], -out[21]);
+    }
+
+    #[test]
+    fn test_sound_recorder() {
+        // 'v0 := 3 ; buzzer := v0 ; loop : jump loop'
+        let mut vm = Vm::new();
+        vm.load_rom(&[0x60, 0x03, 0xF0, 0x18, 0x12, 0x04]).unwrap();
+        let mut recorder = SoundRecorder::new(Buzzer::new(22050), 22050);
+        for _ in 0..60 {
+            let report = vm.run_frame(10).unwrap();
+            recorder.record_frame(&vm, report.sound);
+        }
+        // a second of sound, 367 or 368 samples a frame
+        assert_eq!(recorder.samples.len(), 22050);
+        let sounding = |range: std::ops::Range<usize>| recorder.samples[range].iter().any(|&s| s != 0.0);
+        assert!(sounding(0..367));
+        assert!(sounding(735..1102));
+        // faded out at the end of the third frame
+        assert!(!sounding(1103 + 110..22050));
+
+        let wav = recorder.to_wav();
+        assert_eq!(wav.len(), 44 + 2 * 22050);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(&wav[24..28], &22050u32.to_le_bytes());
+        assert_eq!(&wav[40..44], &44100u32.to_le_bytes());
+        assert_eq!(to_wav(&[1.0, -1.0, 2.0], 8000)[44..], [0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]);
+    }
+}
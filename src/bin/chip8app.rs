@@ -1,33 +1,119 @@
-use std::cmp;
-use std::path::Path;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
-
-use time::{Duration, Instant};
+use std::env;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
 use crate::input;
-use chip8vm::keypad::Keystate;
-use chip8vm::display::{Display, DISPLAY_WIDTH, DISPLAY_HEIGHT};
+use chip8vm::movie::{Movie, Player};
+use chip8vm::profiler::Profiler;
+use chip8vm::quirks::Quirks;
+use chip8vm::trace::{TraceFormat, Tracer};
+use chip8vm::audio::Waveform;
+use chip8vm::debugger::{Breakpoint, OpcodePattern};
 use chip8vm::vm::Vm;
 
+// the threaded emulator driven by the SDL2 frontend
+#[cfg(feature = "sdl2")]
+use {
+    crate::gdbserver,
+    chip8vm::audio::PATTERN_SIZE,
+    chip8vm::debugger::{Debugger, RunMode, StopReason},
+    chip8vm::display::Display,
+    chip8vm::error::VmError,
+    chip8vm::keypad::Keystate,
+    chip8vm::rewind::RewindBuffer,
+    chip8vm::rng::Rng,
+    chip8vm::vm::FrameReport,
+    std::cmp,
+    std::fs::File,
+    std::mem,
+    std::sync::mpsc::{channel, Receiver, Sender},
+    std::thread,
+    time::{Duration, Instant},
+};
+
+/// The default palette : black background, white for the first bitplane
+/// and shades of grey for the second one and their overlap.
+pub const DEFAULT_PALETTE: [u32; 4] = [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555];
+
 /// Structure facilitating the configuration of a 'Chip8Application'.
 /// The configuration functions (e.g. 'w_title') work with moved 'self' values
 /// to allow chaining them inside the Chip8Application::new function call.
 pub struct Chip8Config {
     /// The title of the emulator window.
+    #[cfg(feature = "sdl2")]
     pub window_title: &'static str,
     /// The desired width for the emulator window.
     /// NB : this is just a hint, the application may resize to reach a proper
     /// aspect ratio.
+    #[cfg(feature = "sdl2")]
     pub window_width: u16,
     /// The desired height for the emulator window.
     /// NB : this is just a hint, the application may resize to reach a proper
     /// aspect ratio.
+    #[cfg(feature = "sdl2")]
     pub window_height: u16,
     /// The keyboard configuration. QWERTY by default.
     pub keypad_binding: input::KeyboardBinding,
     /// The virtual machine's desired CPU clock in Hz (cycles per second).
     pub vm_cpu_clock: u32,
+    /// The quirks of the emulated interpreter. COSMAC VIP by default.
+    pub vm_quirks: Quirks,
+    /// The seed of the virtual machine's random generator, for reproducible
+    /// runs. Random if not set.
+    pub vm_seed: Option<u64>,
+    /// The waveform of the buzzer tone.
+    pub audio_waveform: Waveform,
+    /// The frequency of the buzzer tone in Hz.
+    pub audio_frequency: f32,
+    /// The volume of the buzzer, between 0 and 1.
+    pub audio_volume: f32,
+    /// Flash the border of the screen while the buzzer sounds, e.g. for
+    /// silent environments.
+    pub visual_bell: bool,
+    /// The 0xRRGGBB colors of the pixels, indexed by the XO-CHIP bitplanes
+    /// they are set in : none, the first, the second and both.
+    pub palette: [u32; 4],
+    /// The save state file written and read by the 'SaveState' and
+    /// 'LoadState' commands. Next to the ROM, with a '.state' extension, if
+    /// not set.
+    pub state_path: Option<PathBuf>,
+    /// The directory where the SUPER-CHIP RPL user flags of each ROM are
+    /// persisted between sessions. '~/.impl-chip8/rpl' by default.
+    pub rpl_dir: PathBuf,
+    /// The number of frames between two snapshots of the rewind buffer.
+    pub rewind_interval: u32,
+    /// The maximum number of snapshots in the rewind buffer. 5 minutes of
+    /// history by default.
+    pub rewind_capacity: usize,
+    /// The breakpoints the emulation pauses at.
+    pub breakpoints: Vec<Breakpoint>,
+    /// The opcodes the emulation pauses before.
+    pub opcode_breaks: Vec<OpcodePattern>,
+    /// The localhost port of the GDB remote serial protocol server, if any.
+    pub gdb_port: Option<u16>,
+    /// The execution trace file, if any.
+    pub trace_path: Option<PathBuf>,
+    /// The format of the execution trace.
+    pub trace_format: TraceFormat,
+    /// The addresses of the instructions traced.
+    pub trace_range: RangeInclusive<u16>,
+    /// The maximum size of the execution trace in bytes, if any.
+    pub trace_max_size: Option<u64>,
+    /// The file the profile report is written to at exit, if any.
+    pub profile_path: Option<PathBuf>,
+    /// The symbols naming the addresses in the profile report, as written
+    /// by the 'asm' subcommand.
+    pub symbols_path: Option<PathBuf>,
+    /// The file the screen is captured to, as an animated GIF or, with a
+    /// '.y4m' extension, a Y4M video. Named after the time if not set.
+    pub capture_path: Option<PathBuf>,
+    /// The integer scale of the capture. 4 by default.
+    pub capture_scale: usize,
+    /// The file the input movie of the session is recorded to, if any.
+    pub record_path: Option<PathBuf>,
+    /// The input movie played back, if any.
+    pub play_path: Option<PathBuf>,
 }
 
 /// Macro to avoid boilerplate setter code.
@@ -44,55 +130,249 @@ impl Chip8Config {
     /// Create and return the default set of options.
     pub fn new() -> Chip8Config {
         Chip8Config {
+            #[cfg(feature = "sdl2")]
             window_title: "",
+            #[cfg(feature = "sdl2")]
             window_width: 64,
+            #[cfg(feature = "sdl2")]
             window_height: 32,
             keypad_binding: input::KeyboardBinding::QWERTY,
             vm_cpu_clock: 600,
+            vm_quirks: Quirks::default(),
+            vm_seed: None,
+            audio_waveform: Waveform::Square,
+            audio_frequency: 500.0,
+            audio_volume: 0.25,
+            visual_bell: false,
+            palette: DEFAULT_PALETTE,
+            state_path: None,
+            rpl_dir: default_rpl_dir(),
+            rewind_interval: 2,
+            rewind_capacity: 5 * 60 * 30,
+            breakpoints: Vec::new(),
+            opcode_breaks: Vec::new(),
+            gdb_port: None,
+            trace_path: None,
+            trace_format: TraceFormat::Text,
+            trace_range: 0..=0xFFFF,
+            trace_max_size: None,
+            profile_path: None,
+            symbols_path: None,
+            capture_path: None,
+            capture_scale: 4,
+            record_path: None,
+            play_path: None,
         }
     }
 
+    #[cfg(feature = "sdl2")]
     config_set_param!(w_title, window_title, &'static str);
+    #[cfg(feature = "sdl2")]
     config_set_param!(w_width, window_width, u16);
+    #[cfg(feature = "sdl2")]
     config_set_param!(w_height, window_height, u16);
     config_set_param!(key_binds, keypad_binding, input::KeyboardBinding);
     config_set_param!(vm_cpu_clock, vm_cpu_clock, u32);
+    config_set_param!(quirks, vm_quirks, Quirks);
+    config_set_param!(seed, vm_seed, Option<u64>);
+    config_set_param!(audio_waveform, audio_waveform, Waveform);
+    config_set_param!(audio_frequency, audio_frequency, f32);
+    config_set_param!(audio_volume, audio_volume, f32);
+    config_set_param!(visual_bell, visual_bell, bool);
+    config_set_param!(palette, palette, [u32; 4]);
+    config_set_param!(state_path, state_path, Option<PathBuf>);
+    config_set_param!(rpl_dir, rpl_dir, PathBuf);
+    config_set_param!(rewind_interval, rewind_interval, u32);
+    config_set_param!(rewind_capacity, rewind_capacity, usize);
+    config_set_param!(breakpoints, breakpoints, Vec<Breakpoint>);
+    config_set_param!(opcode_breaks, opcode_breaks, Vec<OpcodePattern>);
+    config_set_param!(gdb_port, gdb_port, Option<u16>);
+    config_set_param!(trace_path, trace_path, Option<PathBuf>);
+    config_set_param!(trace_format, trace_format, TraceFormat);
+    config_set_param!(trace_range, trace_range, RangeInclusive<u16>);
+    config_set_param!(trace_max_size, trace_max_size, Option<u64>);
+    config_set_param!(profile_path, profile_path, Option<PathBuf>);
+    config_set_param!(symbols_path, symbols_path, Option<PathBuf>);
+    config_set_param!(capture_path, capture_path, Option<PathBuf>);
+    config_set_param!(capture_scale, capture_scale, usize);
+    config_set_param!(record_path, record_path, Option<PathBuf>);
+    config_set_param!(play_path, play_path, Option<PathBuf>);
+
+    /// Create the execution tracer of the configuration, if a trace file is
+    /// set and can be created.
+    pub fn create_tracer(&self) -> Option<Tracer> {
+        let path = self.trace_path.as_ref()?;
+        match Tracer::create(path, self.trace_format) {
+            Ok(mut tracer) => {
+                info!("tracing the execution to \"{}\".", path.display());
+                tracer.range = self.trace_range.clone();
+                tracer.max_size = self.trace_max_size;
+                Some(tracer)
+            }
+            Err(why) => {
+                warn!("couldn't create the trace file \"{}\" : {}", path.display(), why);
+                None
+            }
+        }
+    }
+
+    /// Create the profiler of the configuration, with its symbols, if a
+    /// profile report file is set.
+    pub fn create_profiler(&self) -> Option<Profiler> {
+        self.profile_path.as_ref()?;
+        let mut profiler = Profiler::new();
+        if let Some(ref path) = self.symbols_path {
+            match fs::read_to_string(path) {
+                Ok(text) => profiler.symbols = Profiler::parse_symbols(&text),
+                Err(why) => warn!("couldn't read the symbols \"{}\" : {}", path.display(), why),
+            }
+        }
+        Some(profiler)
+    }
+
+    /// Set up the virtual machine, with its ROM loaded and its random
+    /// generator created from 'seed', to play back and record the movies of
+    /// the configuration. The movie played back sets the number of
+    /// instructions per frame.
+    /// Return the player and the movie recorded, or an error message if the
+    /// movie played back can't be read.
+    pub fn start_movies(
+        &self,
+        vm: &mut Vm,
+        seed: u64,
+        instructions_per_frame: &mut u32,
+    ) -> Result<(Option<Player>, Option<Movie>), String> {
+        let mut seed = seed;
+        let player = match self.play_path {
+            Some(ref path) => {
+                let text = fs::read_to_string(path).map_err(|why| format!("{} : {}", path.display(), why))?;
+                let movie = Movie::parse(&text).map_err(|why| format!("{} : {}", path.display(), why))?;
+                if let Err(why) = movie.setup(vm) {
+                    warn!("{}, the replay will go out of sync.", why);
+                }
+                info!("playing back the movie \"{}\" ({} frames).", path.display(), movie.frames());
+                seed = movie.seed;
+                *instructions_per_frame = movie.instructions_per_frame;
+                Some(Player::new(movie))
+            }
+            None => None,
+        };
+        let recording = self.record_path.as_ref().map(|path| {
+            info!("recording the movie \"{}\".", path.display());
+            Movie::new(vm, seed, *instructions_per_frame)
+        });
+        Ok((player, recording))
+    }
+}
+
+/// Write the movie recorded to the given file.
+pub fn write_movie(movie: &Movie, path: &Path) {
+    match fs::write(path, movie.to_text()) {
+        Ok(()) => info!("wrote the movie of {} frames to \"{}\".", movie.frames(), path.display()),
+        Err(why) => warn!("couldn't write the movie : {}", why),
+    }
+}
+
+/// Write the profile report of the virtual machine, if it has a profiler.
+pub fn write_profile(vm: &Vm, path: &Path) {
+    if let Some(ref profiler) = vm.profiler {
+        match fs::write(path, profiler.report(vm)) {
+            Ok(()) => info!("wrote the profile report to \"{}\".", path.display()),
+            Err(why) => warn!("couldn't write the profile report : {}", why),
+        }
+    }
+}
+
+/// Return the default directory for the persistent RPL user flags.
+fn default_rpl_dir() -> PathBuf {
+    match env::var_os("HOME") {
+        Some(home) => Path::new(&home).join(".impl-chip8").join("rpl"),
+        None => env::temp_dir().join("impl-chip8").join("rpl"),
+    }
 }
 
 /// A command for the Chip8 virtual machine.
 /// Allows the UI (more specifically the Chip8Emulator's backend) to feed
 /// orders and information to the virtual machine's thread.
+#[cfg(feature = "sdl2")]
 pub enum Chip8VMCommand {
     /// Set the emulation state (running for true, paused for false).
     UpdateRunStatus(bool),
     /// Communicate an update in the status of the key at the given index.
     UpdateKeyStatus(usize, Keystate),
-    /// Reset the virtual machine to its default state.
+    /// Reset the virtual machine to its default state, the loaded ROM
+    /// restarting from scratch.
     Reset,
+    /// Save the complete state of the virtual machine to the save state file.
+    SaveState,
+    /// Restore the virtual machine from the save state file.
+    LoadState,
+    /// Set the rewinding state : while true, the emulation steps backward
+    /// through the rewind buffer in real time, one snapshot every
+    /// 'rewind_interval' frames.
+    UpdateRewindStatus(bool),
+    /// Resume the emulation in the given debugger mode, e.g. to execute a
+    /// single instruction ; it pauses again with a 'Chip8UICommand::Paused'
+    /// once the stepping is complete.
+    Resume(RunMode),
+    /// Run the given function on the virtual machine and its debugger, in
+    /// the virtual machine's thread, e.g. to inspect or patch their state.
+    Inspect(InspectFn),
     /// Shutdown the virtual machine.
     Quit,
 }
 
 /// A command for the Chip8 emulator's UI.
 /// Allows the virtual machine to communicate with the Chip8Emulator's thread.
+#[cfg(feature = "sdl2")]
+#[allow(clippy::large_enum_variant)]
 pub enum Chip8UICommand {
     /// Signal whether the emulator should emit a sound or not (true whenever
     /// the VM's sound timer is not zero).
     UpdateBeepingStatus(bool),
+    /// Communicate the XO-CHIP audio pattern buffer and pitch to play while
+    /// beeping. Sent whenever the program changes either of them.
+    UpdateAudioPattern([u8; PATTERN_SIZE], u8),
     /// A drawing command for the UI, communicating the information needed to
     /// do so. As of now, the 'Display' structure is pretty much that so we can
     /// affort to pass a copy of it.
     /// Should be called only when needed (display flagged dirty).
     UpdateDisplay(Display),
+    /// Signal that the debugger paused the emulation at the given address,
+    /// for the given reason (breakpoint hit, stepping complete...) ; the
+    /// emulation is paused until a 'Chip8VMCommand::Resume' or
+    /// 'UpdateRunStatus(true)'.
+    Paused(u16, StopReason),
+    /// Signal that the virtual machine stopped on the given error ; the
+    /// emulation is paused until a 'Chip8VMCommand::Reset' or 'Quit'.
+    Crashed(VmError),
     /// Signal that the emulation is finished, emitted either after a
     /// 'Chip8VMCommand::Quit' signal was received or when the virtual machine
     /// finished the execution of its loaded program.
     Finished,
 }
 
+/// A function run by 'Chip8VMCommand::Inspect'.
+#[cfg(feature = "sdl2")]
+pub type InspectFn = Box<dyn FnOnce(&mut Vm, &mut Debugger) + Send>;
+
+/// An event of the virtual machine's thread, reported to the debugger front
+/// ends (see 'VmOptions::debug_events').
+#[cfg(feature = "sdl2")]
+pub enum DebugEvent {
+    /// The debugger paused the emulation at the given address, for the
+    /// given reason.
+    Paused(u16, StopReason),
+    /// The virtual machine stopped on an error at the given address.
+    Crashed(u16),
+    /// The emulation is finished.
+    Finished,
+}
+
 /// Trait that any CHIP 8 emulator backend must implement.
 /// The backend is free to implement its 'run' loop however it wants to
 /// but has to respect as completely as it can the 'Chip8Config' it is given.
+#[cfg(feature = "sdl2")]
 pub trait Chip8EmulatorBackend {
     /// Start the UI loop with the given configuration and the provided
     /// thread channels.
@@ -108,6 +388,7 @@ pub trait Chip8EmulatorBackend {
 /// Communication between the virtual machine's emulation loop and the
 /// backend's UI loop is done with 2 channels using respectively
 /// 'Chip8VMCommand' and 'Chip8UICommand'.
+#[cfg(feature = "sdl2")]
 pub struct Chip8Emulator<'a> {
     /// The 'Chip8Config' instance holding the application's configuration.
     config: Chip8Config,
@@ -116,6 +397,7 @@ pub struct Chip8Emulator<'a> {
     backend: Box<dyn Chip8EmulatorBackend + 'a>,
 }
 
+#[cfg(feature = "sdl2")]
 impl<'a> Chip8Emulator<'a> {
     /// Create and return a new Chip8Emulator, with the given 'Chip8Config'.
     pub fn new(
@@ -123,8 +405,8 @@ impl<'a> Chip8Emulator<'a> {
         backend: Box<dyn Chip8EmulatorBackend + 'a>,
     ) -> Chip8Emulator<'a> {
         Chip8Emulator {
-            config: config,
-            backend: backend,
+            config,
+            backend,
         }
     }
 
@@ -133,25 +415,83 @@ impl<'a> Chip8Emulator<'a> {
     /// TODO : more flexible run function (maybe a LoadRomCommand ?)
     pub fn run_rom(&mut self, rom_filepath: &Path) -> bool {
         // VM creation and ROM loading
-        let mut vm = Vm::new();
+        let mut vm = Vm::with_quirks(self.config.vm_quirks);
+        let seed = match self.config.vm_seed {
+            Some(seed) => {
+                info!("seeding the random generator with {}", seed);
+                seed
+            }
+            None => rand::random(),
+        };
+        vm.rng = Rng::new(seed);
+        vm.tracer = self.config.create_tracer();
+        vm.profiler = self.config.create_profiler();
         info!("loading the ROM file \"{}\"...", rom_filepath.display());
-        let oerror = vm.load(rom_filepath);
-        if oerror.is_none() {
-            info!("successfully loaded the ROM file.");
-        } else {
-            error!("loading error : {}", oerror.unwrap());
-            return false;
+        match vm.load(rom_filepath) {
+            Ok(()) => info!("successfully loaded the ROM file."),
+            Err(why) => {
+                error!("loading error : {}", why);
+                return false;
+            }
+        }
+
+        // RPL user flags saved by a previous session of the same ROM
+        let rpl_path = self.config.rpl_dir.join(format!("{:016x}.rpl", vm.rom_hash));
+        if rpl_path.exists() {
+            match vm.load_rpl_flags(&rpl_path) {
+                Ok(()) => info!("restored the RPL user flags from \"{}\".", rpl_path.display()),
+                Err(why) => warn!("couldn't restore the RPL user flags : {}", why),
+            }
         }
 
+        // Input movies, set up once the RPL user flags are restored
+        let mut instructions_per_frame = self.config.vm_cpu_clock / 60;
+        let (player, recording) = match self.config.start_movies(&mut vm, seed, &mut instructions_per_frame) {
+            Ok(movies) => movies,
+            Err(why) => {
+                error!("couldn't read the movie : {}", why);
+                return false;
+            }
+        };
+
         // Communication channels
         let (tx_ui, rx_ui) = channel::<Chip8UICommand>();
         let (tx_vm, rx_vm) = channel::<Chip8VMCommand>();
 
         // VM loop, in a secondary thread
-        let cpu_clock = self.config.vm_cpu_clock;
+        let mut options = VmOptions {
+            cpu_clock: match player {
+                Some(_) => instructions_per_frame * 60,
+                None => self.config.vm_cpu_clock,
+            },
+            rpl_path,
+            state_path: match self.config.state_path {
+                Some(ref path) => path.clone(),
+                None => rom_filepath.with_extension("state"),
+            },
+            rewind_interval: self.config.rewind_interval.max(1),
+            rewind_capacity: self.config.rewind_capacity,
+            debugger: Debugger::new(),
+            debug_events: None,
+            profile_path: self.config.profile_path.clone(),
+            player,
+            recording: recording.zip(self.config.record_path.clone()),
+        };
+        options.debugger.breakpoints = self.config.breakpoints.clone();
+        options.debugger.opcode_breaks = self.config.opcode_breaks.clone();
+
+        // GDB server, in its own thread
+        if let Some(port) = self.config.gdb_port {
+            let (tx_events, rx_events) = channel::<DebugEvent>();
+            options.debug_events = Some(tx_events);
+            // the program waits for GDB to resume it
+            tx_vm.send(Chip8VMCommand::UpdateRunStatus(false)).unwrap();
+            let tx = tx_vm.clone();
+            thread::spawn(move || gdbserver::serve(port, tx, rx_events));
+        }
         thread::spawn(move || {
             // VM thread moved to an external function for better clarity
-            exec_vm(&mut vm, cpu_clock, tx_ui, rx_vm);
+            exec_vm(&mut vm, &options, tx_ui, rx_vm);
         });
 
         // UI loop, in the emulator's thread (should be the main thread)
@@ -161,99 +501,210 @@ impl<'a> Chip8Emulator<'a> {
     }
 }
 
+/// The part of the 'Chip8Config' used by the virtual machine's thread,
+/// resolved for the loaded ROM.
+#[cfg(feature = "sdl2")]
+pub struct VmOptions {
+    /// The CPU clock in Hz.
+    pub cpu_clock: u32,
+    /// The file persisting the RPL user flags of the ROM.
+    pub rpl_path: PathBuf,
+    /// The save state file.
+    pub state_path: PathBuf,
+    /// The number of frames between two rewind snapshots.
+    pub rewind_interval: u32,
+    /// The maximum number of rewind snapshots.
+    pub rewind_capacity: usize,
+    /// The debugger, with the breakpoints set from the configuration.
+    pub debugger: Debugger,
+    /// The channel reporting the pauses to the debugger front ends, if any.
+    pub debug_events: Option<Sender<DebugEvent>>,
+    /// The file the profile report is written to at exit, if any.
+    pub profile_path: Option<PathBuf>,
+    /// The input movie played back, if any.
+    pub player: Option<Player>,
+    /// The input movie recorded, and the file it's written to at exit.
+    pub recording: Option<(Movie, PathBuf)>,
+}
+
 /// Emulation loop simulating the CHIP 8 virtual machine and communicating back
 /// to the emulator's backend implementation by feeding Chip8UI
+#[cfg(feature = "sdl2")]
 pub fn exec_vm(
     vm: &mut Vm,
-    cpu_clock: u32,
+    options: &VmOptions,
     tx: Sender<Chip8UICommand>,
     rx: Receiver<Chip8VMCommand>,
 ) {
     use self::Chip8UICommand::*;
     use self::Chip8VMCommand::*;
 
+    let cpu_clock = options.cpu_clock;
+    let state_path = &options.state_path;
+
     info!(
         "starting the virtual machine thread with a CPU clock of {} Hz",
         cpu_clock
     );
 
-    // time handling is in nanoseconds
-    let mut t = Instant::now();
-    let mut last_t_cpu = Instant::now();
-    let mut last_t_timers = t;
-    let timers_step = Duration::nanoseconds(10i64.pow(9) / (60 as i64));
-    let cpu_step = Duration::nanoseconds(10i64.pow(9) / (cpu_clock as i64));
+    // time handling is in nanoseconds ; the instructions are executed by
+    // batches of a frame, for the emulation not to depend on the timing
+    let mut last_t_frame = Instant::now();
+    let frame_step = Duration::nanoseconds(10i64.pow(9) / 60);
+    let instructions_per_frame = (cpu_clock / 60).max(1);
 
     // VM state
     let mut running = true;
+    // stopped on an error until the next reset
+    let mut crashed = false;
     let mut beeping = false;
-    let mut waiting_for_key = false;
-    // avoid triggering multiple 'wait for key' instructions at once
-    // especially with a high CPU clock
-    let mut wait_for_key_last_pressed = 0xFF;
+    let mut audio = (vm.audio_pattern, vm.pitch);
+    // rewind history, a snapshot every 'rewind_interval' frames
+    let mut rewind = RewindBuffer::new(options.rewind_capacity);
+    let mut rewinding = false;
+    let mut frames: u32 = 0;
+    // the current frame, run in parts when the debugger pauses in the middle
+    let mut report = FrameReport::default();
+    // the key events received, applied at the start of the next frame
+    let mut keys = Vec::new();
+    let mut player = options.player.clone();
+    let mut recording = options.recording.clone();
+    let mut debugger = options.debugger.clone();
+    let send_event = |event| {
+        if let Some(ref events) = options.debug_events {
+            let _ = events.send(event);
+        }
+    };
 
     'vm: loop {
         // Command from the UI
-        match rx.try_recv() {
-            // non-blocking receiving function
-            Ok(vm_command) => match vm_command {
-                UpdateRunStatus(run) => running = run,
-                UpdateKeyStatus(index, state) => match state {
-                    Keystate::Pressed => {
-                        if waiting_for_key && (index != wait_for_key_last_pressed) {
-                            vm.end_wait_for_key(index);
-                            wait_for_key_last_pressed = index;
-                        } else {
-                            vm.keypad.set_key_state(index, state);
-                        }
+        // non-blocking receiving function
+        if let Ok(vm_command) = rx.try_recv() {
+            match vm_command {
+                UpdateRunStatus(run) => {
+                    if run && !running {
+                        debugger.resume(vm, RunMode::Continue);
                     }
-                    Keystate::Released => {
-                        wait_for_key_last_pressed = 0xFF;
-                        if !waiting_for_key {
-                            vm.keypad.set_key_state(index, state);
+                    running = run
+                }
+                UpdateKeyStatus(index, state) => keys.push((index, state)),
+                Reset => {
+                    warn_unreplayable(&recording, "reset");
+                    vm.reset();
+                    crashed = false;
+                }
+                SaveState => {
+                    let result = File::create(state_path)
+                        .map_err(VmError::from)
+                        .and_then(|mut file| vm.save_state(&mut file));
+                    match result {
+                        Ok(()) => info!("saved the state to \"{}\".", state_path.display()),
+                        Err(why) => warn!("couldn't save the state : {}", why),
+                    }
+                }
+                LoadState => {
+                    let result = File::open(state_path)
+                        .map_err(VmError::from)
+                        .and_then(|mut file| Vm::load_state(&mut file));
+                    match result {
+                        Ok(mut state) => {
+                            warn_unreplayable(&recording, "restored state");
+                            state.tracer = vm.tracer.take();
+                            state.profiler = vm.profiler.take();
+                            state.rom = mem::take(&mut vm.rom);
+                            *vm = state;
+                            crashed = false;
+                            info!("restored the state from \"{}\".", state_path.display());
                         }
+                        Err(why) => warn!("couldn't restore the state : {}", why),
+                    }
+                }
+                UpdateRewindStatus(rewind) => {
+                    if rewind {
+                        warn_unreplayable(&recording, "rewind");
                     }
-                },
-                Reset => vm.reset(),
+                    rewinding = rewind
+                }
+                Resume(mode) => {
+                    debugger.resume(vm, mode);
+                    running = true;
+                }
+                Inspect(function) => function(vm, &mut debugger),
                 Quit => {
-                    running = false;
                     info!("terminating the virtual machine thread...");
+                    shutdown(vm, options, &recording);
                     tx.send(Finished).unwrap();
                     break 'vm;
                 }
-            },
-            _ => {}
+            }
         }
 
-        // CPU
-        t = Instant::now();
-        if t - last_t_cpu >= cpu_step {
-            last_t_cpu = t;
-            if running && !waiting_for_key {
-                vm.emulate_cycle();
+        // CPU and timers, a frame every 60 Hz tick
+        let t = Instant::now();
+        if t - last_t_frame >= frame_step {
+            last_t_frame = t;
+            if running && rewinding {
+                // step backward at the pace the snapshots were taken, the
+                // restored display is flagged dirty
+                frames = frames.wrapping_sub(1);
+                let snapshot = if frames.is_multiple_of(options.rewind_interval) { rewind.pop() } else { None };
+                if let Some(mut state) = snapshot {
+                    state.tracer = vm.tracer.take();
+                    state.profiler = vm.profiler.take();
+                    state.rom = mem::take(&mut vm.rom);
+                    *vm = state;
+                    crashed = false;
+                    let display = vm.display.clone();
+                    tx.send(UpdateDisplay(display)).unwrap();
+                    vm.display.dirty = false;
+                }
+            } else if running && !crashed {
+                if report.cycles == 0 {
+                    start_frame(vm, &mut keys, &mut player, &mut recording);
+                }
+                match debugger.run_frame(vm, &mut report, instructions_per_frame) {
+                    Ok(Some(StopReason::Finished)) => {
+                        info!("the program is finished, terminating the virtual machine thread...");
+                        send_event(DebugEvent::Finished);
+                        shutdown(vm, options, &recording);
+                        tx.send(Finished).unwrap();
+                        break 'vm;
+                    }
+                    Ok(None) | Ok(Some(StopReason::Waiting)) => {}
+                    Ok(Some(reason)) => {
+                        running = false;
+                        send_event(DebugEvent::Paused(vm.pc, reason));
+                        tx.send(Paused(vm.pc, reason)).unwrap();
+                    }
+                    Err(why) => {
+                        error!("the virtual machine stopped : {}", why);
+                        send_event(DebugEvent::Crashed(vm.pc));
+                        crashed = true;
+                        tx.send(Crashed(why)).unwrap();
+                    }
+                }
                 if vm.display.dirty {
                     let display = vm.display.clone();
                     tx.send(UpdateDisplay(display)).unwrap();
                     vm.display.dirty = false;
                 }
-            }
-            waiting_for_key = vm.is_waiting_for_key();
-        }
-
-        // Timers
-        t = Instant::now(); // this may be overkill
-        if t - last_t_timers >= timers_step {
-            last_t_timers = t;
-            if running {
-                if vm.delay_timer > 0 {
-                    vm.delay_timer -= 1;
+                if audio != (vm.audio_pattern, vm.pitch) {
+                    audio = (vm.audio_pattern, vm.pitch);
+                    tx.send(UpdateAudioPattern(audio.0, audio.1)).unwrap();
                 }
-                if vm.sound_timer > 0 {
-                    vm.sound_timer -= 1;
-                    if beeping != (vm.sound_timer > 0) {
+
+                // end of the frame, unless the debugger paused in the middle
+                if report.ended {
+                    frames = frames.wrapping_add(1);
+                    if frames.is_multiple_of(options.rewind_interval) {
+                        rewind.push(vm);
+                    }
+                    if beeping != report.sound {
                         beeping = !beeping;
                         tx.send(UpdateBeepingStatus(beeping)).unwrap();
                     }
+                    end_frame(vm, &mut player, &mut recording);
+                    report = FrameReport::default();
                 }
             }
         }
@@ -265,16 +716,95 @@ pub fn exec_vm(
     }
 }
 
+/// Apply the key events received to the virtual machine at the start of a
+/// frame, recording them ; the movie played back replaces them until its end.
+#[cfg(feature = "sdl2")]
+fn start_frame(
+    vm: &mut Vm,
+    keys: &mut Vec<(usize, Keystate)>,
+    player: &mut Option<Player>,
+    recording: &mut Option<(Movie, PathBuf)>,
+) {
+    if let Some(ref mut player) = *player {
+        if !player.is_finished() {
+            *keys = player.frame_events();
+        }
+    }
+    for (index, state) in keys.drain(..) {
+        match *recording {
+            Some((ref mut movie, _)) => movie.record_key(vm, index, state),
+            None => vm.keypad.key_event(index, state),
+        }
+    }
+}
+
+/// Check the movie played back, and record the frame, at the end of a frame.
+#[cfg(feature = "sdl2")]
+fn end_frame(vm: &Vm, player: &mut Option<Player>, recording: &mut Option<(Movie, PathBuf)>) {
+    if let Some(ref mut player) = *player {
+        if player.end_frame(vm) {
+            warn!("the replay diverged from the movie at frame {}.", player.frame - 1);
+        }
+        if player.frame == player.movie.frames() {
+            info!("the movie is over, the keyboard is back in control.");
+        }
+    }
+    if let Some((ref mut movie, _)) = *recording {
+        movie.record_frame(vm);
+    }
+}
+
+/// Warn that the movie recorded won't replay the session past the current
+/// frame, the virtual machine state being replaced by the given action.
+#[cfg(feature = "sdl2")]
+fn warn_unreplayable(recording: &Option<(Movie, PathBuf)>, action: &str) {
+    if let Some((ref movie, _)) = *recording {
+        warn!("the movie can't replay the {} at frame {}, it will go out of sync.", action, movie.frames());
+    }
+}
+
+/// Flush the execution trace, write the profile report and the movie
+/// recorded, and persist the RPL user flags of the virtual machine, before
+/// the end of its thread.
+#[cfg(feature = "sdl2")]
+fn shutdown(vm: &mut Vm, options: &VmOptions, recording: &Option<(Movie, PathBuf)>) {
+    if let Some(ref mut tracer) = vm.tracer {
+        tracer.flush();
+    }
+    if let Some(ref path) = options.profile_path {
+        write_profile(vm, path);
+    }
+    if let Some((ref movie, ref path)) = *recording {
+        write_movie(movie, path);
+    }
+    save_rpl_flags(vm, &options.rpl_path);
+}
+
+/// Persist the RPL user flags of the virtual machine, if the program used them.
+#[cfg(feature = "sdl2")]
+fn save_rpl_flags(vm: &Vm, rpl_path: &Path) {
+    if vm.rpl.iter().all(|&flag| flag == 0) && !rpl_path.exists() {
+        return;
+    }
+    match vm.save_rpl_flags(rpl_path) {
+        Ok(()) => info!("saved the RPL user flags to \"{}\".", rpl_path.display()),
+        Err(why) => warn!("couldn't save the RPL user flags : {}", why),
+    }
+}
+
 /// Return the best (pixel_scale, width, height) combination with the given
-pub fn get_display_size(w_width: u16, w_height: u16) -> (u16, u16, u16) {
-    let scale_w = w_width / (DISPLAY_WIDTH as u16);
-    let scale_h = w_height / (DISPLAY_HEIGHT as u16);
+/// window dimensions, for a display of the given resolution (which changes
+/// when a SUPER-CHIP program switches to the high resolution mode).
+#[cfg(feature = "sdl2")]
+pub fn get_display_size(w_width: u16, w_height: u16, d_width: usize, d_height: usize) -> (u16, u16, u16) {
+    let scale_w = w_width / (d_width as u16);
+    let scale_h = w_height / (d_height as u16);
     let scale = cmp::min(scale_w, scale_h);
 
     // adjust to the smallest scale and recompute the window dimensions
     (
         scale,
-        scale * (DISPLAY_WIDTH as u16),
-        scale * (DISPLAY_HEIGHT as u16),
+        scale * (d_width as u16),
+        scale * (d_height as u16),
     )
 }
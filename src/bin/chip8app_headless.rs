@@ -0,0 +1,161 @@
+use std::fs;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use crate::chip8app::{self, Chip8Config};
+use chip8vm::audio::{Buzzer, SoundRecorder};
+use chip8vm::capture::{Capture, CaptureFormat};
+use chip8vm::headless::{KeyPress, Limit, Runner};
+use chip8vm::rng::Rng;
+use chip8vm::screenshot::{self, TextStyle};
+use chip8vm::vm::Vm;
+
+/// The framebuffer dump written at the end of a headless run.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Output {
+    Pbm,
+    Png,
+    Text(TextStyle),
+    /// The hexadecimal hash of the screen, see 'screenshot::hash'.
+    Hash,
+}
+
+impl Output {
+    /// Return the output of the given name : pbm, png, ascii, unicode or hash.
+    pub fn from_name(name: &str) -> Option<Output> {
+        match &name.to_lowercase()[..] {
+            "pbm" => Some(Output::Pbm),
+            "png" => Some(Output::Png),
+            "ascii" => Some(Output::Text(TextStyle::Ascii)),
+            "unicode" => Some(Output::Text(TextStyle::Unicode)),
+            "hash" => Some(Output::Hash),
+            _ => None,
+        }
+    }
+}
+
+/// The options of a headless run, on top of the 'Chip8Config'.
+pub struct HeadlessOptions {
+    /// When the run stops.
+    pub limit: Limit,
+    /// The scripted key presses.
+    pub script: Vec<KeyPress>,
+    /// The framebuffer dump.
+    pub output: Output,
+    /// The file the dump is written to, the standard output if not set.
+    pub output_path: Option<PathBuf>,
+    /// The WAV file the sound is written to, if any.
+    pub wav_path: Option<PathBuf>,
+    /// The sample rate of the WAV file, in Hz.
+    pub sample_rate: u32,
+    /// The frames captured to the 'Chip8Config::capture_path' file.
+    pub capture_frames: RangeInclusive<u64>,
+}
+
+/// Run the given ROM without any window and dump the final screen.
+/// The random generator is seeded with 0 when the configuration has no seed,
+/// for the runs to be reproducible.
+/// Return the exit code of the process : 0 if all went well, 1 otherwise,
+/// e.g. when the movie played back goes out of sync.
+pub fn run_headless(config: &Chip8Config, options: &HeadlessOptions, rom_filepath: &Path) -> i32 {
+    let mut vm = Vm::with_quirks(config.vm_quirks);
+    let seed = config.vm_seed.unwrap_or(0);
+    vm.rng = Rng::new(seed);
+    vm.tracer = config.create_tracer();
+    vm.profiler = config.create_profiler();
+    if let Err(why) = vm.load(rom_filepath) {
+        error!("loading error : {}", why);
+        return 1;
+    }
+
+    let mut instructions_per_frame = config.vm_cpu_clock / 60;
+    let (player, recording) = match config.start_movies(&mut vm, seed, &mut instructions_per_frame) {
+        Ok(movies) => movies,
+        Err(why) => {
+            error!("couldn't read the movie : {}", why);
+            return 1;
+        }
+    };
+    let mut runner = Runner::new(vm, instructions_per_frame, options.script.clone());
+    runner.player = player;
+    runner.recording = recording;
+    if options.wav_path.is_some() {
+        let mut buzzer = Buzzer::new(options.sample_rate);
+        buzzer.waveform = config.audio_waveform;
+        buzzer.frequency = config.audio_frequency;
+        buzzer.volume = config.audio_volume;
+        runner.sound = Some(SoundRecorder::new(buzzer, options.sample_rate));
+    }
+    if let Some(ref path) = config.capture_path {
+        let format = CaptureFormat::from_path(path);
+        match Capture::create(path, format, config.capture_scale, config.palette) {
+            Ok(capture) => runner.capture = Some(capture),
+            Err(why) => {
+                error!("couldn't create the capture \"{}\" : {}", path.display(), why);
+                return 1;
+            }
+        }
+        runner.capture_frames = options.capture_frames.clone();
+    }
+    let mut status = 0;
+    match runner.run(options.limit) {
+        Ok(true) => info!("the program finished after {} frames.", runner.frame),
+        Ok(false) => info!("ran {} frames, {} cycles.", runner.frame, runner.cycles),
+        Err(why) => {
+            error!("the virtual machine stopped : {}", why);
+            status = 1;
+        }
+    }
+
+    if let Some(ref mut tracer) = runner.vm.tracer {
+        tracer.flush();
+    }
+    if let Some(ref path) = config.profile_path {
+        chip8app::write_profile(&runner.vm, path);
+    }
+    if let (Some(movie), Some(path)) = (&runner.recording, &config.record_path) {
+        chip8app::write_movie(movie, path);
+    }
+    if let Some(capture) = runner.capture.take() {
+        let start = *runner.capture_frames.start();
+        let end = runner.frame.min(runner.capture_frames.end().saturating_add(1));
+        match capture.finish(end.saturating_sub(start) as f64 / 60.0) {
+            Ok(frames) => info!("captured {} frames.", frames),
+            Err(why) => {
+                error!("couldn't write the capture : {}", why);
+                status = 1;
+            }
+        }
+    }
+    if let (Some(sound), Some(path)) = (&runner.sound, &options.wav_path) {
+        match fs::write(path, sound.to_wav()) {
+            Ok(()) => info!("wrote {} frames of sound to \"{}\".", runner.frame, path.display()),
+            Err(why) => {
+                error!("couldn't write the sound : {}", why);
+                status = 1;
+            }
+        }
+    }
+    if let Some(frame) = runner.player.as_ref().and_then(|player| player.diverged) {
+        error!("the replay went out of sync with the movie at frame {}.", frame);
+        status = 1;
+    }
+
+    let display = &runner.vm.display;
+    let data = match options.output {
+        Output::Pbm => screenshot::to_pbm(display),
+        Output::Png => screenshot::to_png(display, &config.palette),
+        Output::Text(style) => screenshot::to_text(display, style).into_bytes(),
+        Output::Hash => format!("{:016x}\n", screenshot::hash(display)).into_bytes(),
+    };
+    let result = match options.output_path {
+        Some(ref path) => fs::write(path, &data),
+        None => io::stdout().write_all(&data),
+    };
+    if let Err(why) = result {
+        error!("couldn't write the screen : {}", why);
+        status = 1;
+    }
+    status
+}
@@ -1,7 +1,11 @@
+use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use sdl2::audio::{AudioCallback, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::messagebox::{show_simple_message_box, MessageBoxFlag};
 use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
 use sdl2::render::{Texture, TextureCreator, WindowCanvas};
@@ -12,24 +16,66 @@ use crate::chip8app::Chip8VMCommand::*;
 use crate::chip8app::{
     get_display_size, Chip8Config, Chip8EmulatorBackend, Chip8UICommand, Chip8VMCommand,
 };
-use chip8vm;
-use chip8vm::display::{Display, DISPLAY_WIDTH, DISPLAY_HEIGHT};
+use chip8vm::audio::Buzzer;
+use chip8vm::capture::{Capture, CaptureFormat};
+use chip8vm::debugger::RunMode;
+use chip8vm::display::{Display, DISPLAY_WIDTH, DISPLAY_HEIGHT, PLANES_MASK};
 use chip8vm::keypad::Keystate::{Pressed, Released};
 
+/// Convert a 0xRRGGBB palette color to its SDL2 counterpart.
+fn sdl_color(rgb: u32) -> Color {
+    Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+}
 
-// todo : make this a backend-agnostic option
-const COLOR_PIXEL_OFF: Color = Color {
-    r: 0,
-    g: 0,
-    b: 0,
-    a: 0xFF,
-};
-const COLOR_PIXEL_ON: Color = Color {
-    r: 0xFF,
-    g: 0xFF,
-    b: 0xFF,
-    a: 0xFF,
-};
+/// Width in pixels of the screen border flashed by the visual bell.
+const BELL_BORDER: u32 = 4;
+
+/// Start capturing the screen to the configured file, or to a timestamped GIF
+/// in the current directory, starting with the given display if any.
+fn start_capture(config: &Chip8Config, screen: &Option<Display>) -> Option<Capture> {
+    let path = config.capture_path.clone().unwrap_or_else(|| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        PathBuf::from(format!("capture-{}.gif", now))
+    });
+    let format = CaptureFormat::from_path(&path);
+    let mut capture = match Capture::create(&path, format, config.capture_scale, config.palette) {
+        Ok(capture) => capture,
+        Err(why) => {
+            warn!("couldn't create the capture \"{}\" : {}", path.display(), why);
+            return None;
+        }
+    };
+    if let Some(ref display) = screen {
+        if let Err(why) = capture.add_frame(display, 0.0) {
+            warn!("couldn't write the capture : {}", why);
+            return None;
+        }
+    }
+    info!("capturing the screen to \"{}\".", path.display());
+    Some(capture)
+}
+
+/// Stop the capture, lasting 'time' seconds.
+fn stop_capture(capture: Capture, time: f64) {
+    match capture.finish(time) {
+        Ok(frames) => info!("captured {} frames.", frames),
+        Err(why) => warn!("couldn't write the capture : {}", why),
+    }
+}
+
+/// SDL2 audio callback playing the buzzer. The device plays all along, the
+/// buzzer fading in and out by itself.
+struct BuzzerAudio {
+    buzzer: Buzzer,
+}
+
+impl AudioCallback for BuzzerAudio {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.buzzer.fill(out);
+    }
+}
 
 /// The SDL2 backend for the Chip8 emulator.
 pub struct Chip8BackendSDL2;
@@ -40,9 +86,10 @@ impl Chip8BackendSDL2 {
         c: &mut WindowCanvas,
         display: Display,
         scale: u32,
+        palette: &[u32; 4],
     ) -> Texture<'c> {
-        let display_width = DISPLAY_WIDTH as u32;
-        let display_height = DISPLAY_HEIGHT as u32;
+        let display_width = display.width() as u32;
+        let display_height = display.height() as u32;
         let pixel_size = scale as i32;
 
         let mut texture = t
@@ -53,14 +100,16 @@ impl Chip8BackendSDL2 {
             )
             .unwrap();
         c.with_texture_canvas(&mut texture, |texture_canvas| {
-            texture_canvas.set_draw_color(COLOR_PIXEL_OFF);
+            texture_canvas.set_draw_color(sdl_color(palette[0]));
             texture_canvas.clear();
-            texture_canvas.set_draw_color(COLOR_PIXEL_ON);
             for y in 0i32..(display_height as i32) {
                 for x in 0i32..(display_width as i32) {
                     // TODO : precompute the used Rect ?
                     // since they only change at window resize...
-                    if display.gfx[y as usize][x as usize] == 1u8 {
+                    // one color per combination of the 2 XO-CHIP bitplanes
+                    let pixel = display.gfx[y as usize][x as usize] & PLANES_MASK;
+                    if pixel != 0 {
+                        texture_canvas.set_draw_color(sdl_color(palette[pixel as usize]));
                         let _ = texture_canvas.fill_rect(Rect::new(
                             x * pixel_size,
                             y * pixel_size,
@@ -74,6 +123,34 @@ impl Chip8BackendSDL2 {
             .unwrap();
         texture
     }
+
+    /// Draw the display in a window of the given size, with the border of
+    /// the visual bell if it rings.
+    fn draw_screen(
+        t: &TextureCreator<WindowContext>,
+        c: &mut WindowCanvas,
+        display: &Display,
+        window_size: (u16, u16),
+        palette: &[u32; 4],
+        bell: bool,
+    ) {
+        // the resolution may have changed since the last frame
+        // (SUPER-CHIP high resolution mode), keep filling the window
+        let (scale, d_width, d_height) =
+            get_display_size(window_size.0, window_size.1, display.width(), display.height());
+        let texture = Chip8BackendSDL2::render_display(t, c, display.clone(), scale as u32, palette);
+        let (d_width, d_height) = (d_width as u32, d_height as u32);
+        c.copy(&texture, None, Some(Rect::new(0, 0, d_width, d_height))).unwrap();
+        if bell {
+            c.set_draw_color(sdl_color(palette[1]));
+            let _ = c.fill_rects(&[
+                Rect::new(0, 0, d_width, BELL_BORDER),
+                Rect::new(0, (d_height - BELL_BORDER) as i32, d_width, BELL_BORDER),
+                Rect::new(0, 0, BELL_BORDER, d_height),
+                Rect::new((d_width - BELL_BORDER) as i32, 0, BELL_BORDER, d_height),
+            ]);
+        }
+    }
 }
 
 impl Chip8EmulatorBackend for Chip8BackendSDL2 {
@@ -88,7 +165,12 @@ impl Chip8EmulatorBackend for Chip8BackendSDL2 {
         info!("starting the main application / rendering thread");
 
         // window dimensions
-        let (scale, width, height) = get_display_size(config.window_width, config.window_height);
+        let (scale, width, height) = get_display_size(
+            config.window_width,
+            config.window_height,
+            DISPLAY_WIDTH,
+            DISPLAY_HEIGHT,
+        );
         info!("chosen scale : {} pixels per CHIP 8 pixel", scale);
 
         // window creation and rendering setup
@@ -96,6 +178,28 @@ impl Chip8EmulatorBackend for Chip8BackendSDL2 {
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
         let mut timer_subsystem = sdl_context.timer().unwrap();
+        // the emulation goes on without sound if no audio device is available
+        let audio_spec = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),
+            samples: None,
+        };
+        let mut audio_device = sdl_context
+            .audio()
+            .and_then(|audio| {
+                audio.open_playback(None, &audio_spec, |spec| {
+                    let mut buzzer = Buzzer::new(spec.freq as u32);
+                    buzzer.waveform = config.audio_waveform;
+                    buzzer.frequency = config.audio_frequency;
+                    buzzer.volume = config.audio_volume;
+                    BuzzerAudio { buzzer }
+                })
+            })
+            .map_err(|why| warn!("couldn't open the audio device : {}", why))
+            .ok();
+        if let Some(ref device) = audio_device {
+            device.resume();
+        }
         let window = video_subsystem
             .window(config.window_title, width as u32, height as u32)
             .position_centered()
@@ -105,10 +209,7 @@ impl Chip8EmulatorBackend for Chip8BackendSDL2 {
         let mut canvas = window.into_canvas().accelerated().build().unwrap();
         let texture_creator = canvas.texture_creator();
 
-        let pixel_size = scale as u32;
-        let display_width = DISPLAY_WIDTH as u32;
-        let display_height = DISPLAY_HEIGHT as u32;
-        canvas.set_draw_color(COLOR_PIXEL_OFF);
+        canvas.set_draw_color(sdl_color(config.palette[0]));
         canvas.clear();
         canvas.present();
 
@@ -129,6 +230,11 @@ impl Chip8EmulatorBackend for Chip8BackendSDL2 {
 
         // Emulation state
         let mut paused = false;
+        // the last display drawn, redrawn when the visual bell rings
+        let mut screen: Option<Display> = None;
+        let mut bell = false;
+        // the screen capture toggled by F8, with its start time in ms
+        let mut capture: Option<(Capture, u32)> = None;
 
         'main: loop {
             // Frame time
@@ -162,6 +268,40 @@ impl Chip8EmulatorBackend for Chip8BackendSDL2 {
                                 info!("Reinitializing the virtual machine.");
                                 tx.send(Reset).unwrap();
                             }
+                            // save and restore the state on F5 / F9
+                            Keycode::F5 => tx.send(SaveState).unwrap(),
+                            Keycode::F9 => tx.send(LoadState).unwrap(),
+                            // rewind while Tab is held
+                            Keycode::Tab => tx.send(UpdateRewindStatus(true)).unwrap(),
+                            // toggle the sound on M
+                            Keycode::M => {
+                                if let Some(ref mut device) = audio_device {
+                                    let mut audio = device.lock();
+                                    let muted = !audio.buzzer.is_muted();
+                                    audio.buzzer.set_muted(muted);
+                                    info!("{} the sound.", if muted { "muted" } else { "unmuted" });
+                                }
+                            }
+                            // start or stop capturing the screen on F8
+                            Keycode::F8 => {
+                                capture = match capture.take() {
+                                    Some((c, start)) => {
+                                        stop_capture(c, (t - start) as f64 / 1000.0);
+                                        None
+                                    }
+                                    None => start_capture(config, &screen).map(|c| (c, t)),
+                                };
+                            }
+                            // debugger stepping, the emulation pauses again once done
+                            Keycode::F10 | Keycode::F11 | Keycode::F12 => {
+                                let mode = match keycode.unwrap() {
+                                    Keycode::F10 => RunMode::StepOver,
+                                    Keycode::F11 => RunMode::StepInto,
+                                    _ => RunMode::StepOut,
+                                };
+                                paused = true;
+                                tx.send(Resume(mode)).unwrap();
+                            }
                             _ => {
                                 if !paused {
                                     if let Some(index) = key_binds.get(&keycode.unwrap()) {
@@ -179,7 +319,9 @@ impl Chip8EmulatorBackend for Chip8BackendSDL2 {
                                 break;
                             }
                         }
-                        if let Some(index) = key_binds.get(&keycode.unwrap()) {
+                        if keycode == Some(Keycode::Tab) {
+                            tx.send(UpdateRewindStatus(false)).unwrap();
+                        } else if let Some(index) = key_binds.get(&keycode.unwrap()) {
                             tx.send(UpdateKeyStatus(*index, Released)).unwrap();
                         }
                     }
@@ -188,40 +330,70 @@ impl Chip8EmulatorBackend for Chip8BackendSDL2 {
             }
 
             // Command from the VM
-            match rx.try_recv() {
-                // non-blocking receiving function
-                Ok(ui_command) => {
-                    match ui_command {
-                        UpdateBeepingStatus(beeping) => {
-                            // TODO
-                            if beeping {
-                                println!("BEEP !");
+            // non-blocking receiving function
+            if let Ok(ui_command) = rx.try_recv() {
+                match ui_command {
+                    UpdateBeepingStatus(beeping) => {
+                        if let Some(ref mut device) = audio_device {
+                            device.lock().buzzer.set_active(beeping);
+                        }
+                        if config.visual_bell {
+                            bell = beeping;
+                            if let Some(ref display) = screen {
+                                Chip8BackendSDL2::draw_screen(
+                                    &texture_creator,
+                                    &mut canvas,
+                                    display,
+                                    (width, height),
+                                    &config.palette,
+                                    bell,
+                                );
                             }
                         }
-                        UpdateDisplay(display) => {
-                            let texture = Chip8BackendSDL2::render_display(
-                                &texture_creator,
-                                &mut canvas,
-                                display,
-                                scale as u32,
-                            );
-                            canvas
-                                .copy(
-                                    &texture,
-                                    None,
-                                    Some(Rect::new(
-                                        0,
-                                        0,
-                                        display_width * pixel_size,
-                                        display_height * pixel_size,
-                                    )),
-                                )
-                                .unwrap();
+                    }
+                    UpdateAudioPattern(pattern, pitch) => {
+                        if let Some(ref mut device) = audio_device {
+                            device.lock().buzzer.set_pattern(pattern, pitch);
                         }
-                        Finished => break 'main,
                     }
+                    UpdateDisplay(display) => {
+                        Chip8BackendSDL2::draw_screen(
+                            &texture_creator,
+                            &mut canvas,
+                            &display,
+                            (width, height),
+                            &config.palette,
+                            bell,
+                        );
+                        if let Some((ref mut c, start)) = capture {
+                            let time = (timer_subsystem.ticks() - start) as f64 / 1000.0;
+                            if let Err(why) = c.add_frame(&display, time) {
+                                warn!("couldn't write the capture : {}", why);
+                                capture = None;
+                            }
+                        }
+                        screen = Some(display);
+                    }
+                    Paused(pc, reason) => {
+                        info!("paused at {:#05X} : {:?}", pc, reason);
+                        paused = true;
+                    }
+                    Crashed(why) => {
+                        let message = format!(
+                            "{}\n\nPress Backspace to reset or Escape to quit.",
+                            why
+                        );
+                        if let Err(why) = show_simple_message_box(
+                            MessageBoxFlag::ERROR,
+                            "CHIP 8 virtual machine error",
+                            &message,
+                            canvas.window(),
+                        ) {
+                            warn!("couldn't show the error message box : {}", why);
+                        }
+                    }
+                    Finished => break 'main,
                 }
-                _ => {}
             }
 
             // Always render at 60 FPS (allows framerate displayers to work)
@@ -232,6 +404,9 @@ impl Chip8EmulatorBackend for Chip8BackendSDL2 {
             update_timer += dt as f32;
         }
 
+        if let Some((c, start)) = capture {
+            stop_capture(c, (timer_subsystem.ticks() - start) as f64 / 1000.0);
+        }
         info!("terminating the main application thread")
     }
 }
@@ -0,0 +1,129 @@
+use std::io::{self, ErrorKind, Read};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::chip8app::Chip8VMCommand::{self, *};
+use crate::chip8app::DebugEvent;
+use chip8vm::gdb::{self, Packet, Response};
+
+/// Delay between two checks for a GDB interrupt request while the program runs.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Serve the GDB remote serial protocol on the given localhost port, one
+/// debugging session at a time, until the virtual machine's thread ends.
+/// The virtual machine is driven through 'tx', and reports its pauses in
+/// 'events'.
+pub fn serve(port: u16, tx: Sender<Chip8VMCommand>, events: Receiver<DebugEvent>) {
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(why) => {
+            error!("couldn't listen for GDB on port {} : {}", port, why);
+            return;
+        }
+    };
+    info!("waiting for GDB on 127.0.0.1:{} (target remote :{})", port, port);
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| session(stream, &tx, &events));
+        match result {
+            Ok(true) => info!("GDB disconnected."),
+            // the virtual machine's thread is gone
+            Ok(false) => return,
+            Err(why) => warn!("GDB session ended : {}", why),
+        }
+    }
+}
+
+/// Run a debugging session. Return false if the virtual machine's thread
+/// ended during it.
+fn session(mut stream: TcpStream, tx: &Sender<Chip8VMCommand>, events: &Receiver<DebugEvent>) -> io::Result<bool> {
+    info!("GDB connected from {}.", stream.peer_addr()?);
+    stream.set_nodelay(true)?;
+    // the program stays paused while GDB inspects it
+    if tx.send(UpdateRunStatus(false)).is_err() {
+        return Ok(false);
+    }
+    let mut no_ack = false;
+
+    loop {
+        let packet = match gdb::read_packet(&mut stream, no_ack) {
+            Ok(Packet::Command(packet)) => packet,
+            // already paused
+            Ok(Packet::Interrupt) => continue,
+            Err(ref why) if why.kind() == ErrorKind::UnexpectedEof => return Ok(true),
+            Err(why) => return Err(why),
+        };
+
+        // handled in the virtual machine's thread
+        let (reply_tx, reply_rx) = channel();
+        let command = packet.clone();
+        let inspect = Inspect(Box::new(move |vm, debugger| {
+            let _ = reply_tx.send(gdb::handle_packet(&command, vm, debugger));
+        }));
+        let response = match tx.send(inspect).ok().and_then(|()| reply_rx.recv().ok()) {
+            Some(response) => response,
+            None => return Ok(false),
+        };
+
+        match response {
+            Response::Reply(reply) => {
+                gdb::write_packet(&mut stream, &reply)?;
+                if packet == b"QStartNoAckMode" {
+                    no_ack = true;
+                }
+            }
+            Response::Resume(mode) => {
+                // forget the pauses GDB didn't wait for
+                while events.try_recv().is_ok() {}
+                if tx.send(Resume(mode)).is_err() {
+                    return Ok(false);
+                }
+                let reply = wait_for_stop(&mut stream, tx, events)?;
+                gdb::write_packet(&mut stream, &reply)?;
+            }
+            Response::Detach => {
+                gdb::write_packet(&mut stream, "OK")?;
+                let _ = tx.send(UpdateRunStatus(true));
+                return Ok(true);
+            }
+            Response::Kill => {
+                let _ = tx.send(Quit);
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// Wait until the running program pauses or GDB interrupts it, and return
+/// the stop reply.
+fn wait_for_stop(stream: &mut TcpStream, tx: &Sender<Chip8VMCommand>, events: &Receiver<DebugEvent>) -> io::Result<String> {
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let reply = loop {
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(DebugEvent::Paused(pc, reason)) => {
+                debug!("paused at {:#05X} for GDB : {:?}", pc, reason);
+                break gdb::stop_reply(reason);
+            }
+            Ok(DebugEvent::Crashed(pc)) => {
+                debug!("crashed at {:#05X}, reporting SIGABRT to GDB", pc);
+                break "S06".to_string();
+            }
+            Ok(DebugEvent::Finished) | Err(RecvTimeoutError::Disconnected) => break "W00".to_string(),
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+        let mut byte = [0u8];
+        match stream.read(&mut byte) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) if byte[0] == 0x03 => {
+                let _ = tx.send(UpdateRunStatus(false));
+                // SIGINT
+                break "S02".to_string();
+            }
+            Ok(_) => {}
+            Err(ref why) if why.kind() == ErrorKind::WouldBlock || why.kind() == ErrorKind::TimedOut => {}
+            Err(why) => return Err(why),
+        }
+    };
+    stream.set_read_timeout(None)?;
+    Ok(reply)
+}
@@ -1,9 +1,11 @@
-use self::sdl2::keyboard::Keycode;
-use sdl2;
+#[cfg(feature = "sdl2")]
+use sdl2::keyboard::Keycode;
+#[cfg(feature = "sdl2")]
 use std::collections::HashMap;
 
 /// Enumerates the supported keyboard bindings for the virtual keypad.
 /// TODO : add a Custom(...key bindings...) type, loaded from a file ?
+#[allow(clippy::upper_case_acronyms)]
 pub enum KeyboardBinding {
     QWERTY,
     AZERTY,
@@ -12,6 +14,7 @@ pub enum KeyboardBinding {
 /// Return the HashMap<Keycode, usize> corresponding to the
 /// given keyboard configuration which allows to simulate the virtual keypad.
 /// See 'chip8vm::keypad::Keypad' for the QWERTY binding.
+#[cfg(feature = "sdl2")]
 pub fn get_sdl_key_bindings(keyboard: &KeyboardBinding) -> HashMap<Keycode, usize> {
     let mut hm = HashMap::new();
 
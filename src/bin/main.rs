@@ -1,17 +1,33 @@
 use std::env;
-use std::path::Path;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process;
 
 #[macro_use]
 extern crate log;
-use env_logger;
-
 use getopts::{Matches, Options};
 
 mod chip8app;
+mod chip8app_headless;
+#[cfg(feature = "sdl2")]
 mod chip8app_sdl2;
+#[cfg(feature = "sdl2")]
+mod gdbserver;
 mod input;
-use crate::chip8app::{Chip8Config, Chip8Emulator, Chip8EmulatorBackend};
+#[cfg(feature = "sdl2")]
+use crate::chip8app::{Chip8Emulator, Chip8EmulatorBackend};
+use crate::chip8app::Chip8Config;
+use crate::chip8app_headless::{HeadlessOptions, Output};
+#[cfg(feature = "sdl2")]
 use crate::chip8app_sdl2::Chip8BackendSDL2;
+use chip8vm::asm;
+use chip8vm::audio::Waveform;
+use chip8vm::debugger::{self, Breakpoint, OpcodePattern};
+use chip8vm::headless::{KeyPress, Limit};
+use chip8vm::quirks::Quirks;
+use chip8vm::screenshot;
+use chip8vm::trace::TraceFormat;
 
 /// CPU clock hard limit.
 /// Above 5000Hz or so, without emulation throttling (thread::sleep_ms)
@@ -19,11 +35,93 @@ use crate::chip8app_sdl2::Chip8BackendSDL2;
 pub const CPU_CLOCK_MAX: u32 = 3000;
 
 fn print_usage(opts: Options) {
-    let brief = "rust-chip8 emulator.\n\nUsage:\n   rust-chip8 [OPTIONS] ROM_FILE\n";
-    println!("{}", opts.usage(&brief));
+    let brief = "rust-chip8 emulator.\n\nUsage:\n   rust-chip8 [OPTIONS] ROM_FILE\n   \
+                 rust-chip8 --headless [OPTIONS] ROM_FILE\n   \
+                 rust-chip8 asm [OPTIONS] SOURCE_FILE\n";
+    println!("{}", opts.usage(brief));
+}
+
+/// The 'asm' subcommand : assemble an Octo source file into a ROM.
+fn assemble_command(args: &[String]) {
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print this help menu.");
+    opts.optopt("o", "output", "The ROM file to write. SOURCE_FILE.ch8 by default.", "ROM_FILE");
+    opts.optopt("", "symbols", "Also write the address of every label to this file.", "SYMBOL_FILE");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(why) => panic!("{}", why),
+    };
+    if matches.opt_present("h") || matches.free.is_empty() {
+        println!("{}", opts.usage("Usage:\n   rust-chip8 asm [OPTIONS] SOURCE_FILE"));
+        return;
+    }
+    let source_path = Path::new(&matches.free[0]);
+    let source = match fs::read_to_string(source_path) {
+        Ok(source) => source,
+        Err(why) => {
+            eprintln!("{}: {}", source_path.display(), why);
+            process::exit(1);
+        }
+    };
+    let program = match asm::assemble(&source) {
+        Ok(program) => program,
+        Err(why) => {
+            eprintln!("{}:{}", source_path.display(), why);
+            process::exit(1);
+        }
+    };
+
+    let rom_path = match matches.opt_str("o") {
+        Some(path) => PathBuf::from(path),
+        None => source_path.with_extension("ch8"),
+    };
+    if let Err(why) = fs::write(&rom_path, &program.rom) {
+        eprintln!("{}: {}", rom_path.display(), why);
+        process::exit(1);
+    }
+    info!("assembled {} bytes to \"{}\".", program.rom.len(), rom_path.display());
+
+    if let Some(path) = matches.opt_str("symbols") {
+        let symbols: String = program
+            .symbols
+            .iter()
+            .map(|(name, address)| format!("{:#06X} {}\n", address, name))
+            .collect();
+        if let Err(why) = fs::write(&path, symbols) {
+            eprintln!("{}: {}", path, why);
+            process::exit(1);
+        }
+    }
+}
+
+/// Parse a palette of 4 comma-separated RRGGBB hexadecimal colors.
+fn parse_palette(string: &str) -> Option<[u32; 4]> {
+    let colors: Vec<u32> = string
+        .split(',')
+        .map(|c| u32::from_str_radix(c.trim().trim_start_matches('#'), 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+    if colors.len() != 4 || colors.iter().any(|&c| c > 0xFFFFFF) {
+        return None;
+    }
+    Some([colors[0], colors[1], colors[2], colors[3]])
+}
+
+/// Parse an address range written "START-END", both included, e.g.
+/// "0x200-0x2FF".
+fn parse_address_range(string: &str) -> Option<RangeInclusive<u16>> {
+    let index = string.find('-')?;
+    let start = debugger::parse_number(string[..index].trim())?;
+    let end = debugger::parse_number(string[index + 1..].trim())?;
+    if start > end {
+        return None;
+    }
+    Some(start..=end)
 }
 
-fn config_from_matches(matches: &Matches) -> Chip8Config {
+/// Return the configuration of the options, or an error for an option that
+/// can't be ignored.
+fn config_from_matches(matches: &Matches) -> Result<Chip8Config, String> {
     let mut config = Chip8Config::new();
 
     let keyboard_config = match matches.opt_str("k") {
@@ -42,8 +140,8 @@ fn config_from_matches(matches: &Matches) -> Chip8Config {
     };
     config = config.key_binds(keyboard_config);
 
-    match matches.opt_str("c") {
-        Some(ref string) => match string.parse::<u32>() {
+    if let Some(ref string) = matches.opt_str("c") {
+        match string.parse::<u32>() {
             Ok(cpu_clock) => {
                 if cpu_clock > CPU_CLOCK_MAX {
                     warn!("CPU clock too high, reverting to the default.");
@@ -52,11 +150,231 @@ fn config_from_matches(matches: &Matches) -> Chip8Config {
                 }
             }
             Err(_) => warn!("\"{}\" is not a valid CPU clock number", string),
-        },
-        _ => {}
+        }
+    }
+
+    if let Some(ref string) = matches.opt_str("seed") {
+        match string.parse::<u64>() {
+            Ok(seed) => config = config.seed(Some(seed)),
+            Err(_) => warn!("\"{}\" is not a valid seed", string),
+        }
+    }
+
+    if let Some(ref string) = matches.opt_str("palette") {
+        match parse_palette(string) {
+            Some(palette) => config = config.palette(palette),
+            None => warn!("\"{}\" is not a valid palette, reverting to the default.", string),
+        }
+    }
+
+    if let Some(ref string) = matches.opt_str("waveform") {
+        match Waveform::from_name(string) {
+            Some(waveform) => config = config.audio_waveform(waveform),
+            None => warn!("unrecognized waveform \"{}\".", string),
+        }
+    }
+
+    if let Some(ref string) = matches.opt_str("frequency") {
+        match string.parse::<f32>() {
+            Ok(frequency) if frequency > 0.0 && frequency < 20000.0 => config = config.audio_frequency(frequency),
+            _ => warn!("\"{}\" is not a valid frequency", string),
+        }
+    }
+
+    if let Some(ref string) = matches.opt_str("volume") {
+        match string.parse::<u8>() {
+            Ok(volume) if volume <= 100 => config = config.audio_volume(volume as f32 / 100.0),
+            _ => warn!("\"{}\" is not a valid volume", string),
+        }
+    }
+
+    if matches.opt_present("visual-bell") {
+        config = config.visual_bell(true);
     }
 
-    config
+    if let Some(ref string) = matches.opt_str("rewind-interval") {
+        match string.parse::<u32>() {
+            Ok(frames) if frames > 0 => config = config.rewind_interval(frames),
+            _ => warn!("\"{}\" is not a valid rewind interval", string),
+        }
+    }
+
+    if let Some(ref string) = matches.opt_str("rewind-capacity") {
+        match string.parse::<usize>() {
+            Ok(snapshots) => config = config.rewind_capacity(snapshots),
+            Err(_) => warn!("\"{}\" is not a valid rewind capacity", string),
+        }
+    }
+
+    if let Some(path) = matches.opt_str("state") {
+        config = config.state_path(Some(PathBuf::from(path)));
+    }
+
+    if let Some(dir) = matches.opt_str("rpl-dir") {
+        config = config.rpl_dir(PathBuf::from(dir));
+    }
+
+    let mut breakpoints = Vec::new();
+    for string in matches.opt_strs("break") {
+        match Breakpoint::parse(&string) {
+            Some(breakpoint) => breakpoints.push(breakpoint),
+            None => warn!("\"{}\" is not a valid breakpoint", string),
+        }
+    }
+    config = config.breakpoints(breakpoints);
+
+    let mut opcode_breaks = Vec::new();
+    for string in matches.opt_strs("break-opcode") {
+        match OpcodePattern::parse(&string) {
+            Some(pattern) => opcode_breaks.push(pattern),
+            None => warn!("\"{}\" is not a valid opcode pattern", string),
+        }
+    }
+    config = config.opcode_breaks(opcode_breaks);
+
+    if let Some(ref string) = matches.opt_str("gdb") {
+        match string.parse::<u16>() {
+            Ok(port) => config = config.gdb_port(Some(port)),
+            Err(_) => warn!("\"{}\" is not a valid port", string),
+        }
+    }
+
+    if let Some(path) = matches.opt_str("trace") {
+        config = config.trace_path(Some(PathBuf::from(path)));
+    }
+
+    if let Some(ref string) = matches.opt_str("trace-format") {
+        match TraceFormat::from_name(string) {
+            Some(format) => config = config.trace_format(format),
+            None => warn!("unrecognized trace format \"{}\".", string),
+        }
+    }
+
+    if let Some(ref string) = matches.opt_str("trace-range") {
+        match parse_address_range(string) {
+            Some(range) => config = config.trace_range(range),
+            None => warn!("\"{}\" is not a valid address range", string),
+        }
+    }
+
+    if let Some(ref string) = matches.opt_str("trace-max-size") {
+        match string.parse::<u64>() {
+            Ok(size) => config = config.trace_max_size(Some(size)),
+            Err(_) => warn!("\"{}\" is not a valid trace size", string),
+        }
+    }
+
+    if let Some(path) = matches.opt_str("profile") {
+        config = config.profile_path(Some(PathBuf::from(path)));
+    }
+
+    if let Some(path) = matches.opt_str("symbols") {
+        config = config.symbols_path(Some(PathBuf::from(path)));
+    }
+
+    if let Some(path) = matches.opt_str("capture") {
+        config = config.capture_path(Some(PathBuf::from(path)));
+    }
+
+    if let Some(ref string) = matches.opt_str("capture-scale") {
+        match string.parse::<usize>() {
+            Ok(scale) if (1..=16).contains(&scale) => config = config.capture_scale(scale),
+            _ => warn!("\"{}\" is not a valid capture scale", string),
+        }
+    }
+
+    if let Some(path) = matches.opt_str("record") {
+        config = config.record_path(Some(PathBuf::from(path)));
+    }
+
+    if let Some(path) = matches.opt_str("play") {
+        config = config.play_path(Some(PathBuf::from(path)));
+    }
+
+    if let Some(ref string) = matches.opt_str("q") {
+        match Quirks::from_name(string) {
+            Some(quirks) => config = config.quirks(quirks),
+            None => return Err(format!("unrecognized quirk profile \"{}\".", string)),
+        }
+    }
+
+    if let Some(ref string) = matches.opt_str("key-wait") {
+        let mut quirks = config.vm_quirks;
+        match &string.to_uppercase()[..] {
+            "PRESS" => quirks.key_wait_release = false,
+            "RELEASE" => quirks.key_wait_release = true,
+            _ => warn!("unrecognized key wait mode \"{}\".", string),
+        }
+        config = config.quirks(quirks);
+    }
+
+    Ok(config)
+}
+
+/// Return the options of the headless mode, or an error message.
+fn headless_options_from_matches(matches: &Matches) -> Result<HeadlessOptions, String> {
+    let parse_count = |name: &str| -> Result<Option<u64>, String> {
+        match matches.opt_str(name) {
+            Some(string) => match string.parse::<u64>() {
+                Ok(count) => Ok(Some(count)),
+                Err(_) => Err(format!("\"{}\" is not a valid number of {}", string, name)),
+            },
+            None => Ok(None),
+        }
+    };
+    let limit = match (parse_count("cycles")?, parse_count("frames")?) {
+        (Some(cycles), _) => Limit::Cycles(cycles),
+        (None, Some(frames)) => Limit::Frames(frames),
+        (None, None) => Limit::Frames(600),
+    };
+
+    let mut script = Vec::new();
+    if let Some(path) = matches.opt_str("keys") {
+        let text = fs::read_to_string(&path).map_err(|why| format!("{}: {}", path, why))?;
+        script = KeyPress::parse_script(&text).map_err(|why| format!("{}: {}", path, why))?;
+    }
+    for string in matches.opt_strs("key") {
+        match KeyPress::parse(&string) {
+            Some(press) => script.push(press),
+            None => return Err(format!("\"{}\" is not a valid key press", string)),
+        }
+    }
+
+    let capture_frames = match matches.opt_str("capture-frames") {
+        Some(string) => {
+            let range = string.split_once('-').and_then(|(first, last)| {
+                Some(first.trim().parse::<u64>().ok()?..=last.trim().parse::<u64>().ok()?)
+            });
+            match range {
+                Some(range) if !range.is_empty() => range,
+                _ => return Err(format!("\"{}\" is not a valid frame range", string)),
+            }
+        }
+        None => 0..=u64::MAX,
+    };
+
+    let sample_rate = match matches.opt_str("sample-rate") {
+        Some(string) => match string.parse::<u32>() {
+            Ok(rate) if (8000..=192_000).contains(&rate) => rate,
+            _ => return Err(format!("\"{}\" is not a valid sample rate", string)),
+        },
+        None => 44100,
+    };
+
+    let output = match matches.opt_str("format") {
+        Some(name) => Output::from_name(&name).ok_or(format!("unrecognized output format \"{}\"", name))?,
+        None => Output::Text(screenshot::TextStyle::Ascii),
+    };
+
+    Ok(HeadlessOptions {
+        limit,
+        script,
+        output,
+        output_path: matches.opt_str("o").map(PathBuf::from),
+        wav_path: matches.opt_str("wav").map(PathBuf::from),
+        sample_rate,
+        capture_frames,
+    })
 }
 
 fn main() {
@@ -64,6 +382,10 @@ fn main() {
 
     // Program options
     let args: Vec<String> = env::args().collect();
+    if args.get(1).map(|arg| &arg[..]) == Some("asm") {
+        assemble_command(&args[2..]);
+        return;
+    }
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "Print this help menu.");
@@ -79,9 +401,201 @@ fn main() {
         "The keyboard configuration to use. QWERTY by default.",
         "QWERTY/AZERTY",
     );
+    opts.optopt(
+        "q",
+        "quirks",
+        "The quirk profile of the emulated interpreter. VIP by default.",
+        "VIP/CHIP48/SCHIP/XOCHIP",
+    );
+    opts.optopt(
+        "",
+        "key-wait",
+        "When FX0A ends : on the key press, or on its release. Set by the quirk profile by default.",
+        "PRESS/RELEASE",
+    );
+    opts.optopt(
+        "",
+        "seed",
+        "The seed of the random generator, for reproducible runs. Random by default.",
+        "SEED",
+    );
+    opts.optopt(
+        "",
+        "palette",
+        "The colors of the background, of the 2 XO-CHIP bitplanes and of their overlap.",
+        "RRGGBB,RRGGBB,RRGGBB,RRGGBB",
+    );
+    opts.optopt(
+        "",
+        "waveform",
+        "The waveform of the buzzer. SQUARE by default ; XO-CHIP programs may play their own pattern.",
+        "SQUARE/SINE/TRIANGLE",
+    );
+    opts.optopt("", "frequency", "The frequency of the buzzer in Hz. 500 by default.", "HZ");
+    opts.optopt("", "volume", "The volume of the buzzer, in percent. 25 by default. M toggles the sound.", "PERCENT");
+    opts.optflag("", "visual-bell", "Flash the border of the screen while the buzzer sounds.");
+    opts.optopt(
+        "",
+        "rewind-interval",
+        "The number of frames between two rewind snapshots. 2 by default.",
+        "FRAMES",
+    );
+    opts.optopt(
+        "",
+        "rewind-capacity",
+        "The maximum number of rewind snapshots, rewound with Tab. 9000 by default.",
+        "SNAPSHOTS",
+    );
+    opts.optopt(
+        "",
+        "state",
+        "The save state file, saved with F5 and restored with F9. ROM_FILE.state by default.",
+        "STATE_FILE",
+    );
+    opts.optopt(
+        "",
+        "rpl-dir",
+        "The directory where the SUPER-CHIP RPL user flags are saved. ~/.impl-chip8/rpl by default.",
+        "DIRECTORY",
+    );
+    opts.optmulti(
+        "",
+        "break",
+        "Pause before the instruction at ADDRESS, if CONDITION (e.g. \"V3 == 0x10\") holds. \
+         Step with F10 (over), F11 (into) and F12 (out), resume with Return.",
+        "ADDRESS[,CONDITION]",
+    );
+    opts.optmulti(
+        "",
+        "break-opcode",
+        "Pause before the instructions matching PATTERN, e.g. DXYN or FX0A.",
+        "PATTERN",
+    );
+    opts.optopt(
+        "",
+        "gdb",
+        "Serve the GDB remote serial protocol on this localhost port ; the emulation starts paused.",
+        "PORT",
+    );
+    opts.optopt(
+        "",
+        "trace",
+        "Write a record of every instruction executed to this file.",
+        "TRACE_FILE",
+    );
+    opts.optopt(
+        "",
+        "trace-format",
+        "The format of the trace. TEXT by default.",
+        "TEXT/BINARY",
+    );
+    opts.optopt(
+        "",
+        "trace-range",
+        "Only trace the instructions between these addresses, both included.",
+        "START-END",
+    );
+    opts.optopt(
+        "",
+        "trace-max-size",
+        "Stop the trace once it reaches this size.",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "profile",
+        "Write the execution profile report (hot spots, subroutines and opcodes) to this file at exit.",
+        "REPORT_FILE",
+    );
+    opts.optopt(
+        "",
+        "symbols",
+        "The label addresses written by the asm subcommand, naming the addresses of the profile report.",
+        "SYMBOL_FILE",
+    );
+    opts.optopt(
+        "",
+        "capture",
+        "The animated GIF, or Y4M video with a .y4m extension, F8 captures the screen to. Headless : \
+         capture the run.",
+        "FILE",
+    );
+    opts.optopt("", "capture-scale", "The integer scale of the screen capture. 4 by default.", "SCALE");
+    opts.optopt(
+        "",
+        "record",
+        "Record the key presses of the session, with the ROM hash, seed and quirks, to this movie file.",
+        "MOVIE_FILE",
+    );
+    opts.optopt(
+        "",
+        "play",
+        "Play back the key presses of this movie file, reporting the frame the replay goes out of sync at.",
+        "MOVIE_FILE",
+    );
+    opts.optflag(
+        "",
+        "headless",
+        "Run without any window, then write the final screen out.",
+    );
+    opts.optopt(
+        "",
+        "frames",
+        "Headless : the number of 60 Hz frames to run. 600 by default.",
+        "FRAMES",
+    );
+    opts.optopt(
+        "",
+        "cycles",
+        "Headless : the number of CPU cycles to run, instead of a number of frames.",
+        "CYCLES",
+    );
+    opts.optmulti(
+        "",
+        "key",
+        "Headless : hold the hexadecimal KEY down at FRAME, for FRAMES frames (1 by default).",
+        "FRAME:KEY[:FRAMES]",
+    );
+    opts.optopt(
+        "",
+        "keys",
+        "Headless : read the key presses from this file, in the --key syntax, separated by \
+         whitespace or commas.",
+        "SCRIPT_FILE",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "Headless : the format of the final screen. ASCII by default.",
+        "PBM/PNG/ASCII/UNICODE/HASH",
+    );
+    opts.optopt(
+        "o",
+        "output",
+        "Headless : the file the final screen is written to. The standard output by default.",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "wav",
+        "Headless : write the sound of the run to this WAV file, each frame lasting exactly 1/60 s.",
+        "WAV_FILE",
+    );
+    opts.optopt(
+        "",
+        "capture-frames",
+        "Headless : only capture these frames, both included.",
+        "FIRST-LAST",
+    );
+    opts.optopt(
+        "",
+        "sample-rate",
+        "Headless : the sample rate of the WAV file in Hz. 44100 by default.",
+        "HZ",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(why) => panic!(why.to_string()),
+        Err(why) => panic!("{}", why),
     };
     if matches.opt_present("h") {
         print_usage(opts);
@@ -94,8 +608,32 @@ fn main() {
         return;
     };
 
+    let config = match config_from_matches(&matches) {
+        Ok(config) => config,
+        Err(why) => {
+            eprintln!("{}", why);
+            process::exit(1);
+        }
+    };
+    if matches.opt_present("headless") {
+        let options = match headless_options_from_matches(&matches) {
+            Ok(options) => options,
+            Err(why) => {
+                eprintln!("{}", why);
+                process::exit(1);
+            }
+        };
+        process::exit(chip8app_headless::run_headless(&config, &options, Path::new(&rom_file)));
+    }
+
+    run_window(config, Path::new(&rom_file));
+}
+
+/// Run the given ROM in the SDL2 window.
+#[cfg(feature = "sdl2")]
+fn run_window(config: Chip8Config, rom_filepath: &Path) {
     // Chip 8 virtual machine creation
-    let config = config_from_matches(&matches)
+    let config = config
         .w_title("rust-chip8 emulator")
         .w_width(800)
         .w_height(600);
@@ -103,8 +641,14 @@ fn main() {
     let mut emulator = Chip8Emulator::new(config, backend);
 
     // Load the ROM and start the emulation
-    let rom_filepath = Path::new(&rom_file);
-    if !emulator.run_rom(&rom_filepath) {
+    if !emulator.run_rom(rom_filepath) {
         panic!("error while loading or running the ROM.");
     }
 }
+
+/// Built without the SDL2 frontend : only the headless mode is available.
+#[cfg(not(feature = "sdl2"))]
+fn run_window(_config: Chip8Config, _rom_filepath: &Path) {
+    eprintln!("built without the SDL2 frontend (the 'sdl2' feature) : only --headless is available.");
+    process::exit(1);
+}
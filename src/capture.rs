@@ -0,0 +1,422 @@
+//! Capture of the screen as a video, without any external encoder : an
+//! animated GIF or an uncompressed YUV4MPEG2 (Y4M) stream.
+//!
+//! The video is always 128 x 64 CHIP-8 pixels, the low resolution screens
+//! being doubled, magnified by an integer scale. Each frame lasts until the
+//! next one : the frames are added with their time, e.g. only when the
+//! display changes.
+
+use crate::display::{Display, HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH, PLANES_MASK};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The frame rate of the Y4M streams, the one of the CHIP-8 timers.
+const Y4M_FRAME_RATE: f64 = 60.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CaptureFormat {
+    /// An animated GIF looping forever, with the timing rounded to the
+    /// hundredth of a second.
+    Gif,
+    /// An uncompressed 4:4:4 YUV4MPEG2 stream at 60 frames per second.
+    Y4m,
+}
+
+impl CaptureFormat {
+    /// Return the format of the given name (case insensitive) : "gif" or
+    /// "y4m".
+    pub fn from_name(name: &str) -> Option<CaptureFormat> {
+        match &name.to_lowercase()[..] {
+            "gif" => Some(CaptureFormat::Gif),
+            "y4m" => Some(CaptureFormat::Y4m),
+            _ => None,
+        }
+    }
+
+    /// Return the format matching the extension of the given file, GIF by
+    /// default.
+    pub fn from_path(path: &Path) -> CaptureFormat {
+        path.extension()
+            .and_then(|extension| CaptureFormat::from_name(&extension.to_string_lossy()))
+            .unwrap_or(CaptureFormat::Gif)
+    }
+}
+
+/// Return the palette indexes of the pixels of the display, at the high
+/// resolution.
+fn frame_pixels(display: &Display) -> Vec<u8> {
+    let zoom = HIRES_DISPLAY_WIDTH / display.width();
+    let mut pixels = Vec::with_capacity(HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT);
+    for y in 0..HIRES_DISPLAY_HEIGHT {
+        let row = &display.gfx[y / zoom];
+        pixels.extend((0..HIRES_DISPLAY_WIDTH).map(|x| row[x / zoom] & PLANES_MASK));
+    }
+    pixels
+}
+
+/// Writer of a screen capture.
+pub struct Capture {
+    out: Box<dyn Write + Send>,
+    format: CaptureFormat,
+    scale: usize,
+    /// The 0xRRGGBB colors, indexed by the XO-CHIP bitplanes.
+    palette: [u32; 4],
+    /// The frame waiting for the next one to know its duration, and its
+    /// time in seconds.
+    pending: Option<(Vec<u8>, f64)>,
+    /// The number of frames written.
+    frames: u64,
+}
+
+impl Capture {
+    /// Create a capture writing to 'out' in the given format, magnified by
+    /// 'scale', with the given palette of 0xRRGGBB colors.
+    pub fn new(
+        out: Box<dyn Write + Send>,
+        format: CaptureFormat,
+        scale: usize,
+        palette: [u32; 4],
+    ) -> io::Result<Capture> {
+        let mut capture = Capture {
+            out,
+            format,
+            scale: scale.max(1),
+            palette,
+            pending: None,
+            frames: 0,
+        };
+        let header = match format {
+            CaptureFormat::Gif => capture.gif_header(),
+            CaptureFormat::Y4m => {
+                let (w, h) = capture.size();
+                format!("YUV4MPEG2 W{} H{} F60:1 Ip A1:1 C444\n", w, h).into_bytes()
+            }
+        };
+        capture.out.write_all(&header)?;
+        Ok(capture)
+    }
+
+    /// Create a capture writing to the given file.
+    pub fn create(path: &Path, format: CaptureFormat, scale: usize, palette: [u32; 4]) -> io::Result<Capture> {
+        Capture::new(Box::new(BufWriter::new(File::create(path)?)), format, scale, palette)
+    }
+
+    /// Return the size of the video, in pixels.
+    pub fn size(&self) -> (usize, usize) {
+        (HIRES_DISPLAY_WIDTH * self.scale, HIRES_DISPLAY_HEIGHT * self.scale)
+    }
+
+    /// Return the number of frames written so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Add the display as the frame starting at the given time, in seconds
+    /// since the start of the capture. A frame like the previous one only
+    /// extends it.
+    pub fn add_frame(&mut self, display: &Display, time: f64) -> io::Result<()> {
+        let pixels = frame_pixels(display);
+        if self.pending.as_ref().is_some_and(|(pending, _)| *pending == pixels) {
+            return Ok(());
+        }
+        self.write_pending(time)?;
+        self.pending = Some((pixels, time));
+        Ok(())
+    }
+
+    /// Write the last frame, lasting until the given time, and flush the
+    /// capture. Return the number of frames written.
+    pub fn finish(mut self, time: f64) -> io::Result<u64> {
+        if let Some((_, start)) = self.pending {
+            // at least one frame long
+            self.write_pending(time.max(start + 1.0 / Y4M_FRAME_RATE))?;
+        }
+        if self.format == CaptureFormat::Gif {
+            self.out.write_all(&[0x3B])?;
+        }
+        self.out.flush()?;
+        Ok(self.frames)
+    }
+
+    /// Write the pending frame, lasting until the given time ; a frame too
+    /// short for the time resolution of the format is dropped.
+    fn write_pending(&mut self, end: f64) -> io::Result<()> {
+        let (pixels, start) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        match self.format {
+            CaptureFormat::Gif => {
+                let delay = (end * 100.0).round() as u64 - (start * 100.0).round() as u64;
+                if delay > 0 {
+                    let image = self.gif_image(&pixels, delay.min(0xFFFF) as u16);
+                    self.out.write_all(&image)?;
+                    self.frames += 1;
+                }
+            }
+            CaptureFormat::Y4m => {
+                let count = (end * Y4M_FRAME_RATE).round() as u64 - (start * Y4M_FRAME_RATE).round() as u64;
+                if count > 0 {
+                    let frame = self.y4m_frame(&pixels);
+                    for _ in 0..count {
+                        self.out.write_all(&frame)?;
+                    }
+                    self.frames += count;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the scaled pixels, row by row.
+    fn scaled(&self, pixels: &[u8]) -> Vec<u8> {
+        let (w, h) = self.size();
+        let mut scaled = Vec::with_capacity(w * h);
+        for row in pixels.chunks(HIRES_DISPLAY_WIDTH) {
+            let line: Vec<u8> = row.iter().flat_map(|&pixel| vec![pixel; self.scale]).collect();
+            for _ in 0..self.scale {
+                scaled.extend_from_slice(&line);
+            }
+        }
+        scaled
+    }
+
+    /// Return the GIF header : the screen descriptor, the palette and the
+    /// extension looping the animation.
+    fn gif_header(&self) -> Vec<u8> {
+        let (w, h) = self.size();
+        let mut header = b"GIF89a".to_vec();
+        header.extend_from_slice(&(w as u16).to_le_bytes());
+        header.extend_from_slice(&(h as u16).to_le_bytes());
+        // a global color table of 4 colors, the background being the first
+        header.extend_from_slice(&[0xF1, 0, 0]);
+        for color in self.palette.iter() {
+            header.extend_from_slice(&color.to_be_bytes()[1..]);
+        }
+        header.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+        header
+    }
+
+    /// Return a GIF image, with its graphic control extension holding its
+    /// delay in hundredths of a second.
+    fn gif_image(&self, pixels: &[u8], delay: u16) -> Vec<u8> {
+        let (w, h) = self.size();
+        let mut image = vec![0x21, 0xF9, 0x04, 0x00];
+        image.extend_from_slice(&delay.to_le_bytes());
+        image.extend_from_slice(&[0, 0]);
+        image.push(0x2C);
+        image.extend_from_slice(&[0, 0, 0, 0]);
+        image.extend_from_slice(&(w as u16).to_le_bytes());
+        image.extend_from_slice(&(h as u16).to_le_bytes());
+        image.push(0);
+        image.push(GIF_MIN_CODE_SIZE);
+        let data = lzw_encode(&self.scaled(pixels));
+        for block in data.chunks(255) {
+            image.push(block.len() as u8);
+            image.extend_from_slice(block);
+        }
+        image.push(0);
+        image
+    }
+
+    /// Return a Y4M frame, in the BT.601 limited range.
+    fn y4m_frame(&self, pixels: &[u8]) -> Vec<u8> {
+        let colors: Vec<[u8; 3]> = self
+            .palette
+            .iter()
+            .map(|&color| {
+                let (r, g, b) = ((color >> 16) as f64, ((color >> 8) & 0xFF) as f64, (color & 0xFF) as f64);
+                let y = 16.0 + 0.257 * r + 0.504 * g + 0.098 * b;
+                let u = 128.0 - 0.148 * r - 0.291 * g + 0.439 * b;
+                let v = 128.0 + 0.439 * r - 0.368 * g - 0.071 * b;
+                [y.round() as u8, u.round() as u8, v.round() as u8]
+            })
+            .collect();
+        let scaled = self.scaled(pixels);
+        let mut frame = b"FRAME\n".to_vec();
+        // the Y, U and V planes
+        for plane in (0..3).map(|n| colors.iter().map(|color| color[n]).collect::<Vec<u8>>()) {
+            frame.extend(scaled.iter().map(|&pixel| plane[pixel as usize]));
+        }
+        frame
+    }
+}
+
+/// The minimum LZW code size of the 4 colors GIF images.
+const GIF_MIN_CODE_SIZE: u8 = 2;
+
+/// The largest GIF LZW code, the table being reset once reached.
+const GIF_MAX_CODE: u16 = 4095;
+
+/// Return the GIF LZW compression of the given palette indexes.
+fn lzw_encode(indexes: &[u8]) -> Vec<u8> {
+    let clear = 1u16 << GIF_MIN_CODE_SIZE;
+    let end = clear + 1;
+    let mut out = Vec::new();
+    let (mut bits, mut nbits) = (0u32, 0u32);
+    let mut emit = |code: u16, size: u32, out: &mut Vec<u8>| {
+        bits |= (code as u32) << nbits;
+        nbits += size;
+        while nbits >= 8 {
+            out.push(bits as u8);
+            bits >>= 8;
+            nbits -= 8;
+        }
+    };
+
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut size = GIF_MIN_CODE_SIZE as u32 + 1;
+    emit(clear, size, &mut out);
+    let mut prefix: Option<u16> = None;
+    for &index in indexes {
+        prefix = match prefix {
+            None => Some(index as u16),
+            Some(code) => match table.get(&(code, index)) {
+                Some(&longer) => Some(longer),
+                None => {
+                    emit(code, size, &mut out);
+                    if next > GIF_MAX_CODE {
+                        emit(clear, size, &mut out);
+                        table.clear();
+                        next = end + 1;
+                        size = GIF_MIN_CODE_SIZE as u32 + 1;
+                    } else {
+                        table.insert((code, index), next);
+                        if next == 1 << size && size < 12 {
+                            size += 1;
+                        }
+                        next += 1;
+                    }
+                    Some(index as u16)
+                }
+            },
+        };
+    }
+    if let Some(code) = prefix {
+        emit(code, size, &mut out);
+    }
+    emit(end, size, &mut out);
+    emit(0, 7, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A capture output readable once the capture is finished.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Decode GIF LZW data, for the test.
+    fn lzw_decode(data: &[u8]) -> Vec<u8> {
+        let clear = 1usize << GIF_MIN_CODE_SIZE;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let reset = |table: &mut Vec<Vec<u8>>| {
+            *table = (0..clear as u8).map(|i| vec![i]).collect();
+            table.push(Vec::new());
+            table.push(Vec::new());
+        };
+        reset(&mut table);
+        let (mut size, mut position) = (GIF_MIN_CODE_SIZE as usize + 1, 0);
+        let mut out = Vec::new();
+        let mut previous: Option<Vec<u8>> = None;
+        loop {
+            let code = (0..size).fold(0, |code, i| {
+                let bit = (data[(position + i) / 8] >> ((position + i) % 8)) & 1;
+                code | (bit as usize) << i
+            });
+            position += size;
+            if code == clear {
+                reset(&mut table);
+                size = GIF_MIN_CODE_SIZE as usize + 1;
+                previous = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return out;
+            }
+            let entry = match (table.get(code), &previous) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(previous)) => [previous.clone(), vec![previous[0]]].concat(),
+                (None, None) => panic!("invalid code"),
+            };
+            if let Some(previous) = previous {
+                table.push([previous, vec![entry[0]]].concat());
+                if table.len() == 1 << size && size < 12 {
+                    size += 1;
+                }
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn test_lzw() {
+        let indexes: Vec<u8> = (0..20000u32).map(|i| ((i * i / 7 + i / 300) % 4) as u8).collect();
+        assert_eq!(lzw_decode(&lzw_encode(&indexes)), indexes);
+        let blank = vec![0; 8192];
+        assert!(lzw_encode(&blank).len() < 200);
+        assert_eq!(lzw_decode(&lzw_encode(&blank)), blank);
+    }
+
+    #[test]
+    fn test_gif() {
+        let out = Shared::default();
+        let mut capture = Capture::new(Box::new(out.clone()), CaptureFormat::Gif, 2, [0, 0xFFFFFF, 0xFF0000, 0xFF]).unwrap();
+        let mut display = Display::new();
+        capture.add_frame(&display, 0.0).unwrap();
+        capture.add_frame(&display, 0.5).unwrap();
+        display.gfx[0][0] = 1;
+        capture.add_frame(&display, 1.0).unwrap();
+        // too short, dropped
+        display.gfx[0][1] = 1;
+        capture.add_frame(&display, 1.001).unwrap();
+        assert_eq!(capture.finish(1.5).unwrap(), 2);
+
+        let data = out.0.lock().unwrap().clone();
+        assert_eq!(&data[..6], b"GIF89a");
+        assert_eq!(&data[6..10], &[0, 1, 128, 0]);
+        assert_eq!(&data[13..19], &[0, 0, 0, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(data[data.len() - 1], 0x3B);
+        // the first frame lasts a second
+        assert_eq!(&data[44..48], &[0x21, 0xF9, 0x04, 0x00]);
+        assert_eq!(&data[48..50], &100u16.to_le_bytes());
+    }
+
+    #[test]
+    fn test_y4m() {
+        let out = Shared::default();
+        let mut capture = Capture::new(Box::new(out.clone()), CaptureFormat::Y4m, 1, [0, 0xFFFFFF, 0, 0]).unwrap();
+        let mut display = Display::new();
+        display.gfx[0][0] = 1;
+        capture.add_frame(&display, 0.0).unwrap();
+        assert_eq!(capture.finish(0.05).unwrap(), 3);
+
+        let data = out.0.lock().unwrap().clone();
+        let header = b"YUV4MPEG2 W128 H64 F60:1 Ip A1:1 C444\n";
+        assert!(data.starts_with(header));
+        let frame_size = 6 + 3 * 128 * 64;
+        assert_eq!(data.len(), header.len() + 3 * frame_size);
+        let frame = &data[header.len()..header.len() + frame_size];
+        assert!(frame.starts_with(b"FRAME\n"));
+        // the low resolution pixel doubled, white on black
+        assert_eq!(&frame[6..9], &[235, 235, 16]);
+        assert_eq!(frame[6 + 128], 235);
+        assert_eq!(frame[6 + 128 * 64], 128);
+    }
+}
@@ -0,0 +1,523 @@
+//! Debugger : breakpoints, watchpoints and stepping on top of 'Vm::step'.
+//!
+//! The debugger doesn't own the virtual machine ; it is given the one to
+//! run at every call, so that a frontend can keep replacing it (save states,
+//! rewinding) while keeping the breakpoints.
+
+use crate::disasm::Instruction;
+use crate::error::VmError;
+use crate::vm::{FrameReport, StepOutcome, Vm};
+
+/// A value of the virtual machine state, as used by the conditions and the
+/// register watchpoints.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operand {
+    /// The register VX.
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    DelayTimer,
+    SoundTimer,
+}
+
+impl Operand {
+    /// Return the current value of the operand.
+    pub fn value(&self, vm: &Vm) -> u16 {
+        match *self {
+            Operand::V(x) => vm.v[x as usize & 0xF] as u16,
+            Operand::I => vm.i,
+            Operand::Pc => vm.pc,
+            Operand::Sp => vm.sp,
+            Operand::DelayTimer => vm.delay_timer as u16,
+            Operand::SoundTimer => vm.sound_timer as u16,
+        }
+    }
+
+    /// Return the operand of the given name (case insensitive), if any :
+    /// "V0" to "VF", "I", "PC", "SP", "DT" or "ST".
+    pub fn from_name(name: &str) -> Option<Operand> {
+        let name = name.to_uppercase();
+        match &name[..] {
+            "I" => Some(Operand::I),
+            "PC" => Some(Operand::Pc),
+            "SP" => Some(Operand::Sp),
+            "DT" => Some(Operand::DelayTimer),
+            "ST" => Some(Operand::SoundTimer),
+            _ if name.len() == 2 && name.starts_with('V') => {
+                u8::from_str_radix(&name[1..], 16).ok().map(Operand::V)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The comparison of a 'Condition'.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// A condition on the virtual machine state, e.g. 'V3 == 0x10'.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+/// Parse a decimal or '0x' hexadecimal number.
+pub fn parse_number(string: &str) -> Option<u16> {
+    match string.strip_prefix("0x").or_else(|| string.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => string.parse().ok(),
+    }
+}
+
+impl Condition {
+    /// Return true if the condition holds for the given virtual machine.
+    pub fn holds(&self, vm: &Vm) -> bool {
+        let value = self.operand.value(vm);
+        match self.comparison {
+            Comparison::Equal => value == self.value,
+            Comparison::NotEqual => value != self.value,
+            Comparison::Less => value < self.value,
+            Comparison::LessOrEqual => value <= self.value,
+            Comparison::Greater => value > self.value,
+            Comparison::GreaterOrEqual => value >= self.value,
+        }
+    }
+
+    /// Parse a condition written as 'OPERAND OP VALUE', e.g. "V3 == 0x10" :
+    /// see 'Operand::from_name' for the operands, OP is one of ==, !=, <,
+    /// <=, > and >=, and VALUE a decimal or '0x' hexadecimal number.
+    pub fn parse(string: &str) -> Option<Condition> {
+        // the two characters operators first, '<' being a prefix of '<='
+        let operators = [
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ];
+        let (index, symbol, comparison) = operators
+            .iter()
+            .filter_map(|&(symbol, comparison)| string.find(symbol).map(|index| (index, symbol, comparison)))
+            .next()?;
+        Some(Condition {
+            operand: Operand::from_name(string[..index].trim())?,
+            comparison,
+            value: parse_number(string[index + symbol.len()..].trim())?,
+        })
+    }
+}
+
+/// A breakpoint, stopping the execution before the instruction at the given
+/// address, if its condition holds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    /// Parse a breakpoint written as 'ADDRESS' or 'ADDRESS,CONDITION', e.g.
+    /// "0x2A4,V3 == 0x10" (see 'Condition::parse').
+    pub fn parse(string: &str) -> Option<Breakpoint> {
+        let (address, condition) = match string.find(',') {
+            Some(index) => (&string[..index], Some(Condition::parse(&string[index + 1..])?)),
+            None => (string, None),
+        };
+        Some(Breakpoint {
+            address: parse_number(address.trim())?,
+            condition,
+        })
+    }
+}
+
+/// The kind of memory access.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Either a read or a write, for the watchpoints only.
+    ReadWrite,
+}
+
+/// A watchpoint, stopping the execution after an instruction accessing some
+/// memory or changing a register.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Watchpoint {
+    /// The given kind of access to the 'len' bytes at 'address'.
+    Memory { address: u16, len: u16, access: Access },
+    /// A change of the value of the operand.
+    Register(Operand),
+}
+
+/// A pattern of opcodes, such as "DXYN" or "FX0A" : the hexadecimal digits
+/// must match, the other characters match any digit.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OpcodePattern {
+    mask: u16,
+    value: u16,
+}
+
+impl OpcodePattern {
+    /// Parse a 4 characters long pattern.
+    pub fn parse(pattern: &str) -> Option<OpcodePattern> {
+        if pattern.chars().count() != 4 {
+            return None;
+        }
+        let (mut mask, mut value) = (0, 0);
+        for c in pattern.chars() {
+            mask <<= 4;
+            value <<= 4;
+            if let Some(digit) = c.to_digit(16) {
+                mask |= 0xF;
+                value |= digit as u16;
+            }
+        }
+        Some(OpcodePattern { mask, value })
+    }
+
+    /// Return true if the (first word of the) instruction matches.
+    pub fn matches(&self, instruction: Instruction) -> bool {
+        instruction.encode()[0] & self.mask == self.value
+    }
+}
+
+/// How the execution goes on after 'Debugger::resume'.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RunMode {
+    /// Until a breakpoint or watchpoint is hit.
+    Continue,
+    /// Execute a single instruction.
+    StepInto,
+    /// Execute a single instruction, running a called subroutine (2NNN) up
+    /// to its return.
+    StepOver,
+    /// Run up to the return (00EE) of the current subroutine.
+    StepOut,
+    /// Run up to the given address.
+    RunTo(u16),
+}
+
+/// The reason why the execution stopped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// The stepping requested by the 'RunMode' is complete.
+    StepComplete,
+    /// A breakpoint at the given address was hit, before its execution.
+    Breakpoint(u16),
+    /// A memory watchpoint was hit by the instruction at 'pc'.
+    MemoryWatch { pc: u16, address: u16, access: Access },
+    /// A register watchpoint was hit by the instruction at 'pc'.
+    RegisterWatch { pc: u16, operand: Operand, old: u16, new: u16 },
+    /// An instruction matching a pattern of 'Debugger::opcode_breaks' is
+    /// about to be executed at 'pc'.
+    Opcode { pc: u16, instruction: Instruction },
+    /// The program waits for a key press or for the next vertical blank.
+    Waiting,
+    /// The program is finished.
+    Finished,
+    /// The instruction budget of 'Debugger::run' was exhausted.
+    Limit,
+}
+
+/// Breakpoints, watchpoints and stepping state.
+#[derive(Clone, Debug)]
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    /// Break before the execution of the instructions matching these.
+    pub opcode_breaks: Vec<OpcodePattern>,
+    mode: RunMode,
+    /// Stack depth when stepping over or out started.
+    depth: u16,
+    /// Address of the instruction the execution stopped before, if it did.
+    stopped_before: Option<u16>,
+    /// Address the execution resumed from, whose breakpoints are ignored
+    /// until its instruction is executed.
+    resumed_at: Option<u16>,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            opcode_breaks: Vec::new(),
+            mode: RunMode::Continue,
+            depth: 0,
+            stopped_before: None,
+            resumed_at: None,
+        }
+    }
+
+    /// Return the current run mode.
+    pub fn mode(&self) -> RunMode {
+        self.mode
+    }
+
+    /// Resume the execution of the virtual machine in the given mode,
+    /// without stopping again on the breakpoint it may be stopped at.
+    pub fn resume(&mut self, vm: &Vm, mode: RunMode) {
+        self.mode = mode;
+        self.depth = vm.sp;
+        self.resumed_at = self.stopped_before.take().filter(|&pc| pc == vm.pc);
+    }
+
+    /// Stop the current stepping, if any : the execution is back to the
+    /// 'Continue' mode.
+    fn stop(&mut self, reason: StopReason) -> Option<StopReason> {
+        self.mode = RunMode::Continue;
+        self.stopped_before = None;
+        Some(reason)
+    }
+
+    /// Execute the next instruction, unless a breakpoint stops the execution
+    /// before it. Return the reason the execution stopped, if it did ; on
+    /// 'StopReason::Waiting' the execution can go on once the virtual
+    /// machine stops waiting.
+    pub fn step(&mut self, vm: &mut Vm) -> Result<Option<StopReason>, VmError> {
+        // a key event may end a wait, the next instruction being checked
+        vm.poll_key_wait();
+        let pc = vm.pc;
+        let ready = !(vm.exited || vm.is_waiting_for_key() || vm.vblank_wait);
+        let mut before = None;
+        if ready && pc as usize + 2 <= vm.memory.len() {
+            let instruction = vm.fetch()?;
+            if self.resumed_at != Some(pc) {
+                let hit = self.breakpoints.iter().any(|breakpoint| {
+                    breakpoint.address == pc && breakpoint.condition.is_none_or(|condition| condition.holds(vm))
+                });
+                let reason = if self.mode == RunMode::RunTo(pc) {
+                    Some(StopReason::StepComplete)
+                } else if hit {
+                    Some(StopReason::Breakpoint(pc))
+                } else if self.opcode_breaks.iter().any(|pattern| pattern.matches(instruction)) {
+                    Some(StopReason::Opcode { pc, instruction })
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    let stop = self.stop(reason);
+                    self.stopped_before = Some(pc);
+                    return Ok(stop);
+                }
+            }
+            let registers: Vec<u16> = self.register_watches().map(|operand| operand.value(vm)).collect();
+            before = Some((vm.memory_access(instruction), registers));
+        }
+
+        match vm.step()? {
+            StepOutcome::Finished => return Ok(self.stop(StopReason::Finished)),
+            StepOutcome::Waiting => return Ok(Some(StopReason::Waiting)),
+            StepOutcome::Executed => self.resumed_at = None,
+        }
+
+        // watchpoints, after the execution
+        if let Some(((read, written), registers)) = before {
+            for watchpoint in &self.watchpoints {
+                if let Watchpoint::Memory { address, len, access } = *watchpoint {
+                    let watched = address as usize..address as usize + len as usize;
+                    let hits = [(Access::Read, &read), (Access::Write, &written)];
+                    for &(kind, range) in &hits {
+                        let start = range.start.max(watched.start);
+                        if start < range.end.min(watched.end) && (access == kind || access == Access::ReadWrite) {
+                            let address = start as u16;
+                            return Ok(self.stop(StopReason::MemoryWatch { pc, address, access: kind }));
+                        }
+                    }
+                }
+            }
+            let changes: Vec<(Operand, u16)> = self.register_watches().zip(registers).collect();
+            for (operand, old) in changes {
+                let new = operand.value(vm);
+                if new != old {
+                    return Ok(self.stop(StopReason::RegisterWatch { pc, operand, old, new }));
+                }
+            }
+        }
+
+        let complete = match self.mode {
+            RunMode::StepInto => true,
+            RunMode::StepOver => vm.sp <= self.depth,
+            RunMode::StepOut => vm.sp < self.depth,
+            RunMode::Continue | RunMode::RunTo(_) => false,
+        };
+        Ok(if complete { self.stop(StopReason::StepComplete) } else { None })
+    }
+
+    /// Return the operands of the register watchpoints.
+    fn register_watches(&self) -> impl Iterator<Item = Operand> + '_ {
+        self.watchpoints.iter().filter_map(|watchpoint| match *watchpoint {
+            Watchpoint::Register(operand) => Some(operand),
+            Watchpoint::Memory { .. } => None,
+        })
+    }
+
+    /// Resume the execution in the given mode and run until it stops, or
+    /// until 'max_instructions' were executed. The timers and vertical
+    /// blanks are left to the caller, see 'StopReason::Waiting'.
+    pub fn run(&mut self, vm: &mut Vm, mode: RunMode, max_instructions: u64) -> Result<StopReason, VmError> {
+        self.resume(vm, mode);
+        for _ in 0..max_instructions {
+            if let Some(reason) = self.step(vm)? {
+                return Ok(reason);
+            }
+        }
+        Ok(StopReason::Limit)
+    }
+
+    /// Run the frame 'report' is about on (see 'Vm::run_frame_with'), one
+    /// instruction at a time, up to its end or until the execution stops :
+    /// return the reason it stopped, if it did. A stop counts as a cycle of
+    /// the frame, which may have ended with it.
+    pub fn run_frame(
+        &mut self,
+        vm: &mut Vm,
+        report: &mut FrameReport,
+        instructions_per_frame: u32,
+    ) -> Result<Option<StopReason>, VmError> {
+        while !(report.ended || report.finished) {
+            let mut stop = None;
+            vm.run_frame_with(report, instructions_per_frame, 1, |vm| {
+                self.step(vm).map(|reason| match reason {
+                    None => StepOutcome::Executed,
+                    Some(StopReason::Waiting) => StepOutcome::Waiting,
+                    Some(StopReason::Finished) => StepOutcome::Finished,
+                    reason => {
+                        stop = reason;
+                        StepOutcome::Executed
+                    }
+                })
+            })?;
+            if report.finished {
+                return Ok(Some(StopReason::Finished));
+            }
+            if stop.is_some() {
+                return Ok(stop);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Execute a single instruction.
+    pub fn step_into(&mut self, vm: &mut Vm) -> Result<StopReason, VmError> {
+        self.run(vm, RunMode::StepInto, 1)
+    }
+
+    /// Execute a single instruction, or a whole subroutine call.
+    pub fn step_over(&mut self, vm: &mut Vm, max_instructions: u64) -> Result<StopReason, VmError> {
+        self.run(vm, RunMode::StepOver, max_instructions)
+    }
+
+    /// Run up to the return of the current subroutine.
+    pub fn step_out(&mut self, vm: &mut Vm, max_instructions: u64) -> Result<StopReason, VmError> {
+        self.run(vm, RunMode::StepOut, max_instructions)
+    }
+
+    /// Run up to the given address.
+    pub fn run_to(&mut self, vm: &mut Vm, address: u16, max_instructions: u64) -> Result<StopReason, VmError> {
+        self.run(vm, RunMode::RunTo(address), max_instructions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ROM calling a subroutine setting V3 to 0x10 then storing it.
+    fn vm() -> Vm {
+        let mut vm = Vm::new();
+        let rom = [
+            0x22, 0x06, // 0x200 : call 0x206
+            0x61, 0x01, // 0x202 : v1 := 1
+            0x12, 0x04, // 0x204 : jump 0x204
+            0x63, 0x10, // 0x206 : v3 := 0x10
+            0xA3, 0x00, // 0x208 : i := 0x300
+            0xF3, 0x55, // 0x20A : save v3
+            0x00, 0xEE, // 0x20C : return
+        ];
+        vm.load_rom(&rom).unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Breakpoint::parse("0x2A4,V3 == 0x10"),
+            Some(Breakpoint {
+                address: 0x2A4,
+                condition: Some(Condition {
+                    operand: Operand::V(3),
+                    comparison: Comparison::Equal,
+                    value: 0x10,
+                }),
+            })
+        );
+        assert_eq!(Condition::parse("i<=512").map(|c| c.comparison), Some(Comparison::LessOrEqual));
+        assert_eq!(Breakpoint::parse("0x200,V3 = 1"), None);
+        assert!(OpcodePattern::parse("FX0A").unwrap().matches(Instruction::WaitKey(5)));
+        assert!(!OpcodePattern::parse("DXYN").unwrap().matches(Instruction::WaitKey(5)));
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut vm = vm();
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step_over(&mut vm, 100).unwrap(), StopReason::StepComplete);
+        assert_eq!((vm.pc, vm.v[3]), (0x202, 0x10));
+
+        let mut vm = self::vm();
+        debugger.step_into(&mut vm).unwrap();
+        debugger.step_into(&mut vm).unwrap();
+        assert_eq!(vm.pc, 0x208);
+        assert_eq!(debugger.step_out(&mut vm, 100).unwrap(), StopReason::StepComplete);
+        assert_eq!(vm.pc, 0x202);
+        assert_eq!(debugger.run_to(&mut vm, 0x204, 100).unwrap(), StopReason::StepComplete);
+        assert_eq!(debugger.run(&mut vm, RunMode::Continue, 10).unwrap(), StopReason::Limit);
+    }
+
+    #[test]
+    fn test_breakpoints_and_watchpoints() {
+        let mut vm = vm();
+        let mut debugger = Debugger::new();
+        debugger.breakpoints.push(Breakpoint::parse("0x20A,V3 == 0x11").unwrap());
+        debugger.breakpoints.push(Breakpoint::parse("0x20C,V3 == 0x10").unwrap());
+        assert_eq!(debugger.run(&mut vm, RunMode::Continue, 100).unwrap(), StopReason::Breakpoint(0x20C));
+        // resuming doesn't stop at the same breakpoint again
+        assert_eq!(debugger.step_into(&mut vm).unwrap(), StopReason::StepComplete);
+
+        let mut vm = self::vm();
+        let mut debugger = Debugger::new();
+        debugger.watchpoints.push(Watchpoint::Register(Operand::I));
+        debugger.watchpoints.push(Watchpoint::Memory { address: 0x2FF, len: 2, access: Access::Write });
+        debugger.opcode_breaks.push(OpcodePattern::parse("00EE").unwrap());
+        assert_eq!(
+            debugger.run(&mut vm, RunMode::Continue, 100).unwrap(),
+            StopReason::RegisterWatch { pc: 0x208, operand: Operand::I, old: 0, new: 0x300 }
+        );
+        assert_eq!(
+            debugger.run(&mut vm, RunMode::Continue, 100).unwrap(),
+            StopReason::MemoryWatch { pc: 0x20A, address: 0x300, access: Access::Write }
+        );
+        assert_eq!(
+            debugger.run(&mut vm, RunMode::Continue, 100).unwrap(),
+            StopReason::Opcode { pc: 0x20C, instruction: Instruction::Return }
+        );
+    }
+}
@@ -0,0 +1,728 @@
+//! Disassembler : decoding of the CHIP 8, SUPER-CHIP and XO-CHIP opcodes into
+//! typed instructions, and their formatting as conventional mnemonics or as
+//! Octo source code.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+
+/// A decoded instruction. The register operands are indexes in 0..16 and
+/// the address operands are 12 bits wide, except for 'LoadILong'.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    /// 0NNN : call a machine code routine (ignored).
+    Sys(u16),
+    /// 00CN : scroll the display down by N pixels (SUPER-CHIP).
+    ScrollDown(u8),
+    /// 00DN : scroll the display up by N pixels (XO-CHIP).
+    ScrollUp(u8),
+    /// 00E0 : clear the display.
+    Clear,
+    /// 00EE : return from a subroutine.
+    Return,
+    /// 00FB : scroll the display right by 4 pixels (SUPER-CHIP).
+    ScrollRight,
+    /// 00FC : scroll the display left by 4 pixels (SUPER-CHIP).
+    ScrollLeft,
+    /// 00FD : exit the interpreter (SUPER-CHIP).
+    Exit,
+    /// 00FE : switch to the low resolution mode (SUPER-CHIP).
+    Lores,
+    /// 00FF : switch to the high resolution mode (SUPER-CHIP).
+    Hires,
+    /// 1NNN : jump to NNN.
+    Jump(u16),
+    /// 2NNN : call the subroutine at NNN.
+    Call(u16),
+    /// 3XNN : skip the next instruction if VX == NN.
+    SkipEqImm(u8, u8),
+    /// 4XNN : skip the next instruction if VX != NN.
+    SkipNeImm(u8, u8),
+    /// 5XY0 : skip the next instruction if VX == VY.
+    SkipEqReg(u8, u8),
+    /// 5XY2 : save VX to VY in memory at I (XO-CHIP).
+    SaveRange(u8, u8),
+    /// 5XY3 : load VX to VY from memory at I (XO-CHIP).
+    LoadRange(u8, u8),
+    /// 6XNN : VX = NN.
+    LoadImm(u8, u8),
+    /// 7XNN : VX += NN, without carry.
+    AddImm(u8, u8),
+    /// 8XY0 : VX = VY.
+    Move(u8, u8),
+    /// 8XY1 : VX |= VY.
+    Or(u8, u8),
+    /// 8XY2 : VX &= VY.
+    And(u8, u8),
+    /// 8XY3 : VX ^= VY.
+    Xor(u8, u8),
+    /// 8XY4 : VX += VY, VF = carry.
+    Add(u8, u8),
+    /// 8XY5 : VX -= VY, VF = not borrow.
+    Sub(u8, u8),
+    /// 8XY6 : VX >>= 1 (or VX = VY >> 1), VF = shifted out bit.
+    ShiftRight(u8, u8),
+    /// 8XY7 : VX = VY - VX, VF = not borrow.
+    SubReverse(u8, u8),
+    /// 8XYE : VX <<= 1 (or VX = VY << 1), VF = shifted out bit.
+    ShiftLeft(u8, u8),
+    /// 9XY0 : skip the next instruction if VX != VY.
+    SkipNeReg(u8, u8),
+    /// ANNN : I = NNN.
+    LoadI(u16),
+    /// BNNN : jump to NNN + V0 (or XNN + VX).
+    JumpOffset(u16),
+    /// CXNN : VX = random & NN.
+    Random(u8, u8),
+    /// DXYN : draw the N rows sprite at I at (VX, VY), 16x16 if N == 0.
+    Draw(u8, u8, u8),
+    /// EX9E : skip the next instruction if the key VX is pressed.
+    SkipKeyPressed(u8),
+    /// EXA1 : skip the next instruction if the key VX is released.
+    SkipKeyReleased(u8),
+    /// F000 NNNN : I = NNNN, 4 bytes long (XO-CHIP).
+    LoadILong(u16),
+    /// FN01 : select the bitplanes N (XO-CHIP).
+    Plane(u8),
+    /// F002 : load the audio pattern buffer from memory at I (XO-CHIP).
+    Audio,
+    /// FX07 : VX = delay timer.
+    GetDelay(u8),
+    /// FX0A : wait for a key press and store it in VX.
+    WaitKey(u8),
+    /// FX15 : delay timer = VX.
+    SetDelay(u8),
+    /// FX18 : sound timer = VX.
+    SetSound(u8),
+    /// FX1E : I += VX.
+    AddI(u8),
+    /// FX29 : I = address of the small font digit VX.
+    Font(u8),
+    /// FX30 : I = address of the big font digit VX (SUPER-CHIP).
+    BigFont(u8),
+    /// FX33 : store the BCD representation of VX in memory at I.
+    Bcd(u8),
+    /// FX3A : audio pitch = VX (XO-CHIP).
+    Pitch(u8),
+    /// FX55 : store V0 to VX in memory at I.
+    Store(u8),
+    /// FX65 : load V0 to VX from memory at I.
+    Load(u8),
+    /// FX75 : save V0 to VX to the RPL user flags (SUPER-CHIP).
+    SaveFlags(u8),
+    /// FX85 : load V0 to VX from the RPL user flags (SUPER-CHIP).
+    LoadFlags(u8),
+}
+
+/// An error while decoding an opcode.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The opcode doesn't match any instruction.
+    UnknownOpcode(u16),
+    /// The opcode is the first word of a 4 bytes long instruction ('F000')
+    /// whose operand is missing.
+    MissingOperand(u16),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnknownOpcode(opcode) => write!(f, "unknown opcode {:04X}", opcode),
+            DecodeError::MissingOperand(opcode) => {
+                write!(f, "missing operand of the opcode {:04X}", opcode)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Decode a 2 bytes long opcode. The first word of a 4 bytes long
+/// instruction ('F000') is reported as 'DecodeError::MissingOperand', see
+/// 'decode_long' and 'decode_at' for those.
+pub fn decode(opcode: u16) -> Result<Instruction, DecodeError> {
+    use self::Instruction::*;
+
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+    let n = (opcode & 0x000F) as u8;
+    let nn = (opcode & 0x00FF) as u8;
+    let nnn = opcode & 0x0FFF;
+    let unknown = Err(DecodeError::UnknownOpcode(opcode));
+
+    let instruction = match opcode >> 12 {
+        0x0 => match opcode {
+            0x00C0..=0x00CF => ScrollDown(n),
+            0x00D0..=0x00DF => ScrollUp(n),
+            0x00E0 => Clear,
+            0x00EE => Return,
+            0x00FB => ScrollRight,
+            0x00FC => ScrollLeft,
+            0x00FD => Exit,
+            0x00FE => Lores,
+            0x00FF => Hires,
+            _ => Sys(nnn),
+        },
+        0x1 => Jump(nnn),
+        0x2 => Call(nnn),
+        0x3 => SkipEqImm(x, nn),
+        0x4 => SkipNeImm(x, nn),
+        0x5 => match n {
+            0x0 => SkipEqReg(x, y),
+            0x2 => SaveRange(x, y),
+            0x3 => LoadRange(x, y),
+            _ => return unknown,
+        },
+        0x6 => LoadImm(x, nn),
+        0x7 => AddImm(x, nn),
+        0x8 => match n {
+            0x0 => Move(x, y),
+            0x1 => Or(x, y),
+            0x2 => And(x, y),
+            0x3 => Xor(x, y),
+            0x4 => Add(x, y),
+            0x5 => Sub(x, y),
+            0x6 => ShiftRight(x, y),
+            0x7 => SubReverse(x, y),
+            0xE => ShiftLeft(x, y),
+            _ => return unknown,
+        },
+        0x9 if n == 0 => SkipNeReg(x, y),
+        0xA => LoadI(nnn),
+        0xB => JumpOffset(nnn),
+        0xC => Random(x, nn),
+        0xD => Draw(x, y, n),
+        0xE => match nn {
+            0x9E => SkipKeyPressed(x),
+            0xA1 => SkipKeyReleased(x),
+            _ => return unknown,
+        },
+        0xF => match nn {
+            0x00 if x == 0 => return Err(DecodeError::MissingOperand(opcode)),
+            0x01 => Plane(x),
+            0x02 if x == 0 => Audio,
+            0x07 => GetDelay(x),
+            0x0A => WaitKey(x),
+            0x15 => SetDelay(x),
+            0x18 => SetSound(x),
+            0x1E => AddI(x),
+            0x29 => Font(x),
+            0x30 => BigFont(x),
+            0x33 => Bcd(x),
+            0x3A => Pitch(x),
+            0x55 => Store(x),
+            0x65 => Load(x),
+            0x75 => SaveFlags(x),
+            0x85 => LoadFlags(x),
+            _ => return unknown,
+        },
+        _ => return unknown,
+    };
+    Ok(instruction)
+}
+
+/// Decode a 4 bytes long instruction, made of the given opcode and operand.
+pub fn decode_long(opcode: u16, operand: u16) -> Result<Instruction, DecodeError> {
+    match opcode {
+        0xF000 => Ok(Instruction::LoadILong(operand)),
+        _ => Err(DecodeError::UnknownOpcode(opcode)),
+    }
+}
+
+/// Decode the instruction at the given address in memory.
+pub fn decode_at(memory: &[u8], address: usize) -> Result<Instruction, DecodeError> {
+    let word = |a: usize| -> Option<u16> {
+        Some((*memory.get(a)? as u16) << 8 | *memory.get(a + 1)? as u16)
+    };
+    let opcode = word(address).ok_or(DecodeError::MissingOperand(0))?;
+    match decode(opcode) {
+        Err(DecodeError::MissingOperand(_)) => match word(address + 2) {
+            Some(operand) => decode_long(opcode, operand),
+            None => Err(DecodeError::MissingOperand(opcode)),
+        },
+        result => result,
+    }
+}
+
+/// The output syntax of the disassembler.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Syntax {
+    /// The conventional mnemonics, e.g. 'LD V1, 0x2A'.
+    Mnemonic,
+    /// The Octo assembly language, e.g. 'v1 := 0x2A'.
+    Octo,
+}
+
+impl Instruction {
+    /// Return the size of the instruction in bytes : 2, or 4 for the XO-CHIP
+    /// long I load.
+    pub fn size(&self) -> usize {
+        match *self {
+            Instruction::LoadILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Return the opcode words of the instruction.
+    pub fn encode(&self) -> Vec<u16> {
+        use self::Instruction::*;
+        let xy = |base: u16, x: u8, y: u8| base | (x as u16) << 8 | (y as u16) << 4;
+        let xnn = |base: u16, x: u8, nn: u8| base | (x as u16) << 8 | nn as u16;
+        let word = match *self {
+            Sys(nnn) => nnn,
+            ScrollDown(n) => 0x00C0 | n as u16,
+            ScrollUp(n) => 0x00D0 | n as u16,
+            Clear => 0x00E0,
+            Return => 0x00EE,
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Lores => 0x00FE,
+            Hires => 0x00FF,
+            Jump(nnn) => 0x1000 | nnn,
+            Call(nnn) => 0x2000 | nnn,
+            SkipEqImm(x, nn) => xnn(0x3000, x, nn),
+            SkipNeImm(x, nn) => xnn(0x4000, x, nn),
+            SkipEqReg(x, y) => xy(0x5000, x, y),
+            SaveRange(x, y) => xy(0x5002, x, y),
+            LoadRange(x, y) => xy(0x5003, x, y),
+            LoadImm(x, nn) => xnn(0x6000, x, nn),
+            AddImm(x, nn) => xnn(0x7000, x, nn),
+            Move(x, y) => xy(0x8000, x, y),
+            Or(x, y) => xy(0x8001, x, y),
+            And(x, y) => xy(0x8002, x, y),
+            Xor(x, y) => xy(0x8003, x, y),
+            Add(x, y) => xy(0x8004, x, y),
+            Sub(x, y) => xy(0x8005, x, y),
+            ShiftRight(x, y) => xy(0x8006, x, y),
+            SubReverse(x, y) => xy(0x8007, x, y),
+            ShiftLeft(x, y) => xy(0x800E, x, y),
+            SkipNeReg(x, y) => xy(0x9000, x, y),
+            LoadI(nnn) => 0xA000 | nnn,
+            JumpOffset(nnn) => 0xB000 | nnn,
+            Random(x, nn) => xnn(0xC000, x, nn),
+            Draw(x, y, n) => xy(0xD000, x, y) | n as u16,
+            SkipKeyPressed(x) => xnn(0xE000, x, 0x9E),
+            SkipKeyReleased(x) => xnn(0xE000, x, 0xA1),
+            LoadILong(nnnn) => return vec![0xF000, nnnn],
+            Plane(n) => xnn(0xF000, n, 0x01),
+            Audio => 0xF002,
+            GetDelay(x) => xnn(0xF000, x, 0x07),
+            WaitKey(x) => xnn(0xF000, x, 0x0A),
+            SetDelay(x) => xnn(0xF000, x, 0x15),
+            SetSound(x) => xnn(0xF000, x, 0x18),
+            AddI(x) => xnn(0xF000, x, 0x1E),
+            Font(x) => xnn(0xF000, x, 0x29),
+            BigFont(x) => xnn(0xF000, x, 0x30),
+            Bcd(x) => xnn(0xF000, x, 0x33),
+            Pitch(x) => xnn(0xF000, x, 0x3A),
+            Store(x) => xnn(0xF000, x, 0x55),
+            Load(x) => xnn(0xF000, x, 0x65),
+            SaveFlags(x) => xnn(0xF000, x, 0x75),
+            LoadFlags(x) => xnn(0xF000, x, 0x85),
+        };
+        vec![word]
+    }
+
+    /// Return true if the instruction skips the next one on some condition.
+    pub fn is_skip(&self) -> bool {
+        use self::Instruction::*;
+        matches!(
+            *self,
+            SkipEqImm(..) | SkipNeImm(..) | SkipEqReg(..) | SkipNeReg(..) | SkipKeyPressed(_) | SkipKeyReleased(_)
+        )
+    }
+
+    /// Return the short name of the instruction in the conventional
+    /// mnemonics, e.g. "LD" or "DRW". Several instructions share a name.
+    pub fn mnemonic(&self) -> &'static str {
+        use self::Instruction::*;
+        match *self {
+            Sys(_) => "SYS",
+            ScrollDown(_) => "SCD",
+            ScrollUp(_) => "SCU",
+            Clear => "CLS",
+            Return => "RET",
+            ScrollRight => "SCR",
+            ScrollLeft => "SCL",
+            Exit => "EXIT",
+            Lores => "LOW",
+            Hires => "HIGH",
+            Jump(_) | JumpOffset(_) => "JP",
+            Call(_) => "CALL",
+            SkipEqImm(..) | SkipEqReg(..) => "SE",
+            SkipNeImm(..) | SkipNeReg(..) => "SNE",
+            Or(..) => "OR",
+            And(..) => "AND",
+            Xor(..) => "XOR",
+            AddImm(..) | Add(..) | AddI(_) => "ADD",
+            Sub(..) => "SUB",
+            ShiftRight(..) => "SHR",
+            SubReverse(..) => "SUBN",
+            ShiftLeft(..) => "SHL",
+            Random(..) => "RND",
+            Draw(..) => "DRW",
+            SkipKeyPressed(_) => "SKP",
+            SkipKeyReleased(_) => "SKNP",
+            Plane(_) => "PLANE",
+            Audio => "AUDIO",
+            SaveRange(..) | LoadRange(..) | LoadImm(..) | Move(..) | LoadI(_) | LoadILong(_)
+            | GetDelay(_) | WaitKey(_) | SetDelay(_) | SetSound(_) | Font(_) | BigFont(_) | Bcd(_)
+            | Pitch(_) | Store(_) | Load(_) | SaveFlags(_) | LoadFlags(_) => "LD",
+        }
+    }
+
+    /// Return the opcode pattern of the instruction, e.g. "8XY4" or "DXYN" :
+    /// unlike the mnemonics, every instruction has its own.
+    pub fn pattern(&self) -> &'static str {
+        use self::Instruction::*;
+        match *self {
+            Sys(_) => "0NNN",
+            ScrollDown(_) => "00CN",
+            ScrollUp(_) => "00DN",
+            Clear => "00E0",
+            Return => "00EE",
+            ScrollRight => "00FB",
+            ScrollLeft => "00FC",
+            Exit => "00FD",
+            Lores => "00FE",
+            Hires => "00FF",
+            Jump(_) => "1NNN",
+            Call(_) => "2NNN",
+            SkipEqImm(..) => "3XNN",
+            SkipNeImm(..) => "4XNN",
+            SkipEqReg(..) => "5XY0",
+            SaveRange(..) => "5XY2",
+            LoadRange(..) => "5XY3",
+            LoadImm(..) => "6XNN",
+            AddImm(..) => "7XNN",
+            Move(..) => "8XY0",
+            Or(..) => "8XY1",
+            And(..) => "8XY2",
+            Xor(..) => "8XY3",
+            Add(..) => "8XY4",
+            Sub(..) => "8XY5",
+            ShiftRight(..) => "8XY6",
+            SubReverse(..) => "8XY7",
+            ShiftLeft(..) => "8XYE",
+            SkipNeReg(..) => "9XY0",
+            LoadI(_) => "ANNN",
+            JumpOffset(_) => "BNNN",
+            Random(..) => "CXNN",
+            Draw(..) => "DXYN",
+            SkipKeyPressed(_) => "EX9E",
+            SkipKeyReleased(_) => "EXA1",
+            LoadILong(_) => "F000",
+            Plane(_) => "FN01",
+            Audio => "F002",
+            GetDelay(_) => "FX07",
+            WaitKey(_) => "FX0A",
+            SetDelay(_) => "FX15",
+            SetSound(_) => "FX18",
+            AddI(_) => "FX1E",
+            Font(_) => "FX29",
+            BigFont(_) => "FX30",
+            Bcd(_) => "FX33",
+            Pitch(_) => "FX3A",
+            Store(_) => "FX55",
+            Load(_) => "FX65",
+            SaveFlags(_) => "FX75",
+            LoadFlags(_) => "FX85",
+        }
+    }
+
+    /// Return the address the instruction refers to, if any : the target of
+    /// a jump or call, or the address loaded in I.
+    pub fn target(&self) -> Option<u16> {
+        use self::Instruction::*;
+        match *self {
+            Jump(a) | Call(a) | JumpOffset(a) | LoadI(a) | LoadILong(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// Format the instruction in the given syntax, naming the addresses
+    /// found in 'labels'.
+    pub fn format(&self, syntax: Syntax, labels: &HashMap<u16, String>) -> String {
+        use self::Instruction::*;
+        let addr = |a: u16| match labels.get(&a) {
+            Some(label) => label.clone(),
+            None => format!("{:#05X}", a),
+        };
+        match syntax {
+            Syntax::Mnemonic => match *self {
+                Sys(a) => format!("SYS {}", addr(a)),
+                ScrollDown(n) | ScrollUp(n) => format!("{} {}", self.mnemonic(), n),
+                Clear | Return | ScrollRight | ScrollLeft | Exit | Lores | Hires | Audio => {
+                    self.mnemonic().to_string()
+                }
+                Jump(a) | Call(a) => format!("{} {}", self.mnemonic(), addr(a)),
+                JumpOffset(a) => format!("JP V0, {}", addr(a)),
+                SkipEqImm(x, nn) | SkipNeImm(x, nn) | LoadImm(x, nn) | AddImm(x, nn) | Random(x, nn) => {
+                    format!("{} V{:X}, {:#04X}", self.mnemonic(), x, nn)
+                }
+                SkipEqReg(x, y) | SkipNeReg(x, y) | Move(x, y) | Or(x, y) | And(x, y) | Xor(x, y)
+                | Add(x, y) | Sub(x, y) | ShiftRight(x, y) | SubReverse(x, y) | ShiftLeft(x, y) => {
+                    format!("{} V{:X}, V{:X}", self.mnemonic(), x, y)
+                }
+                SaveRange(x, y) => format!("LD [I], V{:X}-V{:X}", x, y),
+                LoadRange(x, y) => format!("LD V{:X}-V{:X}, [I]", x, y),
+                LoadI(a) => format!("LD I, {}", addr(a)),
+                LoadILong(a) => format!("LD I, LONG {}", addr(a)),
+                Draw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+                SkipKeyPressed(x) | SkipKeyReleased(x) => format!("{} V{:X}", self.mnemonic(), x),
+                Plane(n) => format!("PLANE {}", n),
+                GetDelay(x) => format!("LD V{:X}, DT", x),
+                WaitKey(x) => format!("LD V{:X}, K", x),
+                SetDelay(x) => format!("LD DT, V{:X}", x),
+                SetSound(x) => format!("LD ST, V{:X}", x),
+                AddI(x) => format!("ADD I, V{:X}", x),
+                Font(x) => format!("LD F, V{:X}", x),
+                BigFont(x) => format!("LD HF, V{:X}", x),
+                Bcd(x) => format!("LD B, V{:X}", x),
+                Pitch(x) => format!("LD PITCH, V{:X}", x),
+                Store(x) => format!("LD [I], V{:X}", x),
+                Load(x) => format!("LD V{:X}, [I]", x),
+                SaveFlags(x) => format!("LD R, V{:X}", x),
+                LoadFlags(x) => format!("LD V{:X}, R", x),
+            },
+            Syntax::Octo => match *self {
+                Sys(a) => format!("{:#04X} {:#04X}", a >> 8, a & 0xFF),
+                ScrollDown(n) => format!("scroll-down {}", n),
+                ScrollUp(n) => format!("scroll-up {}", n),
+                Clear => "clear".to_string(),
+                Return => "return".to_string(),
+                ScrollRight => "scroll-right".to_string(),
+                ScrollLeft => "scroll-left".to_string(),
+                Exit => "exit".to_string(),
+                Lores => "lores".to_string(),
+                Hires => "hires".to_string(),
+                Jump(a) => format!("jump {}", addr(a)),
+                Call(a) => match labels.get(&a) {
+                    Some(label) => label.clone(),
+                    None => format!(":call {:#05X}", a),
+                },
+                // Octo conditions tell when the next instruction is executed
+                SkipEqImm(x, nn) => format!("if v{:x} != {:#04X} then", x, nn),
+                SkipNeImm(x, nn) => format!("if v{:x} == {:#04X} then", x, nn),
+                SkipEqReg(x, y) => format!("if v{:x} != v{:x} then", x, y),
+                SkipNeReg(x, y) => format!("if v{:x} == v{:x} then", x, y),
+                SkipKeyPressed(x) => format!("if v{:x} -key then", x),
+                SkipKeyReleased(x) => format!("if v{:x} key then", x),
+                SaveRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+                LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+                LoadImm(x, nn) => format!("v{:x} := {:#04X}", x, nn),
+                AddImm(x, nn) => format!("v{:x} += {:#04X}", x, nn),
+                Move(x, y) => format!("v{:x} := v{:x}", x, y),
+                Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+                And(x, y) => format!("v{:x} &= v{:x}", x, y),
+                Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+                Add(x, y) => format!("v{:x} += v{:x}", x, y),
+                Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+                ShiftRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+                SubReverse(x, y) => format!("v{:x} =- v{:x}", x, y),
+                ShiftLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+                LoadI(a) => format!("i := {}", addr(a)),
+                LoadILong(a) => format!("i := long {}", addr(a)),
+                JumpOffset(a) => format!("jump0 {}", addr(a)),
+                Random(x, nn) => format!("v{:x} := random {:#04X}", x, nn),
+                Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+                Plane(n) => format!("plane {}", n),
+                Audio => "audio".to_string(),
+                GetDelay(x) => format!("v{:x} := delay", x),
+                WaitKey(x) => format!("v{:x} := key", x),
+                SetDelay(x) => format!("delay := v{:x}", x),
+                SetSound(x) => format!("buzzer := v{:x}", x),
+                AddI(x) => format!("i += v{:x}", x),
+                Font(x) => format!("i := hex v{:x}", x),
+                BigFont(x) => format!("i := bighex v{:x}", x),
+                Bcd(x) => format!("bcd v{:x}", x),
+                Pitch(x) => format!("pitch := v{:x}", x),
+                Store(x) => format!("save v{:x}", x),
+                Load(x) => format!("load v{:x}", x),
+                SaveFlags(x) => format!("saveflags v{:x}", x),
+                LoadFlags(x) => format!("loadflags v{:x}", x),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Format the instruction with the conventional mnemonics.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format(Syntax::Mnemonic, &HashMap::new()))
+    }
+}
+
+/// Return the addresses of the ROM, loaded at 'origin', reachable as code
+/// from its entry point, with the instruction found at each of them.
+fn trace_code(rom: &[u8], origin: u16) -> BTreeMap<u16, Instruction> {
+    let origin = origin as usize;
+    let mut code = BTreeMap::new();
+    let mut pending = vec![origin];
+
+    while let Some(address) = pending.pop() {
+        if address < origin || code.contains_key(&(address as u16)) {
+            continue;
+        }
+        let instruction = match decode_at(rom, address - origin) {
+            Ok(instruction) => instruction,
+            // not code after all, left as data
+            Err(_) => continue,
+        };
+        code.insert(address as u16, instruction);
+        let next = address + instruction.size();
+        match instruction {
+            Instruction::Jump(target) => pending.push(target as usize),
+            Instruction::Call(target) => {
+                pending.push(target as usize);
+                pending.push(next);
+            }
+            // the target of BNNN is only known at run time
+            Instruction::Return | Instruction::Exit | Instruction::JumpOffset(_) => {}
+            _ if instruction.is_skip() => {
+                pending.push(next);
+                // the skipped instruction may be 4 bytes long
+                let skipped = decode_at(rom, next - origin).map_or(2, |i| i.size());
+                pending.push(next + skipped);
+            }
+            _ => pending.push(next),
+        }
+    }
+    code
+}
+
+/// Disassemble a whole ROM loaded at 'origin' (usually 0x200) : the code
+/// reachable from the entry point is shown as instructions, with a label at
+/// every jump, call and I target ; the rest is shown as data bytes.
+pub fn disassemble(rom: &[u8], origin: u16, syntax: Syntax) -> String {
+    let code = trace_code(rom, origin);
+    let end = origin as usize + rom.len();
+    let in_rom = |a: u16| a >= origin && (a as usize) < end;
+
+    // name the targets inside the ROM, the strongest usage winning
+    let mut labels = HashMap::new();
+    for instruction in code.values() {
+        let (prefix, target) = match *instruction {
+            Instruction::Call(a) => ("sub", a),
+            Instruction::Jump(a) | Instruction::JumpOffset(a) => ("label", a),
+            Instruction::LoadI(a) | Instruction::LoadILong(a) => ("data", a),
+            _ => continue,
+        };
+        if !in_rom(target) {
+            continue;
+        }
+        let label = labels.entry(target).or_insert_with(|| format!("{}_{:04X}", prefix, target));
+        if prefix == "sub" {
+            *label = format!("sub_{:04X}", target);
+        }
+    }
+    if syntax == Syntax::Octo {
+        labels.insert(origin, "main".to_string());
+    }
+    // split the ROM in lines : the instructions, and the data in between,
+    // cut at the labels, 8 bytes per line at most
+    let mut lines = Vec::new();
+    let mut address = origin as usize;
+    while address < end {
+        let len = match code.get(&(address as u16)) {
+            Some(instruction) if address + instruction.size() <= end => instruction.size(),
+            _ => {
+                let mut len = 1;
+                while len < 8
+                    && address + len < end
+                    && !code.contains_key(&((address + len) as u16))
+                    && !labels.contains_key(&((address + len) as u16))
+                {
+                    len += 1;
+                }
+                len
+            }
+        };
+        lines.push((address as u16, len));
+        address += len;
+    }
+    // the targets in the middle of an instruction can't be labelled
+    let starts: BTreeSet<u16> = lines.iter().map(|&(a, _)| a).collect();
+    labels.retain(|a, _| starts.contains(a));
+
+    let mut out = String::new();
+    for (a, len) in lines {
+        if let Some(label) = labels.get(&a) {
+            match syntax {
+                Syntax::Mnemonic => out.push_str(&format!("{}:\n", label)),
+                Syntax::Octo => out.push_str(&format!(": {}\n", label)),
+            }
+        }
+        match code.get(&a) {
+            Some(instruction) if instruction.size() == len => {
+                let text = instruction.format(syntax, &labels);
+                let words: Vec<String> = instruction.encode().iter().map(|w| format!("{:04X}", w)).collect();
+                match syntax {
+                    Syntax::Mnemonic => {
+                        out.push_str(&format!("{:#05X}:  {:<9}  {}\n", a, words.join(" "), text))
+                    }
+                    Syntax::Octo => out.push_str(&format!("\t{:<24} # {:#05X}\n", text, a)),
+                }
+            }
+            _ => {
+                let offset = (a - origin) as usize;
+                let bytes: Vec<String> = rom[offset..offset + len].iter().map(|b| format!("{:#04X}", b)).collect();
+                match syntax {
+                    Syntax::Mnemonic => out.push_str(&format!("{:#05X}:  DB {}\n", a, bytes.join(", "))),
+                    Syntax::Octo => out.push_str(&format!("\t{:<24} # {:#05X}\n", bytes.join(" "), a)),
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_encode() {
+        for opcode in 0..=0xFFFFu16 {
+            if let Ok(instruction) = decode(opcode) {
+                assert_eq!(instruction.encode(), vec![opcode]);
+                let digits = format!("{:04X}", opcode);
+                let fixed = instruction.pattern().chars().zip(digits.chars());
+                assert!(fixed.filter(|&(p, _)| p.is_ascii_hexdigit()).all(|(p, d)| p == d), "{}", digits);
+            }
+        }
+        assert_eq!(decode(0xD12F), Ok(Instruction::Draw(1, 2, 0xF)));
+        assert_eq!(decode(0x5121), Err(DecodeError::UnknownOpcode(0x5121)));
+        assert_eq!(decode_at(&[0xF0, 0x00, 0x12, 0x34], 0), Ok(Instruction::LoadILong(0x1234)));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let rom = [
+            0x22, 0x06, // call sub
+            0xA2, 0x0A, // i := data
+            0x12, 0x02, // loop : jump loop
+            0x60, 0x2A, // sub : v0 := 42
+            0x00, 0xEE, // return
+            0xFF, 0x81, // data
+        ];
+        let mnemonic = disassemble(&rom, 0x200, Syntax::Mnemonic);
+        assert_eq!(
+            mnemonic,
+            "0x200:  2206       CALL sub_0206\n\
+             label_0202:\n\
+             0x202:  A20A       LD I, data_020A\n\
+             0x204:  1202       JP label_0202\n\
+             sub_0206:\n\
+             0x206:  602A       LD V0, 0x2A\n\
+             0x208:  00EE       RET\n\
+             data_020A:\n\
+             0x20A:  DB 0xFF, 0x81\n"
+        );
+        let octo = disassemble(&rom, 0x200, Syntax::Octo);
+        assert!(octo.starts_with(": main\n\tsub_0206"));
+        assert!(octo.contains("\ti := data_020A"));
+        assert!(octo.contains("\t0xFF 0x81"));
+    }
+}
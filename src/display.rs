@@ -2,46 +2,163 @@
 pub const DISPLAY_WIDTH: usize = 64;
 /// 显示高度 32 像素
 pub const DISPLAY_HEIGHT: usize = 32;
+/// 高分辨率模式 (SUPER-CHIP) 显示宽度 128 像素
+pub const HIRES_DISPLAY_WIDTH: usize = 128;
+/// 高分辨率模式 (SUPER-CHIP) 显示高度 64 像素
+pub const HIRES_DISPLAY_HEIGHT: usize = 64;
 
+/// XO-CHIP 的两个位平面 (bitplane) 的掩码
+pub const PLANES_MASK: u8 = 0b11;
+
+/// The framebuffer, big enough for the SUPER-CHIP high resolution mode.
+/// In low resolution mode only the top-left 64x32 pixels are used.
+/// Each pixel holds one bit per XO-CHIP bitplane (bit 0 for the first plane,
+/// bit 1 for the second one), hence one of 4 colors.
 #[derive(Clone)]
 pub struct Display {
-    pub gfx: [[u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+    pub gfx: [[u8; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT],
+    pub hires: bool,
+    /// The bitplanes affected by the drawing, clearing and scrolling
+    /// operations, selected by the XO-CHIP FN01 instruction.
+    pub planes: u8,
     pub dirty: bool,
 }
 
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
+}
+
 impl Display {
     pub fn new() -> Display {
         Display {
-            gfx: [[0u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT],
+            gfx: [[0u8; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT],
+            hires: false,
+            planes: 0b01,
             dirty: true,
         }
     }
 
+    /// Return the width in pixels of the current resolution.
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_DISPLAY_WIDTH } else { DISPLAY_WIDTH }
+    }
+
+    /// Return the height in pixels of the current resolution.
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_DISPLAY_HEIGHT } else { DISPLAY_HEIGHT }
+    }
+
+    /// Clear the selected bitplanes.
     pub fn clear(&mut self) {
-        self.gfx = [[0u8; DISPLAY_WIDTH]; DISPLAY_HEIGHT];
+        let mask = !self.planes;
+        for row in self.gfx.iter_mut() {
+            for pixel in row.iter_mut() {
+                *pixel &= mask;
+            }
+        }
         self.dirty = true;
     }
 
-    pub fn draw(&mut self, xpos: usize, ypos: usize, sprite: &[u8]) -> bool {
-        let mut collision = false;
-        let h = sprite.len();
+    /// Switch between the low and high resolution modes, clearing the
+    /// whole screen.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.gfx = [[0u8; HIRES_DISPLAY_WIDTH]; HIRES_DISPLAY_HEIGHT];
+        self.dirty = true;
+    }
 
-        for j in 0..h {
-            for i in 0..8 {
-                let y = (ypos + j) % DISPLAY_HEIGHT;
-                let x = (xpos + i) % DISPLAY_WIDTH;
+    /// Select the bitplanes affected by the next operations.
+    pub fn select_planes(&mut self, planes: u8) {
+        self.planes = planes & PLANES_MASK;
+    }
 
-                if (sprite[j] & (0x80 >> i)) != 0x00 {
-                    if self.gfx[y][x] == 0x01 {
-                        collision = true;
+    /// Move the selected bitplanes of the screen content by the given offsets,
+    /// the pixels scrolled in being blank.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (w, h) = (self.width() as isize, self.height() as isize);
+        let mask = self.planes;
+        let src = self.gfx;
+        for y in 0..h {
+            for x in 0..w {
+                let (sx, sy) = (x - dx, y - dy);
+                let moved = if sx >= 0 && sx < w && sy >= 0 && sy < h {
+                    src[sy as usize][sx as usize] & mask
+                } else {
+                    0
+                };
+                let pixel = &mut self.gfx[y as usize][x as usize];
+                *pixel = (*pixel & !mask) | moved;
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Scroll the screen content down by the given number of pixels.
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    /// Scroll the screen content up by the given number of pixels.
+    pub fn scroll_up(&mut self, n: usize) {
+        self.scroll(0, -(n as isize));
+    }
+
+    /// Scroll the screen content left by the given number of pixels.
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n as isize), 0);
+    }
+
+    /// Scroll the screen content right by the given number of pixels.
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n as isize, 0);
+    }
+
+    /// Draw the given sprite, 'width' pixels wide (8 or 16, with 2 bytes per
+    /// row in the latter case), at the given position.
+    /// When several bitplanes are selected, the sprite holds the data of each
+    /// of them one after the other, the first plane first.
+    /// Return the number of sprite rows in which a pixel was erased.
+    /// The starting position always wraps around the screen ; the pixels
+    /// going past the edges are either clipped or wrapped depending on 'clip'.
+    pub fn draw(&mut self, xpos: usize, ypos: usize, sprite: &[u8], width: usize, clip: bool) -> usize {
+        let (w, h) = (self.width(), self.height());
+        let bytes_per_row = width / 8;
+        let xpos = xpos % w;
+        let ypos = ypos % h;
+        let selected: Vec<u8> = [0b01, 0b10].iter().cloned().filter(|p| self.planes & p != 0).collect();
+        if selected.is_empty() {
+            return 0;
+        }
+        let plane_len = sprite.len() / selected.len();
+        // bit j set when the sprite row j collided, on any plane
+        let mut collided_rows = 0u32;
+
+        for (plane, data) in selected.iter().zip(sprite.chunks(plane_len)) {
+            for (j, row) in data.chunks(bytes_per_row).enumerate() {
+                if clip && ypos + j >= h {
+                    break;
+                }
+                let y = (ypos + j) % h;
+                for i in 0..width {
+                    if clip && xpos + i >= w {
+                        break;
+                    }
+                    let x = (xpos + i) % w;
+
+                    if (row[i / 8] & (0x80 >> (i % 8))) != 0x00 {
+                        if self.gfx[y][x] & plane != 0 {
+                            collided_rows |= 1 << j;
+                        }
+                        self.gfx[y][x] ^= plane;
                     }
-                    self.gfx[y][x] ^= 0x01;
                 }
             }
         }
         self.dirty = true;
 
-        collision
+        collided_rows.count_ones() as usize
     }
 }
 
@@ -63,3 +180,23 @@ pub static FONT_SET: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
+
+/// The SUPER-CHIP 8x10 font, extended to the A-F digits as in XO-CHIP.
+pub static BIG_FONT_SET: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
@@ -0,0 +1,70 @@
+use crate::disasm::DecodeError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// An error stopping the virtual machine, either while loading a ROM or
+/// while executing it. The execution errors carry the address of the
+/// faulty instruction.
+#[derive(Debug)]
+pub enum VmError {
+    /// A ROM or save state file couldn't be read or written.
+    Io(io::Error),
+    /// The ROM doesn't fit in the program memory.
+    RomTooLarge { size: usize, max: usize },
+    /// A 2NNN call was made with all the 16 stack levels in use.
+    StackOverflow { pc: u16 },
+    /// A 00EE return was made with an empty stack.
+    StackUnderflow { pc: u16 },
+    /// An instruction accessed the memory past the end of the address space,
+    /// first at the given address.
+    MemoryOutOfBounds { pc: u16, address: usize },
+    /// The opcode at the given address isn't a valid instruction.
+    InvalidOpcode { pc: u16, error: DecodeError },
+    /// A save state couldn't be decoded, for the given reason.
+    InvalidSaveState(String),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmError::Io(ref why) => write!(f, "I/O error : {}", why),
+            VmError::RomTooLarge { size, max } => write!(
+                f,
+                "the ROM is too large : {} bytes, at most {} bytes fit in memory",
+                size, max
+            ),
+            VmError::StackOverflow { pc } => {
+                write!(f, "stack overflow on the subroutine call at {:#05X}", pc)
+            }
+            VmError::StackUnderflow { pc } => {
+                write!(f, "stack underflow on the subroutine return at {:#05X}", pc)
+            }
+            VmError::MemoryOutOfBounds { pc, address } => write!(
+                f,
+                "the instruction at {:#05X} accessed the memory out of bounds at {:#06X}",
+                pc, address
+            ),
+            VmError::InvalidOpcode { pc, error } => {
+                write!(f, "invalid instruction at {:#05X} : {}", pc, error)
+            }
+            VmError::InvalidSaveState(ref reason) => write!(f, "invalid save state : {}", reason),
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            VmError::Io(ref why) => Some(why),
+            VmError::InvalidOpcode { ref error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(why: io::Error) -> VmError {
+        VmError::Io(why)
+    }
+}
@@ -0,0 +1,431 @@
+//! GDB remote serial protocol : the packets of a GDB stub debugging a 'Vm'
+//! through a 'Debugger', independently of the transport (see the '--gdb'
+//! option of the emulator for a TCP server).
+//!
+//! The registers are described to GDB by a custom target description, in
+//! this order : V0 to VF (8 bits), I, PC and SP (16 bits, little-endian),
+//! the delay and sound timers (8 bits).
+//!
+//! Supported packets : '?', 'g', 'G', 'p', 'P', 'm', 'M', 'c', 's', 'D', 'k',
+//! 'Z0' / 'Z1' breakpoints, 'Z2' / 'Z3' / 'Z4' watchpoints, and the queries
+//! 'qSupported', 'qXfer:features:read', 'QStartNoAckMode', 'qAttached' and
+//! the thread ones, answered for a single thread.
+
+use crate::debugger::{Access, Breakpoint, Debugger, RunMode, StopReason, Watchpoint};
+use crate::vm::Vm;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+/// The target description of the virtual machine.
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.impl-chip8.cpu">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="16" type="uint16"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// Number of registers of the target description.
+const REGISTER_COUNT: usize = 21;
+
+/// What a stub must do after handling a packet.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    /// Send the given reply packet.
+    Reply(String),
+    /// Resume the execution in the given mode ; the reply is the stop reply
+    /// sent once it stops again (see 'stop_reply').
+    Resume(RunMode),
+    /// Reply "OK", then let the program run without the debugger.
+    Detach,
+    /// Kill the program, without reply.
+    Kill,
+}
+
+/// A packet read by 'read_packet'.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Packet {
+    /// A command, with the escapes of its payload resolved.
+    Command(Vec<u8>),
+    /// The interrupt request (Ctrl-C) of GDB.
+    Interrupt,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(string: &str) -> Option<Vec<u8>> {
+    if !string.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..string.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(string.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(string: &str) -> Option<usize> {
+    usize::from_str_radix(string, 16).ok()
+}
+
+/// Parse the 'ADDR,LEN' arguments of the memory packets.
+fn address_and_length(string: &str) -> Option<(usize, usize)> {
+    let mut parts = string.splitn(2, ',');
+    Some((parse_hex(parts.next()?)?, parse_hex(parts.next()?)?))
+}
+
+/// Return the memory range of the 'ADDR,LEN' arguments, if it lies within
+/// the memory of the virtual machine.
+fn memory_range(vm: &Vm, string: &str) -> Option<Range<usize>> {
+    let (address, len) = address_and_length(string)?;
+    let end = address.checked_add(len)?;
+    if end <= vm.memory.len() {
+        Some(address..end)
+    } else {
+        None
+    }
+}
+
+/// Return the little-endian bytes of the given register.
+fn register(vm: &Vm, index: usize) -> Vec<u8> {
+    match index {
+        0..=15 => vec![vm.v[index]],
+        16 => vm.i.to_le_bytes().to_vec(),
+        17 => vm.pc.to_le_bytes().to_vec(),
+        18 => vm.sp.to_le_bytes().to_vec(),
+        19 => vec![vm.delay_timer],
+        _ => vec![vm.sound_timer],
+    }
+}
+
+/// Set the given register from its little-endian bytes, returning the
+/// number of bytes used.
+fn set_register(vm: &mut Vm, index: usize, bytes: &[u8]) -> Option<usize> {
+    let word = || Some(u16::from_le_bytes([*bytes.first()?, *bytes.get(1)?]));
+    match index {
+        0..=15 => vm.v[index] = *bytes.first()?,
+        16 => vm.i = word()?,
+        17 => vm.pc = word()?,
+        // the stack has 16 levels
+        18 => vm.sp = word()?.min(16),
+        19 => vm.delay_timer = *bytes.first()?,
+        _ => vm.sound_timer = *bytes.first()?,
+    }
+    Some(register(vm, index).len())
+}
+
+/// Return the stop reply packet for the given reason.
+pub fn stop_reply(reason: StopReason) -> String {
+    match reason {
+        StopReason::MemoryWatch { address, access, .. } => {
+            let kind = match access {
+                Access::Read => "rwatch",
+                Access::Write => "watch",
+                Access::ReadWrite => "awatch",
+            };
+            format!("T05{}:{:x};", kind, address)
+        }
+        StopReason::Finished => "W00".to_string(),
+        // SIGTRAP
+        _ => "S05".to_string(),
+    }
+}
+
+/// Handle a command packet for the given virtual machine and debugger.
+pub fn handle_packet(packet: &[u8], vm: &mut Vm, debugger: &mut Debugger) -> Response {
+    let packet = String::from_utf8_lossy(packet);
+    let reply = |string: &str| Response::Reply(string.to_string());
+    let error = reply("E01");
+
+    let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+    match command {
+        "?" => reply("S05"),
+        "g" => Response::Reply((0..REGISTER_COUNT).map(|index| hex(&register(vm, index))).collect()),
+        "G" => {
+            let bytes = match unhex(args) {
+                Some(bytes) => bytes,
+                None => return error,
+            };
+            let mut offset = 0;
+            for index in 0..REGISTER_COUNT {
+                match set_register(vm, index, &bytes[offset.min(bytes.len())..]) {
+                    Some(len) => offset += len,
+                    None => return error,
+                }
+            }
+            reply("OK")
+        }
+        "p" => match parse_hex(args) {
+            Some(index) if index < REGISTER_COUNT => Response::Reply(hex(&register(vm, index))),
+            _ => error,
+        },
+        "P" => {
+            let mut parts = args.splitn(2, '=');
+            let index = parts.next().and_then(parse_hex);
+            let bytes = parts.next().and_then(unhex);
+            match (index, bytes) {
+                (Some(index), Some(bytes)) if index < REGISTER_COUNT => match set_register(vm, index, &bytes) {
+                    Some(_) => reply("OK"),
+                    None => error,
+                },
+                _ => error,
+            }
+        }
+        "m" => match memory_range(vm, args) {
+            Some(range) => Response::Reply(hex(&vm.memory[range])),
+            None => error,
+        },
+        "M" => {
+            let mut parts = args.splitn(2, ':');
+            let range = parts.next().and_then(|range| memory_range(vm, range));
+            let bytes = parts.next().and_then(unhex);
+            match (range, bytes) {
+                (Some(range), Some(bytes)) if range.len() == bytes.len() => {
+                    vm.memory[range].copy_from_slice(&bytes);
+                    reply("OK")
+                }
+                _ => error,
+            }
+        }
+        "c" | "s" => {
+            if let Some(address) = parse_hex(args) {
+                vm.pc = address as u16;
+            }
+            Response::Resume(if command == "c" { RunMode::Continue } else { RunMode::StepInto })
+        }
+        "D" => Response::Detach,
+        "k" => Response::Kill,
+        "Z" | "z" => {
+            let fields: Vec<Option<usize>> = args.splitn(3, ',').map(parse_hex).collect();
+            let (kind, address, len) = match fields[..] {
+                [Some(kind), Some(address), Some(len)] if address <= 0xFFFF => (kind, address as u16, len),
+                _ => return error,
+            };
+            let insert = command == "Z";
+            let access = match kind {
+                0 | 1 => {
+                    let breakpoint = Breakpoint { address, condition: None };
+                    debugger.breakpoints.retain(|b| *b != breakpoint);
+                    if insert {
+                        debugger.breakpoints.push(breakpoint);
+                    }
+                    return reply("OK");
+                }
+                2 => Access::Write,
+                3 => Access::Read,
+                4 => Access::ReadWrite,
+                _ => return reply(""),
+            };
+            let watchpoint = Watchpoint::Memory { address, len: len.clamp(1, 0xFFFF) as u16, access };
+            debugger.watchpoints.retain(|w| *w != watchpoint);
+            if insert {
+                debugger.watchpoints.push(watchpoint);
+            }
+            reply("OK")
+        }
+        _ => {
+            if let Some(annex) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+                return match address_and_length(annex) {
+                    Some((offset, len)) => {
+                        let xml = TARGET_XML.as_bytes();
+                        let start = offset.min(xml.len());
+                        let end = start.saturating_add(len).min(xml.len());
+                        let more = if end < xml.len() { "m" } else { "l" };
+                        Response::Reply(format!("{}{}", more, String::from_utf8_lossy(&xml[start..end])))
+                    }
+                    None => error,
+                };
+            }
+            match packet.split(':').next().unwrap_or("") {
+                "qSupported" => reply("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+"),
+                "QStartNoAckMode" => reply("OK"),
+                "qAttached" => reply("1"),
+                "qC" => reply("QC1"),
+                "qfThreadInfo" => reply("m1"),
+                "qsThreadInfo" => reply("l"),
+                "qSymbol" => reply("OK"),
+                _ if packet.starts_with('H') || packet.starts_with('T') => reply("OK"),
+                // unsupported
+                _ => reply(""),
+            }
+        }
+    }
+}
+
+/// Read the next packet, acknowledging it unless 'no_ack'. Packets with a
+/// bad checksum are rejected and skipped.
+pub fn read_packet<S: Read + Write>(stream: &mut S, no_ack: bool) -> io::Result<Packet> {
+    let mut byte = [0u8];
+    loop {
+        stream.read_exact(&mut byte)?;
+        match byte[0] {
+            0x03 => return Ok(Packet::Interrupt),
+            b'$' => {}
+            // acknowledgments of our packets, and noise
+            _ => continue,
+        }
+        let mut payload = Vec::new();
+        let mut checksum = 0u8;
+        loop {
+            stream.read_exact(&mut byte)?;
+            if byte[0] == b'#' {
+                break;
+            }
+            checksum = checksum.wrapping_add(byte[0]);
+            payload.push(byte[0]);
+        }
+        let mut expected = [0u8; 2];
+        stream.read_exact(&mut expected)?;
+        let valid = std::str::from_utf8(&expected)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok())
+            .is_some_and(|expected| expected == checksum);
+        if !no_ack {
+            stream.write_all(if valid { b"+" } else { b"-" })?;
+        }
+        if valid {
+            return Ok(Packet::Command(unescape(&payload)));
+        }
+    }
+}
+
+/// Resolve the '}' escapes of a binary payload.
+fn unescape(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len());
+    let mut bytes = payload.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => match bytes.next() {
+                Some(&escaped) => out.push(escaped ^ 0x20),
+                None => break,
+            },
+            _ => out.push(byte),
+        }
+    }
+    out
+}
+
+/// Write the given packet.
+pub fn write_packet<W: Write>(stream: &mut W, payload: &str) -> io::Result<()> {
+    let mut escaped = Vec::with_capacity(payload.len());
+    for &byte in payload.as_bytes() {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.extend_from_slice(&[b'}', byte ^ 0x20]);
+        } else {
+            escaped.push(byte);
+        }
+    }
+    let checksum = escaped.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    stream.write_all(b"$")?;
+    stream.write_all(&escaped)?;
+    write!(stream, "#{:02x}", checksum)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An in-memory stream, reading the given input.
+    struct Stream {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_framing() {
+        let mut stream = Stream {
+            input: io::Cursor::new(b"+$m200,2#5d$g#00$g#67\x03".to_vec()),
+            output: Vec::new(),
+        };
+        assert_eq!(read_packet(&mut stream, false).unwrap(), Packet::Command(b"m200,2".to_vec()));
+        // the bad checksum is rejected
+        assert_eq!(read_packet(&mut stream, false).unwrap(), Packet::Command(b"g".to_vec()));
+        assert_eq!(read_packet(&mut stream, true).unwrap(), Packet::Interrupt);
+        assert_eq!(stream.output, b"+-+");
+
+        let mut output = Vec::new();
+        write_packet(&mut output, "a#b").unwrap();
+        assert_eq!(output, b"$a}\x03b#43");
+    }
+
+    #[test]
+    fn test_session() {
+        let mut vm = Vm::new();
+        vm.load_rom(&[0x63, 0x10, 0xA3, 0x00, 0xF3, 0x55, 0x12, 0x06]).unwrap();
+        let mut debugger = Debugger::new();
+        let mut send = |packet: &str, vm: &mut Vm| handle_packet(packet.as_bytes(), vm, &mut debugger);
+        let reply = |string: &str| Response::Reply(string.to_string());
+
+        assert_eq!(send("m200,4", &mut vm), reply("6310a300"));
+        assert_eq!(send("p11", &mut vm), reply("0002"));
+        assert_eq!(send("P3=2a", &mut vm), reply("OK"));
+        let registers = match send("g", &mut vm) {
+            Response::Reply(registers) => registers,
+            response => panic!("unexpected {:?}", response),
+        };
+        assert_eq!(registers.len(), 2 * 24);
+        assert_eq!(&registers[6..8], "2a");
+        assert_eq!(send("M300,2:abcd", &mut vm), reply("OK"));
+        assert_eq!(vm.memory[0x300..0x302], [0xAB, 0xCD]);
+        assert_eq!(send("qXfer:features:read:target.xml:0,a", &mut vm), reply("m<?xml vers"));
+        // out of range or overflowing accesses are rejected
+        assert_eq!(send("mffff,2", &mut vm), reply("E01"));
+        assert_eq!(send("mffffffffffffffff,10", &mut vm), reply("E01"));
+        assert_eq!(send("Mffff,2:abcd", &mut vm), reply("E01"));
+        assert_eq!(send("Mffffffffffffffff,2:abcd", &mut vm), reply("E01"));
+        assert_eq!(send("mfffe,2", &mut vm), reply("0000"));
+
+        assert_eq!(send("Z0,204,2", &mut vm), reply("OK"));
+        assert_eq!(send("Z2,300,1", &mut vm), reply("OK"));
+        assert_eq!(send("s", &mut vm), Response::Resume(RunMode::StepInto));
+        assert_eq!(send("c", &mut vm), Response::Resume(RunMode::Continue));
+        assert_eq!(send("z0,204,2", &mut vm), reply("OK"));
+
+        let mut debugger = Debugger::new();
+        handle_packet(b"Z0,204,2", &mut vm, &mut debugger);
+        handle_packet(b"Z2,300,1", &mut vm, &mut debugger);
+        let reason = debugger.run(&mut vm, RunMode::Continue, 100).unwrap();
+        assert_eq!(stop_reply(reason), "S05");
+        let reason = debugger.run(&mut vm, RunMode::Continue, 100).unwrap();
+        assert_eq!(stop_reply(reason), "T05watch:300;");
+    }
+}
@@ -0,0 +1,234 @@
+//! Running a program without any user interface, for a given number of
+//! cycles or frames, with scripted key presses : e.g. to run ROMs in CI on
+//! machines without a display.
+
+use crate::audio::SoundRecorder;
+use crate::capture::Capture;
+use crate::error::VmError;
+use crate::keypad::Keystate;
+use crate::movie::{Movie, Player};
+use std::ops::RangeInclusive;
+use crate::vm::{FrameReport, Vm};
+
+/// A key held down by a script for a number of frames.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct KeyPress {
+    /// The frame the key is pressed at, the first one being 0.
+    pub frame: u64,
+    /// The index of the key, 0x0 to 0xF.
+    pub key: usize,
+    /// The number of frames the key is held down.
+    pub frames: u64,
+}
+
+impl KeyPress {
+    /// Parse a key press written "FRAME:KEY[:FRAMES]", the key being an
+    /// hexadecimal digit, e.g. "120:5" or "120:5:30". The key is held down
+    /// for 1 frame by default.
+    pub fn parse(string: &str) -> Option<KeyPress> {
+        let mut fields = string.trim().split(':');
+        let frame = fields.next()?.trim().parse().ok()?;
+        let key = usize::from_str_radix(fields.next()?.trim(), 16).ok().filter(|&key| key < 16)?;
+        let frames = match fields.next() {
+            Some(frames) => frames.trim().parse().ok().filter(|&frames| frames > 0)?,
+            None => 1,
+        };
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(KeyPress { frame, key, frames })
+    }
+
+    /// Parse a script of key presses, separated by whitespace or commas ;
+    /// '#' starts a comment running to the end of the line.
+    pub fn parse_script(script: &str) -> Result<Vec<KeyPress>, String> {
+        let mut presses = Vec::new();
+        for (n, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            for word in line.split(|c: char| c.is_whitespace() || c == ',').filter(|w| !w.is_empty()) {
+                match KeyPress::parse(word) {
+                    Some(press) => presses.push(press),
+                    None => return Err(format!("line {} : invalid key press \"{}\"", n + 1, word)),
+                }
+            }
+        }
+        Ok(presses)
+    }
+}
+
+/// When a headless run stops.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Limit {
+    /// After the given number of CPU cycles, counting the ones spent
+    /// waiting for a key press or for the vertical blank.
+    Cycles(u64),
+    /// After the given number of 60 Hz frames.
+    Frames(u64),
+}
+
+/// A virtual machine run at a fixed number of instructions per frame,
+/// without any real time throttling.
+pub struct Runner {
+    pub vm: Vm,
+    /// The number of CPU cycles per 60 Hz frame.
+    pub instructions_per_frame: u32,
+    /// The scripted key presses.
+    pub script: Vec<KeyPress>,
+    /// The input movie played back, on top of the script.
+    pub player: Option<Player>,
+    /// The input movie recorded, if any.
+    pub recording: Option<Movie>,
+    /// The recorder of the sound, if any.
+    pub sound: Option<SoundRecorder>,
+    /// The capture of the screen, if any, stopped on error.
+    pub capture: Option<Capture>,
+    /// The frames captured, the first one starting the capture.
+    pub capture_frames: RangeInclusive<u64>,
+    /// The number of frames run so far.
+    pub frame: u64,
+    /// The number of CPU cycles run so far.
+    pub cycles: u64,
+    /// The current frame, run in parts under a cycle limit.
+    report: FrameReport,
+}
+
+impl Runner {
+    pub fn new(vm: Vm, instructions_per_frame: u32, script: Vec<KeyPress>) -> Runner {
+        Runner {
+            vm,
+            instructions_per_frame: instructions_per_frame.max(1),
+            script,
+            player: None,
+            recording: None,
+            sound: None,
+            capture: None,
+            capture_frames: 0..=u64::MAX,
+            frame: 0,
+            cycles: 0,
+            report: FrameReport::default(),
+        }
+    }
+
+    /// Run the program until the given limit, possibly in the middle of a
+    /// frame. Return true if the program finished before.
+    pub fn run(&mut self, limit: Limit) -> Result<bool, VmError> {
+        loop {
+            if let Limit::Frames(frames) = limit {
+                if self.report.cycles == 0 && self.frame >= frames {
+                    return Ok(false);
+                }
+            }
+            let max_cycles = match limit {
+                Limit::Cycles(cycles) if self.cycles >= cycles => return Ok(false),
+                Limit::Cycles(cycles) => cycles - self.cycles,
+                Limit::Frames(_) => u64::MAX,
+            };
+            if self.report.cycles == 0 {
+                self.update_keys();
+            }
+            let cycles = self.report.cycles;
+            let result = self.vm.run_frame_with(&mut self.report, self.instructions_per_frame, max_cycles, Vm::step);
+            self.cycles += u64::from(self.report.cycles - cycles);
+            result?;
+            if self.report.finished {
+                return Ok(true);
+            }
+            if self.report.ended {
+                self.end_frame();
+                self.report = FrameReport::default();
+            }
+        }
+    }
+
+    /// Press and release the scripted keys and the keys of the movie played
+    /// back, for the current frame.
+    fn update_keys(&mut self) {
+        let frame = self.frame;
+        let mut events: Vec<(usize, Keystate)> = self
+            .script
+            .iter()
+            .filter(|press| press.frame + press.frames == frame)
+            .map(|press| (press.key, Keystate::Released))
+            .collect();
+        events.extend(
+            self.script
+                .iter()
+                .filter(|press| press.frame == frame)
+                .map(|press| (press.key, Keystate::Pressed)),
+        );
+        if let Some(ref mut player) = self.player {
+            events.extend(player.frame_events());
+        }
+        for (key, state) in events {
+            match self.recording {
+                Some(ref mut movie) => movie.record_key(&mut self.vm, key, state),
+                None => self.vm.keypad.key_event(key, state),
+            }
+        }
+    }
+
+    /// Record the sound and screen of the frame the virtual machine ended,
+    /// checking and recording the input movies.
+    fn end_frame(&mut self) {
+        if let Some(ref mut sound) = self.sound {
+            sound.record_frame(&self.vm, self.report.sound);
+        }
+        if let Some(ref mut capture) = self.capture {
+            if self.capture_frames.contains(&self.frame) {
+                let time = (self.frame - self.capture_frames.start()) as f64 / 60.0;
+                if let Err(why) = capture.add_frame(&self.vm.display, time) {
+                    warn!("couldn't write the capture, stopping it : {}", why);
+                    self.capture = None;
+                }
+            }
+        }
+        if let Some(ref mut player) = self.player {
+            if player.end_frame(&self.vm) {
+                warn!("the replay diverged from the movie at frame {}.", self.frame);
+            }
+        }
+        if let Some(ref mut movie) = self.recording {
+            movie.record_frame(&self.vm);
+        }
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+
+    #[test]
+    fn test_key_press() {
+        assert_eq!(KeyPress::parse("120:a"), Some(KeyPress { frame: 120, key: 0xA, frames: 1 }));
+        assert_eq!(KeyPress::parse("3:F:30"), Some(KeyPress { frame: 3, key: 0xF, frames: 30 }));
+        assert_eq!(KeyPress::parse("3:10"), None);
+        assert_eq!(KeyPress::parse("3:1:0"), None);
+        assert_eq!(KeyPress::parse("3"), None);
+        let script = KeyPress::parse_script("# start\n10:5, 20:6:2\n\n30:0 # fire").unwrap();
+        assert_eq!(script.len(), 3);
+        assert_eq!(KeyPress::parse_script("10:5\n20:x").unwrap_err(), "line 2 : invalid key press \"20:x\"");
+    }
+
+    #[test]
+    fn test_run() {
+        // V0 := key ; V1 := delay ; loop : V2 += 1 ; jump loop
+        let rom = [0xF0, 0x0A, 0xF1, 0x07, 0x72, 0x01, 0x12, 0x04];
+        let mut vm = Vm::with_quirks(Quirks::superchip());
+        vm.load_rom(&rom).unwrap();
+        vm.delay_timer = 50;
+        let mut runner = Runner::new(vm, 10, vec![KeyPress { frame: 3, key: 7, frames: 2 }]);
+
+        assert!(!runner.run(Limit::Frames(3)).unwrap());
+        assert!(runner.vm.is_waiting_for_key());
+        assert_eq!(runner.cycles, 30);
+        assert!(!runner.run(Limit::Cycles(32)).unwrap());
+        assert_eq!(runner.vm.v[0], 7);
+        assert_eq!(runner.vm.v[1], 47);
+        assert_eq!(runner.vm.keypad.get_key_state(7), Keystate::Pressed);
+        assert!(!runner.run(Limit::Frames(6)).unwrap());
+        assert_eq!(runner.vm.keypad.get_key_state(7), Keystate::Released);
+        assert_eq!(runner.vm.delay_timer, 44);
+    }
+}
@@ -1,18 +1,41 @@
+use std::collections::VecDeque;
+
+/// The maximum number of key events queued ; the oldest are dropped past it,
+/// when nothing consumes them.
+const MAX_EVENTS: usize = 32;
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Keystate {
     Pressed,
     Released,
 }
 
+/// A change of state of a key : a press or a release edge.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub key: usize,
+    pub state: Keystate,
+}
+
 pub struct Keypad {
     /// 16 个键，1 -> F , 如果被按下的话，就是 true
     keys: [Keystate; 16],
+    /// The key events not consumed yet, the oldest first. Consumed by the
+    /// virtual machine when it waits for a key (FX0A).
+    events: VecDeque<KeyEvent>,
+}
+
+impl Default for Keypad {
+    fn default() -> Keypad {
+        Keypad::new()
+    }
 }
 
 impl Keypad {
     pub fn new() -> Keypad {
         Keypad {
             keys: [Keystate::Released; 16],
+            events: VecDeque::new(),
         }
     }
 
@@ -22,9 +45,74 @@ impl Keypad {
         self.keys[index]
     }
 
-    /// Set the current key state for the key at the given index.
+    /// Set the current key state for the key at the given index, without
+    /// queueing any event (e.g. to restore a state).
     pub fn set_key_state(&mut self, index: usize, state: Keystate) {
         debug_assert!(index < 16);
         self.keys[index] = state;
     }
+
+    /// Forward a key event from the frontend : set the state of the key and
+    /// queue the edge if the state changed. Key repeats are ignored.
+    pub fn key_event(&mut self, index: usize, state: Keystate) {
+        debug_assert!(index < 16);
+        if self.keys[index] == state {
+            return;
+        }
+        self.keys[index] = state;
+        self.queue_event(KeyEvent { key: index, state });
+    }
+
+    /// Queue a key event, without setting the state of the key (e.g. to
+    /// restore a state).
+    pub fn queue_event(&mut self, event: KeyEvent) {
+        debug_assert!(event.key < 16);
+        if self.events.len() >= MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// Return the key events queued, the oldest first.
+    pub fn events(&self) -> impl Iterator<Item = &KeyEvent> {
+        self.events.iter()
+    }
+
+    /// Remove and return the oldest key event, if any.
+    pub fn pop_event(&mut self) -> Option<KeyEvent> {
+        self.events.pop_front()
+    }
+
+    /// Forget the key events queued so far.
+    pub fn clear_events(&mut self) {
+        self.events.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_events() {
+        let mut keypad = Keypad::new();
+        keypad.key_event(5, Keystate::Pressed);
+        keypad.key_event(5, Keystate::Pressed);
+        keypad.key_event(5, Keystate::Released);
+        assert_eq!(keypad.pop_event(), Some(KeyEvent { key: 5, state: Keystate::Pressed }));
+        assert_eq!(keypad.pop_event(), Some(KeyEvent { key: 5, state: Keystate::Released }));
+        assert_eq!(keypad.pop_event(), None);
+
+        keypad.set_key_state(3, Keystate::Pressed);
+        assert_eq!(keypad.get_key_state(3), Keystate::Pressed);
+        assert_eq!(keypad.pop_event(), None);
+
+        for _ in 0..MAX_EVENTS {
+            keypad.key_event(1, Keystate::Pressed);
+            keypad.key_event(1, Keystate::Released);
+        }
+        keypad.key_event(2, Keystate::Pressed);
+        assert_eq!(keypad.events.len(), MAX_EVENTS);
+        assert_eq!(keypad.events.back(), Some(&KeyEvent { key: 2, state: Keystate::Pressed }));
+    }
 }
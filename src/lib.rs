@@ -1,6 +1,22 @@
 #[macro_use]
 extern crate log;
 
+pub mod asm;
+pub mod audio;
+pub mod capture;
+pub mod debugger;
+pub mod disasm;
 pub mod display;
+pub mod error;
+pub mod gdb;
+pub mod headless;
 pub mod keypad;
+pub mod movie;
+pub mod profiler;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod savestate;
+pub mod screenshot;
+pub mod trace;
 pub mod vm;
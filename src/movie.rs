@@ -0,0 +1,356 @@
+//! Input movies : the key presses and releases of a session, stamped with
+//! their frame number, replayed to reproduce the session exactly.
+//!
+//! The movie also holds everything else the emulation depends on : the ROM
+//! hash, the random generator seed, the quirks, the number of instructions
+//! per frame and the RPL user flags at the start. A checksum of the virtual
+//! machine state is recorded at the end of every frame, for a replay going
+//! out of sync to report the first frame it diverged at.
+//!
+//! The text format starts with the 'HEADER' line, then the header fields,
+//! one "NAME VALUE" line each, then one line per event, in frame order :
+//!
+//! ```text
+//! CHIP8-MOVIE 1
+//! rom 3f2b8a0c9d4e5f61
+//! seed 42
+//! quirks 000000bf
+//! instructions 10
+//! rpl 00000000000000000000000000000000
+//! 0 =1a2b3c4d
+//! 1 +5
+//! 1 =5e6f7a8b
+//! 2 -5
+//! 2 =9c0d1e2f
+//! ```
+//!
+//! "FRAME +K" and "FRAME -K" press and release the key K at the start of the
+//! frame, "FRAME =CHECKSUM" is the checksum at its end (see 'checksum').
+
+use crate::keypad::Keystate;
+use crate::quirks::Quirks;
+use crate::rng::Rng;
+use crate::savestate::{quirks_bits, quirks_from_bits};
+use crate::vm::{rom_hash, Vm};
+
+/// The first line of the movies, the number being the format version.
+pub const HEADER: &str = "CHIP8-MOVIE 1";
+
+/// Return the checksum of the complete state of the virtual machine.
+pub fn checksum(vm: &Vm) -> u32 {
+    rom_hash(&vm.to_state_bytes()) as u32
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    /// The hash of the ROM recorded, see 'Vm::rom_hash'.
+    pub rom_hash: u64,
+    /// The seed of the random generator.
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub instructions_per_frame: u32,
+    /// The RPL user flags at the start.
+    pub rpl: [u8; 16],
+    /// The key events, with the frame they happen at the start of.
+    pub events: Vec<(u64, usize, Keystate)>,
+    /// The checksum of the virtual machine at the end of every frame.
+    pub checksums: Vec<u32>,
+}
+
+impl Movie {
+    /// Start the recording of the given virtual machine, whose random
+    /// generator was created from 'seed'.
+    pub fn new(vm: &Vm, seed: u64, instructions_per_frame: u32) -> Movie {
+        Movie {
+            rom_hash: vm.rom_hash,
+            seed,
+            quirks: vm.quirks,
+            instructions_per_frame,
+            rpl: vm.rpl,
+            events: Vec::new(),
+            checksums: Vec::new(),
+        }
+    }
+
+    /// Return the number of frames recorded.
+    pub fn frames(&self) -> u64 {
+        self.checksums.len() as u64
+    }
+
+    /// Set up the virtual machine, with its ROM loaded, to replay the movie.
+    /// Return an error if the ROM isn't the one recorded.
+    pub fn setup(&self, vm: &mut Vm) -> Result<(), String> {
+        vm.quirks = self.quirks;
+        vm.rng = Rng::new(self.seed);
+        vm.rpl = self.rpl;
+        if vm.rom_hash != self.rom_hash {
+            return Err(format!(
+                "the movie was recorded with another ROM (hash {:016x}, not {:016x})",
+                self.rom_hash, vm.rom_hash
+            ));
+        }
+        Ok(())
+    }
+
+    /// Set the state of a key of the virtual machine, recording the event if
+    /// the state changed.
+    pub fn record_key(&mut self, vm: &mut Vm, key: usize, state: Keystate) {
+        if vm.keypad.get_key_state(key) != state {
+            self.events.push((self.frames(), key, state));
+        }
+        vm.keypad.key_event(key, state);
+    }
+
+    /// Record the end of a frame.
+    pub fn record_frame(&mut self, vm: &Vm) {
+        self.checksums.push(checksum(vm));
+    }
+
+    /// Return the movie in the text format.
+    pub fn to_text(&self) -> String {
+        let rpl: Vec<String> = self.rpl.iter().map(|flag| format!("{:02x}", flag)).collect();
+        let mut text = format!(
+            "{}\nrom {:016x}\nseed {}\nquirks {:08x}\ninstructions {}\nrpl {}\n",
+            HEADER,
+            self.rom_hash,
+            self.seed,
+            quirks_bits(&self.quirks),
+            self.instructions_per_frame,
+            rpl.concat()
+        );
+        let mut events = self.events.iter().peekable();
+        for (frame, sum) in self.checksums.iter().enumerate() {
+            while let Some((_, key, state)) = events.next_if(|&&(f, _, _)| f == frame as u64) {
+                let sign = if *state == Keystate::Pressed { '+' } else { '-' };
+                text += &format!("{} {}{:x}\n", frame, sign, key);
+            }
+            text += &format!("{} ={:08x}\n", frame, sum);
+        }
+        // the events of the frame in progress when the recording stopped
+        for (frame, key, state) in events {
+            let sign = if *state == Keystate::Pressed { '+' } else { '-' };
+            text += &format!("{} {}{:x}\n", frame, sign, key);
+        }
+        text
+    }
+
+    /// Parse a movie in the text format. The errors tell the faulty line,
+    /// e.g. "line 8 : invalid event \"12 *3\"".
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, line)| line.trim()) != Some(HEADER) {
+            return Err(format!("not a movie, the first line isn't \"{}\"", HEADER));
+        }
+        let mut movie = Movie {
+            rom_hash: 0,
+            seed: 0,
+            quirks: Quirks::default(),
+            instructions_per_frame: 0,
+            rpl: [0; 16],
+            events: Vec::new(),
+            checksums: Vec::new(),
+        };
+
+        for (n, line) in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |what: &str| format!("line {} : invalid {} \"{}\"", n + 1, what, line);
+            let (name, value) = line.split_once(' ').ok_or_else(|| invalid("line"))?;
+            let value = value.trim();
+            match name {
+                "rom" => movie.rom_hash = u64::from_str_radix(value, 16).map_err(|_| invalid("ROM hash"))?,
+                "seed" => movie.seed = value.parse().map_err(|_| invalid("seed"))?,
+                "quirks" => {
+                    movie.quirks = quirks_from_bits(u32::from_str_radix(value, 16).map_err(|_| invalid("quirks"))?)
+                }
+                "instructions" => {
+                    movie.instructions_per_frame = value.parse().map_err(|_| invalid("number of instructions"))?
+                }
+                "rpl" => {
+                    if value.len() != 32 || !value.is_ascii() {
+                        return Err(invalid("RPL user flags"));
+                    }
+                    for (i, flag) in movie.rpl.iter_mut().enumerate() {
+                        *flag = u8::from_str_radix(&value[2 * i..2 * i + 2], 16)
+                            .map_err(|_| invalid("RPL user flags"))?;
+                    }
+                }
+                frame => {
+                    let frame: u64 = frame.parse().map_err(|_| invalid("line"))?;
+                    if frame < movie.frames() || movie.events.last().is_some_and(|&(f, _, _)| frame < f) {
+                        return Err(format!("line {} : frame {} out of order", n + 1, frame));
+                    }
+                    let (sign, digits) = value.split_at(value.chars().next().map_or(0, char::len_utf8));
+                    match sign {
+                        "+" | "-" => {
+                            let key = usize::from_str_radix(digits, 16).map_err(|_| invalid("event"))?;
+                            if key > 0xF {
+                                return Err(invalid("event"));
+                            }
+                            let state = if sign == "+" { Keystate::Pressed } else { Keystate::Released };
+                            movie.events.push((frame, key, state));
+                        }
+                        "=" => {
+                            if frame != movie.frames() {
+                                return Err(format!("line {} : missing the checksum of frame {}", n + 1, movie.frames()));
+                            }
+                            let sum = u32::from_str_radix(digits, 16).map_err(|_| invalid("checksum"))?;
+                            movie.checksums.push(sum);
+                        }
+                        _ => return Err(invalid("event")),
+                    }
+                }
+            }
+        }
+
+        if movie.instructions_per_frame == 0 {
+            return Err("the movie has no number of instructions per frame".to_string());
+        }
+        Ok(movie)
+    }
+}
+
+/// The replay of a 'Movie', fed frame by frame to a virtual machine set up
+/// by 'Movie::setup'.
+#[derive(Clone)]
+pub struct Player {
+    pub movie: Movie,
+    /// The number of frames replayed so far.
+    pub frame: u64,
+    /// The index of the next key event.
+    next_event: usize,
+    /// The first frame whose checksum differs from the recorded one, if any.
+    pub diverged: Option<u64>,
+}
+
+impl Player {
+    pub fn new(movie: Movie) -> Player {
+        Player {
+            movie,
+            frame: 0,
+            next_event: 0,
+            diverged: None,
+        }
+    }
+
+    /// Return whether all the frames recorded were replayed.
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames()
+    }
+
+    /// Return the key events of the frame starting, to be fed to the virtual
+    /// machine.
+    pub fn frame_events(&mut self) -> Vec<(usize, Keystate)> {
+        let mut events = Vec::new();
+        while let Some(&(frame, key, state)) = self.movie.events.get(self.next_event) {
+            if frame > self.frame {
+                break;
+            }
+            events.push((key, state));
+            self.next_event += 1;
+        }
+        events
+    }
+
+    /// Feed the key events of the frame starting to the virtual machine.
+    pub fn start_frame(&mut self, vm: &mut Vm) {
+        for (key, state) in self.frame_events() {
+            vm.keypad.key_event(key, state);
+        }
+    }
+
+    /// Check the state of the virtual machine at the end of a frame against
+    /// the recorded one. Return true if the replay diverges at this frame,
+    /// the first one to differ.
+    pub fn end_frame(&mut self, vm: &Vm) -> bool {
+        let recorded = self.movie.checksums.get(self.frame as usize).cloned();
+        let diverges = self.diverged.is_none() && recorded.is_some_and(|sum| sum != checksum(vm));
+        if diverges {
+            self.diverged = Some(self.frame);
+        }
+        self.frame += 1;
+        diverges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load 'loop : v0 := key ; v1 := random 0xFF ; i += v1 ; jump loop'.
+    fn new_vm(seed: u64) -> Vm {
+        let mut vm = Vm::with_quirks(Quirks::superchip());
+        vm.load_rom(&[0xF0, 0x0A, 0xC1, 0xFF, 0xF1, 0x1E, 0x12, 0x00]).unwrap();
+        vm.rng = Rng::new(seed);
+        vm
+    }
+
+    fn replay(movie: &Movie) -> (Vm, Player) {
+        let mut vm = new_vm(0);
+        movie.setup(&mut vm).unwrap();
+        let mut player = Player::new(movie.clone());
+        while !player.is_finished() {
+            player.start_frame(&mut vm);
+            vm.run_frame(movie.instructions_per_frame).unwrap();
+            player.end_frame(&vm);
+        }
+        (vm, player)
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let keys = [
+            (2, 5, Keystate::Pressed),
+            (2, 5, Keystate::Pressed),
+            (4, 5, Keystate::Released),
+            (9, 0xA, Keystate::Pressed),
+        ];
+        let mut vm = new_vm(42);
+        let mut movie = Movie::new(&vm, 42, 10);
+        for frame in 0..20 {
+            for &(_, key, state) in keys.iter().filter(|&&(f, _, _)| f == frame) {
+                movie.record_key(&mut vm, key, state);
+            }
+            vm.run_frame(10).unwrap();
+            movie.record_frame(&vm);
+        }
+        assert_eq!(movie.frames(), 20);
+        assert_eq!(movie.events, vec![keys[0], keys[2], keys[3]]);
+
+        let text = movie.to_text();
+        assert!(text.starts_with("CHIP8-MOVIE 1\n"));
+        assert!(text.contains("\nseed 42\nquirks 00000054\ninstructions 10\n"));
+        assert!(text.contains("\n2 +5\n2 ="));
+        let mut parsed = Movie::parse(&text).unwrap();
+        assert_eq!(parsed, movie);
+
+        let (replayed, player) = replay(&parsed);
+        assert_eq!(player.diverged, None);
+        assert_eq!((replayed.i, replayed.v), (vm.i, vm.v));
+
+        // a late key press
+        parsed.events[0].0 = 3;
+        assert_eq!(replay(&parsed).1.diverged, Some(2));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Movie::parse("CHIP8-SAVE\n").is_err());
+        let header = "CHIP8-MOVIE 1\ninstructions 10\n";
+        assert_eq!(
+            Movie::parse(&format!("{}0 =00000000\n1 *3\n", header)).unwrap_err(),
+            "line 4 : invalid event \"1 *3\""
+        );
+        assert_eq!(
+            Movie::parse(&format!("{}1 =00000000\n", header)).unwrap_err(),
+            "line 3 : missing the checksum of frame 0"
+        );
+        assert_eq!(
+            Movie::parse(&format!("{}3 +1\n2 -1\n", header)).unwrap_err(),
+            "line 4 : frame 2 out of order"
+        );
+        assert!(Movie::parse("CHIP8-MOVIE 1\n").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+//! Profiler : execution counts per address, cycles spent in every
+//! subroutine and a histogram of the opcodes, gathered by 'Vm::step' when a
+//! 'Profiler' is set on the virtual machine, and reported sorted by cost.
+//!
+//! The cycles are counted with 'Vm::run_counter', one per instruction.
+
+use crate::disasm::{decode_at, Instruction, Syntax};
+use crate::vm::Vm;
+use std::collections::{BTreeMap, HashMap};
+
+/// The maximum number of hot spots in a report.
+const MAX_HOT_SPOTS: usize = 40;
+
+/// The deepest call stack followed ; the oldest calls are forgotten past it,
+/// e.g. when a program leaves its subroutines with jumps.
+const MAX_CALL_DEPTH: usize = 64;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SubroutineStats {
+    /// The number of calls.
+    pub calls: u64,
+    /// The number of cycles from the calls to the returns, both included,
+    /// with the nested calls.
+    pub cycles: u64,
+}
+
+pub struct Profiler {
+    /// The number of executions of the instruction at every address.
+    counts: Vec<u64>,
+    /// The number of executions of every opcode pattern, see
+    /// 'Instruction::pattern'.
+    histogram: BTreeMap<&'static str, u64>,
+    /// The statistics of the subroutines, by address.
+    subroutines: BTreeMap<u16, SubroutineStats>,
+    /// The subroutines being run, with the cycle of their call.
+    calls: Vec<(u16, u64)>,
+    /// The names of the addresses, e.g. the labels of the assembled source.
+    pub symbols: BTreeMap<u16, String>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            counts: vec![0; 0x10000],
+            histogram: BTreeMap::new(),
+            subroutines: BTreeMap::new(),
+            calls: Vec::new(),
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    /// Parse the symbols written by the 'asm' subcommand, one "ADDRESS NAME"
+    /// line per label, e.g. "0x0200 main". Invalid lines are skipped.
+    pub fn parse_symbols(text: &str) -> BTreeMap<u16, String> {
+        let mut symbols = BTreeMap::new();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            if let (Some(address), Some(name)) = (fields.next(), fields.next()) {
+                let address = address.trim_start_matches("0x").trim_start_matches("0X");
+                if let Ok(address) = u16::from_str_radix(address, 16) {
+                    symbols.insert(address, name.to_string());
+                }
+            }
+        }
+        symbols
+    }
+
+    /// Return the number of executions of the instruction at 'address'.
+    pub fn count(&self, address: u16) -> u64 {
+        self.counts[address as usize]
+    }
+
+    /// Return the statistics of the subroutine at 'address'.
+    pub fn subroutine(&self, address: u16) -> SubroutineStats {
+        self.subroutines.get(&address).cloned().unwrap_or_default()
+    }
+
+    /// Return the number of executions of the given opcode pattern.
+    pub fn opcode_count(&self, pattern: &str) -> u64 {
+        self.histogram.get(pattern).cloned().unwrap_or(0)
+    }
+
+    /// Account for the instruction at 'pc', executed as the cycle number
+    /// 'cycle' - 1.
+    pub(crate) fn record(&mut self, pc: u16, instruction: Instruction, cycle: u64) {
+        self.counts[pc as usize] += 1;
+        *self.histogram.entry(instruction.pattern()).or_insert(0) += 1;
+        match instruction {
+            Instruction::Call(address) => {
+                if self.calls.len() >= MAX_CALL_DEPTH {
+                    self.calls.remove(0);
+                }
+                self.calls.push((address, cycle - 1));
+                self.subroutines.entry(address).or_default().calls += 1;
+            }
+            Instruction::Return => {
+                if let Some((address, start)) = self.calls.pop() {
+                    // a restored save state may go back in time
+                    self.subroutines.entry(address).or_default().cycles += cycle.saturating_sub(start);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Return the name of 'address' : its symbol, the nearest symbol before
+    /// it with an offset, or nothing.
+    fn name(&self, address: u16) -> String {
+        match self.symbols.range(..=address).next_back() {
+            Some((&start, name)) if start == address => name.clone(),
+            Some((&start, name)) => format!("{}+{}", name, address - start),
+            None => String::new(),
+        }
+    }
+
+    /// Return the profile report of the given virtual machine : the most
+    /// executed instructions, the subroutines by cycles and the opcodes
+    /// histogram, each sorted by decreasing cost.
+    pub fn report(&self, vm: &Vm) -> String {
+        let total = vm.run_counter;
+        let percent = |n: u64| if total == 0 { 0.0 } else { n as f64 * 100.0 / total as f64 };
+        let labels: HashMap<u16, String> = self.symbols.iter().map(|(&a, n)| (a, n.clone())).collect();
+        let mut report = format!("Profile of {} instructions\n", total);
+
+        report += "\nHot spots\n     count       %  address  instruction\n";
+        let mut addresses: Vec<u16> = (0..=0xFFFF).filter(|&a| self.counts[a as usize] > 0).collect();
+        addresses.sort_by_key(|&a| (std::cmp::Reverse(self.counts[a as usize]), a));
+        for &address in addresses.iter().take(MAX_HOT_SPOTS) {
+            let count = self.counts[address as usize];
+            let instruction = match decode_at(&vm.memory, address as usize) {
+                Ok(instruction) => instruction.format(Syntax::Mnemonic, &labels),
+                Err(why) => why.to_string(),
+            };
+            report += &format!(
+                "{:>10} {:>6.2}%  {:#06X}   {:<24} {}\n",
+                count,
+                percent(count),
+                address,
+                instruction,
+                self.name(address)
+            );
+        }
+        if addresses.len() > MAX_HOT_SPOTS {
+            report += &format!("       ... {} more addresses\n", addresses.len() - MAX_HOT_SPOTS);
+        }
+
+        report += "\nSubroutines, with their nested calls\n    cycles       %      calls  cycles/call  address  name\n";
+        let mut subroutines: Vec<(&u16, &SubroutineStats)> = self.subroutines.iter().collect();
+        subroutines.sort_by_key(|&(&address, stats)| (std::cmp::Reverse(stats.cycles), address));
+        for (&address, stats) in subroutines {
+            let name = match self.symbols.get(&address) {
+                Some(name) => name.clone(),
+                None => format!("sub_{:04X}", address),
+            };
+            report += &format!(
+                "{:>10} {:>6.2}% {:>10} {:>12.1}  {:#06X}   {}\n",
+                stats.cycles,
+                percent(stats.cycles),
+                stats.calls,
+                stats.cycles as f64 / stats.calls.max(1) as f64,
+                address,
+                name
+            );
+        }
+
+        report += "\nOpcodes\n     count       %  opcode\n";
+        let mut histogram: Vec<(&&str, &u64)> = self.histogram.iter().collect();
+        histogram.sort_by_key(|&(&pattern, &count)| (std::cmp::Reverse(count), pattern));
+        for (pattern, &count) in histogram {
+            report += &format!("{:>10} {:>6.2}%  {}\n", count, percent(count), pattern);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let mut vm = Vm::new();
+        let rom = [
+            0x22, 0x06, // 0x200 : call 0x206
+            0x22, 0x06, // 0x202 : call 0x206
+            0x12, 0x04, // 0x204 : jump 0x204
+            0x70, 0x01, // 0x206 : v0 += 1
+            0x00, 0xEE, // 0x208 : return
+        ];
+        vm.load_rom(&rom).unwrap();
+        let mut profiler = Profiler::new();
+        profiler.symbols = Profiler::parse_symbols("0x0200 main\n0x0206 bump\nbad line\n");
+        vm.profiler = Some(profiler);
+        for _ in 0..10 {
+            vm.step().unwrap();
+        }
+
+        let profiler = vm.profiler.as_ref().unwrap();
+        assert_eq!(profiler.count(0x204), 4);
+        assert_eq!(profiler.count(0x206), 2);
+        assert_eq!(profiler.subroutine(0x206), SubroutineStats { calls: 2, cycles: 6 });
+        assert_eq!(profiler.opcode_count("2NNN"), 2);
+        assert_eq!(profiler.opcode_count("1NNN"), 4);
+
+        let report = profiler.report(&vm);
+        assert!(report.starts_with("Profile of 10 instructions\n"));
+        assert!(report.contains("         4  40.00%  0x0204   JP 0x204                 main+4\n"));
+        assert!(report.contains("         6  60.00%          2          3.0  0x0206   bump\n"));
+        assert!(report.contains("         4  40.00%  1NNN\n"));
+    }
+}
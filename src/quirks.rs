@@ -0,0 +1,114 @@
+/// Behaviour switches for the instructions whose semantics differ between
+/// the historical CHIP 8 interpreters.
+///
+/// A 'Vm' is created with one set of quirks (see 'Vm::with_quirks') ; the
+/// named presets below match the most common platforms ROMs are written for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quirks {
+    /// 8XY6 / 8XYE shift VY and store the result in VX, instead of shifting
+    /// VX in place.
+    pub shift_uses_vy: bool,
+    /// FX55 / FX65 leave I pointing right after the last register
+    /// transferred, instead of leaving it untouched.
+    pub load_store_increments_i: bool,
+    /// BNNN jumps to XNN + VX, instead of NNN + V0.
+    pub jump_with_vx: bool,
+    /// 8XY1 / 8XY2 / 8XY3 reset VF to 0.
+    pub vf_reset: bool,
+    /// DXYN clips the sprites at the screen edges, instead of wrapping them
+    /// around to the other side.
+    pub clip_sprites: bool,
+    /// DXYN waits for the next vertical blank (60 Hz) before the execution
+    /// goes on, limiting the program to one sprite per frame.
+    pub display_wait: bool,
+    /// In high resolution mode, DXYN sets VF to the number of sprite rows
+    /// that collided or were clipped at the bottom, instead of just 0 or 1.
+    pub collision_row_count: bool,
+    /// FX0A waits for a key to be pressed then released, instead of ending
+    /// on the press.
+    pub key_wait_release: bool,
+    /// The program space spans the XO-CHIP 64 KiB of memory, instead of
+    /// ending at 0x1000 : it bounds the size of the ROMs loaded.
+    pub extended_memory: bool,
+}
+
+impl Quirks {
+    /// The original CHIP 8 interpreter of the COSMAC VIP.
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_with_vx: false,
+            vf_reset: true,
+            clip_sprites: true,
+            display_wait: true,
+            collision_row_count: false,
+            key_wait_release: true,
+            extended_memory: false,
+        }
+    }
+
+    /// The CHIP-48 interpreter of the HP-48 calculators.
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_with_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+            collision_row_count: false,
+            key_wait_release: false,
+            extended_memory: false,
+        }
+    }
+
+    /// The SUPER-CHIP 1.1 interpreter, successor of CHIP-48.
+    pub fn superchip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_with_vx: true,
+            vf_reset: false,
+            clip_sprites: true,
+            display_wait: false,
+            collision_row_count: true,
+            key_wait_release: false,
+            extended_memory: false,
+        }
+    }
+
+    /// The XO-CHIP extension, as implemented by Octo.
+    pub fn xochip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_with_vx: false,
+            vf_reset: false,
+            clip_sprites: false,
+            display_wait: false,
+            collision_row_count: false,
+            key_wait_release: true,
+            extended_memory: true,
+        }
+    }
+
+    /// Return the preset matching the given name (case insensitive), if any.
+    /// Recognized names : "VIP", "CHIP48", "SCHIP" and "XOCHIP".
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match &name.to_uppercase()[..] {
+            "VIP" | "CHIP8" => Some(Quirks::cosmac_vip()),
+            "CHIP48" => Some(Quirks::chip48()),
+            "SCHIP" | "SUPERCHIP" => Some(Quirks::superchip()),
+            "XOCHIP" => Some(Quirks::xochip()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    /// The COSMAC VIP behaviour, i.e. the original CHIP 8 semantics.
+    fn default() -> Quirks {
+        Quirks::cosmac_vip()
+    }
+}
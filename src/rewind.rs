@@ -0,0 +1,181 @@
+//! Bounded history of virtual machine snapshots, to rewind the emulation.
+//!
+//! Only the newest snapshot is kept as a complete save state ; every older
+//! one is stored as the difference with its successor : the XOR of both save
+//! states, run-length encoded. Between two snapshots a few frames apart most
+//! of the memory and of the framebuffer don't change, so a snapshot typically
+//! costs a few hundred bytes instead of the ~74 KiB of a save state.
+
+use crate::vm::Vm;
+use std::collections::VecDeque;
+
+/// A snapshot older than the newest one.
+enum Delta {
+    /// Run-length encoded XOR with the next snapshot.
+    Xor(Vec<u8>),
+    /// Complete save state, used when the save state size changed.
+    Full(Vec<u8>),
+}
+
+impl Delta {
+    fn len(&self) -> usize {
+        match *self {
+            Delta::Xor(ref data) | Delta::Full(ref data) => data.len(),
+        }
+    }
+}
+
+/// Append the variable-length encoding of 'n' to 'out'.
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        n |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+/// Encode the XOR of 'a' and 'b', of the same length, as a sequence of
+/// (zero run length, literal length, literal bytes) triplets.
+fn encode_xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < a.len() {
+        let zeros = a[pos..].iter().zip(&b[pos..]).take_while(|(x, y)| x == y).count();
+        pos += zeros;
+        let literals = a[pos..].iter().zip(&b[pos..]).take_while(|(x, y)| x != y).count();
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literals);
+        out.extend(a[pos..pos + literals].iter().zip(&b[pos..pos + literals]).map(|(x, y)| x ^ y));
+        pos += literals;
+    }
+    out
+}
+
+/// Apply in place a difference encoded by 'encode_xor'.
+fn apply_xor(state: &mut [u8], delta: &[u8]) {
+    let (mut pos, mut read) = (0, 0);
+    while read < delta.len() {
+        pos += read_varint(delta, &mut read);
+        let literals = read_varint(delta, &mut read);
+        for (byte, x) in state[pos..pos + literals].iter_mut().zip(&delta[read..read + literals]) {
+            *byte ^= x;
+        }
+        pos += literals;
+        read += literals;
+    }
+}
+
+/// Ring buffer of the last snapshots of a virtual machine.
+pub struct RewindBuffer {
+    /// Maximum number of snapshots kept.
+    capacity: usize,
+    /// Save state of the newest snapshot.
+    newest: Option<Vec<u8>>,
+    /// The older snapshots, the oldest first.
+    deltas: VecDeque<Delta>,
+}
+
+impl RewindBuffer {
+    /// Create an empty buffer keeping at most 'capacity' snapshots.
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            capacity: capacity.max(1),
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Return the number of snapshots in the buffer.
+    pub fn len(&self) -> usize {
+        self.newest.as_ref().map_or(0, |_| 1 + self.deltas.len())
+    }
+
+    /// Return true if the buffer holds no snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Return the approximate memory used by the snapshots, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        let newest = self.newest.as_ref().map_or(0, |state| state.len());
+        newest + self.deltas.iter().map(Delta::len).sum::<usize>()
+    }
+
+    /// Forget all the snapshots.
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+    }
+
+    /// Take a snapshot of the given virtual machine, dropping the oldest
+    /// snapshot if the buffer is full.
+    pub fn push(&mut self, vm: &Vm) {
+        let state = vm.to_state_bytes();
+        if let Some(previous) = self.newest.take() {
+            let delta = if previous.len() == state.len() {
+                Delta::Xor(encode_xor(&previous, &state))
+            } else {
+                Delta::Full(previous)
+            };
+            self.deltas.push_back(delta);
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.newest = Some(state);
+    }
+
+    /// Remove the newest snapshot and return the virtual machine it holds,
+    /// or None if the buffer is empty.
+    pub fn pop(&mut self) -> Option<Vm> {
+        let state = self.newest.take()?;
+        self.newest = self.deltas.pop_back().map(|delta| match delta {
+            Delta::Xor(delta) => {
+                let mut previous = state.clone();
+                apply_xor(&mut previous, &delta);
+                previous
+            }
+            Delta::Full(previous) => previous,
+        });
+        // the snapshots were taken from valid virtual machines
+        Vm::from_state_bytes(&state).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewind() {
+        let mut vm = Vm::new();
+        // loop : V0 += 1 ; jump back
+        vm.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let mut buffer = RewindBuffer::new(3);
+        for _ in 0..5 {
+            buffer.push(&vm);
+            vm.step().unwrap();
+            vm.step().unwrap();
+        }
+        assert_eq!(buffer.len(), 3);
+        assert!(buffer.memory_usage() < vm.to_state_bytes().len() + 200);
+
+        let counters: Vec<u8> = std::iter::from_fn(|| buffer.pop()).map(|vm| vm.v[0]).collect();
+        assert_eq!(counters, vec![4, 3, 2]);
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,46 @@
+/// The random source of the CXNN instruction : a xorshift64* generator,
+/// small enough for its whole state to be saved and restored, so that a
+/// run started from the same seed (or save state) is fully deterministic.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator from the given seed ; any value, 0 included, is
+    /// a valid seed.
+    pub fn new(seed: u64) -> Rng {
+        // splitmix64 finalizer, spreading the seed bits and avoiding the
+        // all-zero state xorshift can't leave
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        Rng::from_state(z)
+    }
+
+    /// Create a generator from a random seed.
+    pub fn from_entropy() -> Rng {
+        Rng::new(rand::random())
+    }
+
+    /// Create a generator from a state returned by 'state'.
+    pub fn from_state(state: u64) -> Rng {
+        Rng {
+            state: if state == 0 { 1 } else { state },
+        }
+    }
+
+    /// Return the internal state of the generator.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    /// Return the next random byte.
+    pub fn next_u8(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+}
@@ -0,0 +1,335 @@
+//! Save states : snapshots of the complete virtual machine state.
+//!
+//! The binary format, all integers being little-endian, is :
+//!
+//! ```text
+//! magic    4 bytes   "C8SS"
+//! version  u16       format version, currently 1
+//! chunks   ...       until the end of the data
+//! ```
+//!
+//! Each chunk is a 4 bytes ASCII tag, followed by the u32 length of its
+//! payload and the payload itself :
+//!
+//! | tag    | payload                                                      |
+//! |--------|--------------------------------------------------------------|
+//! | `CPU ` | i u16, pc u16, v 16 x u8, sp u16, stack 16 x u16,            |
+//! |        | delay timer u8, sound timer u8, waiting for key u8,          |
+//! |        | key register u8, run counter u64, vblank wait u8, exited u8  |
+//! | `MEM ` | the memory content                                           |
+//! | `DISP` | hires u8, selected planes u8, 128 x 64 pixels u8             |
+//! | `KEYS` | 16 x u8 key states, 1 when pressed                           |
+//! | `QRKS` | u32 bitfield, bit N for the Nth field of 'Quirks'            |
+//! | `AUDI` | pitch u8, audio pattern 16 x u8                              |
+//! | `RPL ` | 16 x u8 RPL user flags                                       |
+//! | `ROM ` | ROM hash u64                                                 |
+//! | `RAND` | random generator state u64                                   |
+//! | `KWAI` | key pressed while waiting for a key u8, 0xFF for none,       |
+//! |        | then the key events queued, 2 x u8 each : key, 1 if pressed  |
+//!
+//! Readers skip the chunks they don't know and keep the default value of the
+//! state a missing chunk would hold, so that new chunks can be added without
+//! bumping the version ; the version only changes when the payload of an
+//! existing chunk does.
+
+use crate::display::{HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH};
+use crate::error::VmError;
+use crate::keypad::{KeyEvent, Keystate};
+use crate::quirks::Quirks;
+use crate::rng::Rng;
+use crate::vm::Vm;
+use std::io::{Read, Write};
+
+/// Magic number opening every save state.
+pub const MAGIC: &[u8; 4] = b"C8SS";
+
+/// Version of the save state format written by 'Vm::save_state'.
+pub const VERSION: u16 = 1;
+
+fn invalid(reason: &str) -> VmError {
+    VmError::InvalidSaveState(reason.to_string())
+}
+
+/// Sequential little-endian reader over a chunk payload.
+struct Payload<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Payload<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], VmError> {
+        if self.data.len() < len {
+            return Err(invalid("truncated chunk"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, VmError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, VmError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, VmError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, VmError> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, tag: &[u8; 4], payload: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Return the quirks as a bitfield, in the order of the 'Quirks' fields.
+pub(crate) fn quirks_bits(quirks: &Quirks) -> u32 {
+    [
+        quirks.shift_uses_vy,
+        quirks.load_store_increments_i,
+        quirks.jump_with_vx,
+        quirks.vf_reset,
+        quirks.clip_sprites,
+        quirks.display_wait,
+        quirks.collision_row_count,
+        quirks.key_wait_release,
+        quirks.extended_memory,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (n, &set)| bits | ((set as u32) << n))
+}
+
+pub(crate) fn quirks_from_bits(bits: u32) -> Quirks {
+    let bit = |n: u32| bits & (1 << n) != 0;
+    Quirks {
+        shift_uses_vy: bit(0),
+        load_store_increments_i: bit(1),
+        jump_with_vx: bit(2),
+        vf_reset: bit(3),
+        clip_sprites: bit(4),
+        display_wait: bit(5),
+        collision_row_count: bit(6),
+        key_wait_release: bit(7),
+        extended_memory: bit(8),
+    }
+}
+
+impl Vm {
+    /// Return the save state of the virtual machine, see the module
+    /// documentation for the format.
+    pub fn to_state_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.memory.len() + 0x2400);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+
+        let mut cpu = Vec::with_capacity(64);
+        cpu.extend_from_slice(&self.i.to_le_bytes());
+        cpu.extend_from_slice(&self.pc.to_le_bytes());
+        cpu.extend_from_slice(&self.v);
+        cpu.extend_from_slice(&self.sp.to_le_bytes());
+        for level in self.stack.iter() {
+            cpu.extend_from_slice(&level.to_le_bytes());
+        }
+        cpu.push(self.delay_timer);
+        cpu.push(self.sound_timer);
+        cpu.push(self.wait_for_key.0 as u8);
+        cpu.push(self.wait_for_key.1);
+        cpu.extend_from_slice(&self.run_counter.to_le_bytes());
+        cpu.push(self.vblank_wait as u8);
+        cpu.push(self.exited as u8);
+        write_chunk(&mut out, b"CPU ", &cpu);
+
+        write_chunk(&mut out, b"MEM ", &self.memory);
+
+        let mut disp = Vec::with_capacity(2 + HIRES_DISPLAY_WIDTH * HIRES_DISPLAY_HEIGHT);
+        disp.push(self.display.hires as u8);
+        disp.push(self.display.planes);
+        for row in self.display.gfx.iter() {
+            disp.extend_from_slice(row);
+        }
+        write_chunk(&mut out, b"DISP", &disp);
+
+        let keys: Vec<u8> = (0..16)
+            .map(|k| (self.keypad.get_key_state(k) == Keystate::Pressed) as u8)
+            .collect();
+        write_chunk(&mut out, b"KEYS", &keys);
+
+        write_chunk(&mut out, b"QRKS", &quirks_bits(&self.quirks).to_le_bytes());
+
+        let mut audio = vec![self.pitch];
+        audio.extend_from_slice(&self.audio_pattern);
+        write_chunk(&mut out, b"AUDI", &audio);
+
+        write_chunk(&mut out, b"RPL ", &self.rpl);
+        write_chunk(&mut out, b"ROM ", &self.rom_hash.to_le_bytes());
+        write_chunk(&mut out, b"RAND", &self.rng.state().to_le_bytes());
+
+        let mut wait = vec![self.wait_for_key_pressed.unwrap_or(0xFF)];
+        for event in self.keypad.events() {
+            wait.push(event.key as u8);
+            wait.push((event.state == Keystate::Pressed) as u8);
+        }
+        write_chunk(&mut out, b"KWAI", &wait);
+
+        out
+    }
+
+    /// Write the save state of the virtual machine to the given writer.
+    pub fn save_state<W: Write>(&self, writer: &mut W) -> Result<(), VmError> {
+        writer.write_all(&self.to_state_bytes())?;
+        Ok(())
+    }
+
+    /// Create a virtual machine from the given save state.
+    pub fn from_state_bytes(data: &[u8]) -> Result<Vm, VmError> {
+        let mut input = Payload { data };
+        if input.bytes(4).map_err(|_| invalid("missing header"))? != MAGIC {
+            return Err(invalid("not a CHIP 8 save state"));
+        }
+        let version = input.u16().map_err(|_| invalid("missing header"))?;
+        if version > VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+
+        let mut vm = Vm::new();
+        while !input.data.is_empty() {
+            let tag = input.bytes(4)?;
+            let len = input.u32()? as usize;
+            let mut chunk = Payload { data: input.bytes(len)? };
+            match tag {
+                b"CPU " => {
+                    vm.i = chunk.u16()?;
+                    vm.pc = chunk.u16()?;
+                    vm.v.copy_from_slice(chunk.bytes(16)?);
+                    vm.sp = chunk.u16()?;
+                    for level in vm.stack.iter_mut() {
+                        *level = chunk.u16()?;
+                    }
+                    vm.delay_timer = chunk.u8()?;
+                    vm.sound_timer = chunk.u8()?;
+                    vm.wait_for_key = (chunk.u8()? != 0, chunk.u8()? & 0xF);
+                    vm.run_counter = chunk.u64()?;
+                    vm.vblank_wait = chunk.u8()? != 0;
+                    vm.exited = chunk.u8()? != 0;
+                    if vm.sp as usize > vm.stack.len() {
+                        return Err(invalid("stack pointer out of bounds"));
+                    }
+                }
+                b"MEM " => vm.memory = chunk.data.to_vec(),
+                b"DISP" => {
+                    vm.display.hires = chunk.u8()? != 0;
+                    vm.display.select_planes(chunk.u8()?);
+                    for row in vm.display.gfx.iter_mut() {
+                        row.copy_from_slice(chunk.bytes(HIRES_DISPLAY_WIDTH)?);
+                    }
+                    vm.display.dirty = true;
+                }
+                b"KEYS" => {
+                    for (k, &pressed) in chunk.bytes(16)?.iter().enumerate() {
+                        let state = if pressed != 0 { Keystate::Pressed } else { Keystate::Released };
+                        vm.keypad.set_key_state(k, state);
+                    }
+                }
+                b"QRKS" => vm.quirks = quirks_from_bits(chunk.u32()?),
+                b"AUDI" => {
+                    vm.pitch = chunk.u8()?;
+                    vm.audio_pattern.copy_from_slice(chunk.bytes(16)?);
+                }
+                b"RPL " => vm.rpl.copy_from_slice(chunk.bytes(16)?),
+                b"ROM " => vm.rom_hash = chunk.u64()?,
+                b"RAND" => vm.rng = Rng::from_state(chunk.u64()?),
+                b"KWAI" => {
+                    vm.wait_for_key_pressed = Some(chunk.u8()?).filter(|&key| key < 16);
+                    while !chunk.data.is_empty() {
+                        let key = (chunk.u8()? & 0xF) as usize;
+                        let state = if chunk.u8()? != 0 { Keystate::Pressed } else { Keystate::Released };
+                        vm.keypad.queue_event(KeyEvent { key, state });
+                    }
+                }
+                // chunk written by a newer version
+                _ => {}
+            }
+        }
+        if vm.memory.len() < 0x200 {
+            return Err(invalid("memory too small"));
+        }
+
+        Ok(vm)
+    }
+
+    /// Create a virtual machine from the save state read from the given reader.
+    pub fn load_state<R: Read>(reader: &mut R) -> Result<Vm, VmError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Vm::from_state_bytes(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut vm = Vm::with_quirks(Quirks::superchip());
+        vm.load_rom(&[0x60, 0x2A, 0xA2, 0x10, 0x00, 0xFF, 0xD0, 0x10]).unwrap();
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+        vm.delay_timer = 12;
+        vm.keypad.set_key_state(0xA, Keystate::Pressed);
+
+        let mut state = Vec::new();
+        vm.save_state(&mut state).unwrap();
+        let restored = Vm::load_state(&mut &state[..]).unwrap();
+        assert_eq!(restored.to_state_bytes(), state);
+        assert_eq!(restored.v[0], 0x2A);
+        assert!(restored.display.hires);
+        assert_eq!(restored.quirks, Quirks::superchip());
+        assert_eq!(restored.keypad.get_key_state(0xA), Keystate::Pressed);
+    }
+
+    #[test]
+    fn test_save_state_while_waiting_for_key() {
+        // V3 := key ; exit
+        let mut vm = Vm::new();
+        vm.load_rom(&[0xF3, 0x0A, 0x00, 0xFD]).unwrap();
+        vm.step().unwrap();
+        vm.keypad.key_event(5, Keystate::Pressed);
+        vm.poll_key_wait();
+        vm.keypad.key_event(9, Keystate::Pressed);
+        vm.keypad.key_event(5, Keystate::Released);
+        assert!(vm.is_waiting_for_key());
+
+        let state = vm.to_state_bytes();
+        let mut restored = Vm::from_state_bytes(&state).unwrap();
+        assert_eq!(restored.to_state_bytes(), state);
+        assert_eq!(restored.wait_for_key_pressed, Some(5));
+        // the release of the key pressed first ends the wait
+        restored.poll_key_wait();
+        assert!(!restored.is_waiting_for_key());
+        assert_eq!(restored.v[3], 5);
+        assert_eq!(restored.pc, 0x202);
+    }
+
+    #[test]
+    fn test_unknown_chunks_are_skipped() {
+        let vm = Vm::new();
+        let mut state = vm.to_state_bytes();
+        write_chunk(&mut state, b"NEW!", &[1, 2, 3]);
+        assert!(Vm::from_state_bytes(&state).is_ok());
+        assert!(Vm::from_state_bytes(b"C8SS\x09\x00").is_err());
+        assert!(Vm::from_state_bytes(b"nope").is_err());
+    }
+}
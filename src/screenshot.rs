@@ -0,0 +1,193 @@
+//! Encoding of the framebuffer content, without any dependency : binary
+//! PBM and indexed PNG images, text art and a hash, e.g. to compare the
+//! screen of a headless run with a known good one.
+//!
+//! Only the pixels of the current resolution are encoded.
+
+use crate::display::Display;
+use crate::vm::rom_hash;
+
+/// The characters drawing the pixels, indexed by the XO-CHIP bitplanes they
+/// are set in : none, the first, the second and both.
+const ASCII_PIXELS: [char; 4] = ['.', '#', '+', '@'];
+
+/// The text rendering of the screen.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextStyle {
+    /// One character per pixel, see 'ASCII_PIXELS'.
+    Ascii,
+    /// Unicode half blocks, one character per 2 rows of pixels ; the
+    /// bitplanes aren't told apart.
+    Unicode,
+}
+
+/// Return the pixels of the current resolution, row by row.
+pub fn pixels(display: &Display) -> Vec<u8> {
+    let (w, h) = (display.width(), display.height());
+    display.gfx[..h].iter().flat_map(|row| row[..w].iter().cloned()).collect()
+}
+
+/// Return the hash of the pixels of the current resolution, the resolution
+/// included.
+pub fn hash(display: &Display) -> u64 {
+    let mut data = vec![display.width() as u8, display.height() as u8];
+    data.extend(pixels(display));
+    rom_hash(&data)
+}
+
+/// Return the screen as a binary PBM image, any lit pixel being black.
+pub fn to_pbm(display: &Display) -> Vec<u8> {
+    let (w, h) = (display.width(), display.height());
+    let mut image = format!("P4\n{} {}\n", w, h).into_bytes();
+    for row in display.gfx[..h].iter() {
+        for byte in row[..w].chunks(8) {
+            let bits = byte.iter().enumerate().fold(0u8, |bits, (i, &pixel)| {
+                if pixel != 0 { bits | (0x80 >> i) } else { bits }
+            });
+            image.push(bits);
+        }
+    }
+    image
+}
+
+/// Return the screen as an indexed color PNG image, with the given palette
+/// of 0xRRGGBB colors.
+pub fn to_png(display: &Display, palette: &[u32; 4]) -> Vec<u8> {
+    let (w, h) = (display.width(), display.height());
+    let mut image = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    // 8 bits per pixel, palette color type, no interlacing
+    let mut header = Vec::new();
+    header.extend_from_slice(&(w as u32).to_be_bytes());
+    header.extend_from_slice(&(h as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 3, 0, 0, 0]);
+    write_chunk(&mut image, b"IHDR", &header);
+
+    let colors: Vec<u8> = palette.iter().flat_map(|&color| color.to_be_bytes()[1..].to_vec()).collect();
+    write_chunk(&mut image, b"PLTE", &colors);
+
+    // every row starts with its filter type, none
+    let mut scanlines = Vec::with_capacity((w + 1) * h);
+    for row in display.gfx[..h].iter() {
+        scanlines.push(0);
+        scanlines.extend_from_slice(&row[..w]);
+    }
+    write_chunk(&mut image, b"IDAT", &zlib_stored(&scanlines));
+    write_chunk(&mut image, b"IEND", &[]);
+    image
+}
+
+/// Return the screen as text lines.
+pub fn to_text(display: &Display, style: TextStyle) -> String {
+    let (w, h) = (display.width(), display.height());
+    let mut text = String::new();
+    match style {
+        TextStyle::Ascii => {
+            for row in display.gfx[..h].iter() {
+                text.extend(row[..w].iter().map(|&pixel| ASCII_PIXELS[pixel as usize & 0b11]));
+                text.push('\n');
+            }
+        }
+        TextStyle::Unicode => {
+            for rows in display.gfx[..h].chunks(2) {
+                for x in 0..w {
+                    let top = rows[0][x] != 0;
+                    let bottom = rows.len() > 1 && rows[1][x] != 0;
+                    text.push(match (top, bottom) {
+                        (false, false) => ' ',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (true, true) => '█',
+                    });
+                }
+                text.push('\n');
+            }
+        }
+    }
+    text
+}
+
+/// Append a PNG chunk to 'image'.
+fn write_chunk(image: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = image.len();
+    image.extend_from_slice(kind);
+    image.extend_from_slice(data);
+    let crc = crc32(&image[start..]);
+    image.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Return the zlib stream of 'data' in uncompressed blocks ; the screen
+/// being at most 8 KiB, compressing it isn't worth the trouble.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        stream.push(last as u8);
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+/// The CRC-32 checksum of the PNG chunks.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// The Adler-32 checksum of the zlib streams.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut display = Display::new();
+        display.draw(0, 0, &[0xC0, 0x40], 8, true);
+
+        let text = to_text(&display, TextStyle::Ascii);
+        assert_eq!(text.lines().count(), 32);
+        assert!(text.starts_with(&format!("##{}\n.#{}\n", ".".repeat(62), ".".repeat(62))));
+        let text = to_text(&display, TextStyle::Unicode);
+        assert_eq!(text.lines().count(), 16);
+        assert!(text.starts_with(&format!("▀█{}\n", " ".repeat(62))));
+
+        let pbm = to_pbm(&display);
+        assert!(pbm.starts_with(b"P4\n64 32\n\xC0\x00"));
+        assert_eq!(pbm.len(), 9 + 8 * 32);
+
+        let png = to_png(&display, &[0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555]);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]));
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+        let before = hash(&display);
+        display.set_hires(true);
+        assert_ne!(hash(&display), before);
+        assert_eq!(pixels(&display).len(), 128 * 64);
+    }
+}
@@ -0,0 +1,311 @@
+//! Execution trace : one record per instruction executed by 'Vm::step',
+//! written to a file when a 'Tracer' is set on the virtual machine.
+//!
+//! The text format has one line per instruction, with 4 fields separated
+//! by '|' : the cycle, the address and the opcode ; the disassembly ; the
+//! registers changed, e.g. 'V3:00->10 I:0200->0300' ; the memory written,
+//! e.g. '[0300]=01 03 07'.
+//!
+//! The binary format starts with the 'BINARY_MAGIC' header, then each
+//! record is, little-endian : the cycle (u64), the address (u16), the number
+//! of opcode words (u8) and the words (u16 each), the number of registers
+//! changed (u8) and for each of them its index in 'TRACED_REGISTERS' (u8)
+//! and its new value (u16), the address (u16) and the number (u16) of the
+//! bytes written, then these bytes.
+
+use crate::debugger::Operand;
+use crate::disasm::Instruction;
+use crate::vm::Vm;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+
+/// The header of the binary traces, the last byte being the version.
+pub const BINARY_MAGIC: [u8; 5] = *b"C8TR\x01";
+
+/// The registers whose changes are traced ; the program counter changes at
+/// every instruction and isn't part of them.
+pub const TRACED_REGISTERS: [Operand; 20] = [
+    Operand::V(0x0), Operand::V(0x1), Operand::V(0x2), Operand::V(0x3),
+    Operand::V(0x4), Operand::V(0x5), Operand::V(0x6), Operand::V(0x7),
+    Operand::V(0x8), Operand::V(0x9), Operand::V(0xA), Operand::V(0xB),
+    Operand::V(0xC), Operand::V(0xD), Operand::V(0xE), Operand::V(0xF),
+    Operand::I, Operand::Sp, Operand::DelayTimer, Operand::SoundTimer,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+impl TraceFormat {
+    /// Return the format of the given name (case insensitive) : "text" or
+    /// "binary".
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match &name.to_lowercase()[..] {
+            "text" => Some(TraceFormat::Text),
+            "binary" => Some(TraceFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+/// The trace of one executed instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// The number of instructions executed before this one.
+    pub cycle: u64,
+    pub pc: u16,
+    pub instruction: Instruction,
+    /// The registers changed, with their old and new values.
+    pub changes: Vec<(Operand, u16, u16)>,
+    /// The address of the memory written, and the bytes written there.
+    pub written: (u16, Vec<u8>),
+}
+
+/// The state of the virtual machine before an instruction, turned into a
+/// 'Record' once the instruction is executed.
+pub(crate) struct Snapshot {
+    cycle: u64,
+    pc: u16,
+    instruction: Instruction,
+    registers: [u16; 20],
+    written: Range<usize>,
+}
+
+impl Snapshot {
+    pub(crate) fn take(vm: &Vm, cycle: u64, instruction: Instruction) -> Snapshot {
+        let mut registers = [0; 20];
+        for (value, register) in registers.iter_mut().zip(TRACED_REGISTERS.iter()) {
+            *value = register.value(vm);
+        }
+        Snapshot {
+            cycle,
+            pc: vm.pc,
+            instruction,
+            registers,
+            written: vm.memory_access(instruction).1,
+        }
+    }
+
+    pub(crate) fn record(self, vm: &Vm) -> Record {
+        let changes = TRACED_REGISTERS
+            .iter()
+            .zip(self.registers.iter())
+            .map(|(&register, &old)| (register, old, register.value(vm)))
+            .filter(|&(_, old, new)| old != new)
+            .collect();
+        Record {
+            cycle: self.cycle,
+            pc: self.pc,
+            instruction: self.instruction,
+            changes,
+            written: (self.written.start as u16, vm.memory[self.written].to_vec()),
+        }
+    }
+}
+
+/// Return the trace name of a register, e.g. "V3" or "DT".
+fn register_name(register: Operand) -> String {
+    match register {
+        Operand::V(x) => format!("V{:X}", x),
+        Operand::I => "I".to_string(),
+        Operand::Pc => "PC".to_string(),
+        Operand::Sp => "SP".to_string(),
+        Operand::DelayTimer => "DT".to_string(),
+        Operand::SoundTimer => "ST".to_string(),
+    }
+}
+
+impl Record {
+    /// Return the record as a line of the text format.
+    pub fn to_text(&self) -> String {
+        let opcode: Vec<String> = self.instruction.encode().iter().map(|word| format!("{:04X}", word)).collect();
+        let changes: Vec<String> = self
+            .changes
+            .iter()
+            .map(|&(register, old, new)| match register {
+                Operand::V(_) | Operand::DelayTimer | Operand::SoundTimer => {
+                    format!("{}:{:02X}->{:02X}", register_name(register), old, new)
+                }
+                _ => format!("{}:{:04X}->{:04X}", register_name(register), old, new),
+            })
+            .collect();
+        let written = if self.written.1.is_empty() {
+            String::new()
+        } else {
+            let bytes: Vec<String> = self.written.1.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("[{:04X}]={}", self.written.0, bytes.join(" "))
+        };
+        format!(
+            "{} {:04X} {} | {} | {} | {}\n",
+            self.cycle,
+            self.pc,
+            opcode.join(" "),
+            self.instruction,
+            changes.join(" "),
+            written
+        )
+    }
+
+    /// Return the record in the binary format.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.cycle.to_le_bytes());
+        data.extend_from_slice(&self.pc.to_le_bytes());
+        let words = self.instruction.encode();
+        data.push(words.len() as u8);
+        for word in words {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.push(self.changes.len() as u8);
+        for &(register, _, new) in &self.changes {
+            let index = TRACED_REGISTERS.iter().position(|&r| r == register).unwrap_or(0xFF);
+            data.push(index as u8);
+            data.extend_from_slice(&new.to_le_bytes());
+        }
+        data.extend_from_slice(&self.written.0.to_le_bytes());
+        data.extend_from_slice(&(self.written.1.len() as u16).to_le_bytes());
+        data.extend_from_slice(&self.written.1);
+        data
+    }
+}
+
+/// Writer of the execution trace, set in 'Vm::tracer'. By default every
+/// instruction is traced, without any size limit.
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    /// Only the instructions at these addresses are traced.
+    pub range: RangeInclusive<u16>,
+    /// The maximum size of the trace in bytes, if any.
+    pub max_size: Option<u64>,
+    /// The number of bytes written so far.
+    size: u64,
+    /// Set when the trace is full, or couldn't be written.
+    stopped: bool,
+}
+
+impl Tracer {
+    /// Create a tracer writing to 'out' in the given format.
+    pub fn new(mut out: Box<dyn Write + Send>, format: TraceFormat) -> io::Result<Tracer> {
+        let mut size = 0;
+        if format == TraceFormat::Binary {
+            out.write_all(&BINARY_MAGIC)?;
+            size = BINARY_MAGIC.len() as u64;
+        }
+        Ok(Tracer {
+            out,
+            format,
+            range: 0..=0xFFFF,
+            max_size: None,
+            size,
+            stopped: false,
+        })
+    }
+
+    /// Create a tracer writing to the given file.
+    pub fn create(path: &Path, format: TraceFormat) -> io::Result<Tracer> {
+        Tracer::new(Box::new(BufWriter::new(File::create(path)?)), format)
+    }
+
+    /// Return whether the instruction at the given address is traced.
+    pub fn traces(&self, pc: u16) -> bool {
+        !self.stopped && self.range.contains(&pc)
+    }
+
+    /// Write the record of an instruction. The trace stops, with a warning,
+    /// once it reaches its maximum size or if it can't be written.
+    pub fn write(&mut self, record: &Record) {
+        if self.stopped {
+            return;
+        }
+        let data = match self.format {
+            TraceFormat::Text => record.to_text().into_bytes(),
+            TraceFormat::Binary => record.to_binary(),
+        };
+        if self.max_size.is_some_and(|max| self.size + data.len() as u64 > max) {
+            warn!("the trace reached its maximum size of {} bytes, stopping it.", self.size);
+            self.stopped = true;
+            return;
+        }
+        if let Err(why) = self.out.write_all(&data) {
+            warn!("couldn't write the trace, stopping it : {}", why);
+            self.stopped = true;
+            return;
+        }
+        self.size += data.len() as u64;
+    }
+
+    /// Flush the buffered records.
+    pub fn flush(&mut self) {
+        if let Err(why) = self.out.flush() {
+            warn!("couldn't write the trace : {}", why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A trace output readable once the tracer is set on the virtual machine.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run 'v3 := 0x10 ; i := 0x300 ; save v3 ; jump 0x206' with the given
+    /// tracer, for 5 instructions.
+    fn run(tracer: Tracer) {
+        let mut vm = Vm::new();
+        vm.load_rom(&[0x63, 0x10, 0xA3, 0x00, 0xF3, 0x55, 0x12, 0x06]).unwrap();
+        vm.tracer = Some(tracer);
+        for _ in 0..5 {
+            vm.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()), TraceFormat::Text).unwrap();
+        tracer.range = 0x202..=0x206;
+        run(tracer);
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "1 0202 A300 | LD I, 0x300 | I:0000->0300 | ");
+        assert_eq!(lines[1], "2 0204 F355 | LD [I], V3 | I:0300->0304 | [0300]=00 00 00 10");
+        assert_eq!(lines[2], "3 0206 1206 | JP 0x206 |  | ");
+
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()), TraceFormat::Text).unwrap();
+        tracer.max_size = Some(100);
+        run(tracer);
+        assert_eq!(out.0.lock().unwrap().iter().filter(|&&c| c == b'\n').count(), 2);
+    }
+
+    #[test]
+    fn test_binary_trace() {
+        let out = Shared::default();
+        let mut tracer = Tracer::new(Box::new(out.clone()), TraceFormat::Binary).unwrap();
+        tracer.range = 0x200..=0x200;
+        run(tracer);
+        let data = out.0.lock().unwrap().clone();
+        let mut expected = BINARY_MAGIC.to_vec();
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x02, 1, 0x10, 0x63, 1, 3, 0x10, 0x00]);
+        expected.extend_from_slice(&[0x00, 0x00, 0, 0]);
+        assert_eq!(data, expected);
+    }
+}
@@ -1,11 +1,17 @@
-use crate::display::{Display, FONT_SET};
-use std::sync::Mutex;
-use rand::Rng;
+use crate::audio::{DEFAULT_PATTERN, PATTERN_SIZE};
+use crate::disasm;
+pub use crate::disasm::{decode, DecodeError, Instruction};
+use crate::display::{Display, BIG_FONT_SET, FONT_SET};
+use crate::error::VmError;
 use crate::keypad::{Keypad, Keystate};
-use std::fs::File;
-use std::io::Read;
+use crate::profiler::Profiler;
+use crate::quirks::Quirks;
+use crate::rng::Rng;
+use crate::trace::{Snapshot, Tracer};
+use std::fs;
+use std::io;
+use std::ops::Range;
 use std::path::Path;
-use std::error::Error;
 
 pub struct Vm {
     // register index
@@ -14,8 +20,8 @@ pub struct Vm {
     // program counter
     pub pc: u16,
 
-    // memory
-    pub memory: [u8; 4096],
+    // memory, the XO-CHIP 64 KiB address space
+    pub memory: Vec<u8>,
 
     //registers, v0 - vf
     pub v: [u8; 16],
@@ -40,51 +46,135 @@ pub struct Vm {
 
     pub wait_for_key: (bool, u8),
 
-    run_counter: u64,
-}
+    // key pressed while waiting for a key, whose release ends the wait with
+    // the key_wait_release quirk
+    pub(crate) wait_for_key_pressed: Option<u8>,
 
-fn read_word(memory: [u8; 4096], index: u16) -> u16 {
-    (memory[index as usize] as u16) << 8
-        | (memory[(index + 1) as usize] as u16)
+    // SUPER-CHIP RPL user flags, saved and restored by FX75 / FX85
+    pub rpl: [u8; 16],
+
+    // hash of the loaded ROM, identifies the program (see 'rom_hash')
+    pub rom_hash: u64,
+
+    // the loaded ROM, reloaded by 'reset' ; not part of the save states
+    pub rom: Vec<u8>,
+
+    // XO-CHIP audio pitch, set by FX3A
+    pub pitch: u8,
+
+    // XO-CHIP 1 bit audio pattern buffer, loaded by F002
+    pub audio_pattern: [u8; PATTERN_SIZE],
+
+    // behaviour of the ambiguous instructions
+    pub quirks: Quirks,
+
+    // random source of CXNN, randomly seeded unless replaced
+    pub rng: Rng,
+
+    // set by DXYN when the display wait quirk is on, until the next vblank
+    pub(crate) vblank_wait: bool,
+
+    // set by 00FD (SUPER-CHIP exit)
+    pub(crate) exited: bool,
+
+    pub(crate) run_counter: u64,
+
+    // execution trace, off unless set
+    pub tracer: Option<Tracer>,
+
+    // execution profile, off unless set
+    pub profiler: Option<Profiler>,
 }
 
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
 
-macro_rules! arg_x {
-    ($opcode:expr) => {
-        (($opcode & 0x0F00) >> 8) as usize
-    };
+/// The result of a successful 'Vm::run_frame', or the progress of a frame
+/// run in parts by 'Vm::run_frame_with'.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FrameReport {
+    /// The number of instructions executed.
+    pub instructions: u32,
+    /// The number of CPU cycles run, counting the ones spent waiting.
+    pub cycles: u32,
+    /// The sound timer was active during the frame : the buzzer should
+    /// sound for it.
+    pub sound: bool,
+    /// The display changed during the frame.
+    pub display_changed: bool,
+    /// The program waits for a key press (FX0A).
+    pub waiting_for_key: bool,
+    /// The program is finished, the frame wasn't ended.
+    pub finished: bool,
+    /// All the cycles of the frame ran and it was ended.
+    pub ended: bool,
 }
 
-macro_rules! arg_y {
-    ($opcode:expr) => {
-        (($opcode & 0x00F0) >> 4) as usize
-    };
+/// The result of a successful 'Vm::step'.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StepOutcome {
+    /// An instruction was executed.
+    Executed,
+    /// No instruction was executed : the program waits for a key press
+    /// (FX0A) or for the next vertical blank (display wait quirk).
+    Waiting,
+    /// The program is finished, either by 00FD or by reaching the end of
+    /// the memory.
+    Finished,
 }
 
-macro_rules! arg_nnn {
-    ($opcode:expr) => {
-        $opcode & 0x0FFF
-    };
+/// Return the 64 bits FNV-1a hash of the given ROM content.
+/// Used to identify a program, e.g. to key its persistent RPL flags.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
 }
 
-macro_rules! arg_nn {
-    ($opcode:expr) => {
-        ($opcode & 0x00FF) as u8
-    };
+/// Size of the addressable memory : the XO-CHIP 64 KiB address space, which
+/// includes the 4 KiB of the original CHIP 8.
+pub const MEMORY_SIZE: usize = 0x10000;
+
+/// End of the original CHIP 8 4 KiB memory, see the 'extended_memory'
+/// quirk.
+const PROGRAM_END: usize = 0x1000;
+
+/// Address of the SUPER-CHIP big font in memory, right after the small one.
+const BIG_FONT_START: usize = 0x50;
+
+/// Default XO-CHIP audio pitch, i.e. a 4000 Hz playback rate.
+pub const DEFAULT_PITCH: u8 = 64;
+
+/// Return the registers VX to VY of the XO-CHIP 5XY2 / 5XY3 instructions,
+/// in descending order if X > Y.
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
+    }
 }
 
-macro_rules! arg_n {
-    ($opcode:expr) => {
-        ($opcode & 0x000F) as u8
-    };
+fn read_word(memory: &[u8], index: u16) -> u16 {
+    (memory[index as usize] as u16) << 8
+        | (memory[(index as usize + 1) % memory.len()] as u16)
 }
 
+
 impl Vm {
     pub fn new() -> Vm {
+        Vm::with_quirks(Quirks::default())
+    }
+
+    /// Create a virtual machine emulating the given set of quirks.
+    pub fn with_quirks(quirks: Quirks) -> Vm {
         let mut vm = Vm {
             i: 0,
             pc: 0,
-            memory: [0; 4096],
+            memory: vec![0; MEMORY_SIZE],
             v: [0; 16],
             display: Display::new(),
             keypad: Keypad::new(),
@@ -93,273 +183,532 @@ impl Vm {
             delay_timer: 0,
             sound_timer: 0,
             wait_for_key: (false, 0),
+            wait_for_key_pressed: None,
+            rpl: [0; 16],
+            rom_hash: rom_hash(&[]),
+            rom: Vec::new(),
+            pitch: DEFAULT_PITCH,
+            audio_pattern: DEFAULT_PATTERN,
+            quirks,
+            rng: Rng::from_entropy(),
+            vblank_wait: false,
+            exited: false,
             run_counter: 0,
+            tracer: None,
+            profiler: None,
         };
 
-        for i in 0..80 {
-            vm.memory[i] = FONT_SET[i];
-        }
+        vm.load_fonts();
         // the program space starts at 0x200
         vm.pc = 0x200;
 
         vm
     }
 
-    pub fn is_waiting_for_key(&self) -> bool {
-        self.wait_for_key.0
+    fn load_fonts(&mut self) {
+        self.memory[..FONT_SET.len()].copy_from_slice(&FONT_SET);
+        self.memory[BIG_FONT_START..BIG_FONT_START + BIG_FONT_SET.len()].copy_from_slice(&BIG_FONT_SET);
     }
 
-    pub fn debug_info(&self, opt: u16) {
-        println!("run counter: {}, opt: {:X?}", self.run_counter, opt);
-        println!("register: {:?}", self.v);
-        println!("pc: {:?}", self.pc);
-        println!("stack: {:?}\n\n\n", self.stack);
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.wait_for_key.0
     }
 
-    pub fn end_wait_for_key(&mut self, key_index: usize) {
+    /// If the program waits for a key (FX0A), consume the key events queued
+    /// since the wait started and end it on the first key press, or on the
+    /// release of the first key pressed with the key_wait_release quirk.
+    /// Called by 'step'.
+    pub fn poll_key_wait(&mut self) {
         if !self.is_waiting_for_key() {
-            warn!(concat!(
-            "Chip8::end_wait_for_key_press called but the VM ",
-            "wasn't waiting for a key press - ignoring"
-            ));
             return;
         }
-        self.v[self.wait_for_key.1 as usize] = key_index as u8;
-        self.wait_for_key.0 = false;
-        self.pc += 2;
+        while let Some(event) = self.keypad.pop_event() {
+            let key = event.key as u8;
+            match event.state {
+                Keystate::Pressed if !self.quirks.key_wait_release => return self.end_wait_for_key(key),
+                Keystate::Pressed if self.wait_for_key_pressed.is_none() => self.wait_for_key_pressed = Some(key),
+                Keystate::Released if self.wait_for_key_pressed == Some(key) => return self.end_wait_for_key(key),
+                _ => {}
+            }
+        }
     }
 
-    pub fn load(&mut self, filepath: &Path) -> Option<String> {
-        let file = match File::open(filepath) {
-            Ok(f) => f,
-            Err(ref why) => {
-                return Some(format!(
-                    "couldn't open rom file \"{}\" : {}",
-                    filepath.display(),
-                    Error::description(why)
-                ));
-            }
-        };
-        for (i, b) in file.bytes().enumerate() {
-            //if b.is_none() /* EOF */ { break; }
-            match b {
-                Ok(byte) => self.memory[self.pc as usize + i] = byte,
-                Err(e) => {
-                    return Some(format!("error while reading ROM : {}", e.to_string()));
-                }
-            }
+    fn end_wait_for_key(&mut self, key: u8) {
+        self.v[self.wait_for_key.1 as usize] = key;
+        self.wait_for_key = (false, 0);
+        self.wait_for_key_pressed = None;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    /// Load the ROM file at the given path in the program space.
+    pub fn load(&mut self, filepath: &Path) -> Result<(), VmError> {
+        let rom = fs::read(filepath)?;
+        self.load_rom(&rom)
+    }
+
+    /// Load the given ROM content in the program space.
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), VmError> {
+        let start = self.pc as usize;
+        let max = self.memory_end().saturating_sub(start);
+        if rom.len() > max {
+            return Err(VmError::RomTooLarge { size: rom.len(), max });
+        }
+        self.memory[start..start + rom.len()].copy_from_slice(rom);
+        self.rom_hash = rom_hash(rom);
+        self.rom = rom.to_vec();
+        Ok(())
+    }
+
+    /// Load the RPL user flags from the given file, as written by
+    /// 'save_rpl_flags'. A file shorter than 16 bytes only sets the first flags.
+    pub fn load_rpl_flags(&mut self, filepath: &Path) -> io::Result<()> {
+        let bytes = fs::read(filepath)?;
+        let len = bytes.len().min(self.rpl.len());
+        self.rpl[..len].copy_from_slice(&bytes[..len]);
+        Ok(())
+    }
+
+    /// Save the RPL user flags to the given file, creating its parent
+    /// directory if needed.
+    pub fn save_rpl_flags(&self, filepath: &Path) -> io::Result<()> {
+        if let Some(parent) = filepath.parent() {
+            fs::create_dir_all(parent)?;
         }
-        None
+        fs::write(filepath, self.rpl)
     }
 
+    /// Restart the loaded ROM from scratch, e.g. to recover from a crash.
     pub fn reset(&mut self) {
         self.i = 0;
         self.pc = 0x200;
-        self.memory = [0; 4096];
+        self.memory = vec![0; MEMORY_SIZE];
+        self.pitch = DEFAULT_PITCH;
+        self.audio_pattern = DEFAULT_PATTERN;
         self.v = [0; 16];
         self.stack = [0; 16];
         self.sp = 0;
         self.delay_timer = 0;
-        self.display.clear();
-        for i in 0..80 {
-            self.memory[i] = FONT_SET[i];
-        }
+        self.sound_timer = 0;
+        self.wait_for_key = (false, 0);
+        self.wait_for_key_pressed = None;
+        self.keypad.clear_events();
+        self.vblank_wait = false;
+        self.exited = false;
+        self.display = Display::new();
+        self.load_fonts();
+        self.memory[0x200..0x200 + self.rom.len()].copy_from_slice(&self.rom);
     }
 
-    pub fn emulate_cycle(&mut self) -> bool {
+    /// Execute the next instruction, if the program isn't waiting or
+    /// finished. On error the virtual machine is left as it was when the
+    /// faulty instruction started.
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
         // Is the program finished ?
-        if self.pc >= 4094 {
-            return true;
+        if self.exited || self.pc as usize + 2 > self.memory.len() {
+            return Ok(StepOutcome::Finished);
+        }
+        // Waiting for a key press, or the vertical blank after a draw ?
+        self.poll_key_wait();
+        if self.is_waiting_for_key() || self.vblank_wait {
+            return Ok(StepOutcome::Waiting);
         }
-        // Fetch and execute the opcode to execute ;
-        // an opcode being 2 bytes long, we need to read 2 bytes from memory
-        let op = read_word(self.memory, self.pc);
+        // Fetch, decode and execute the instruction
+        let instruction = self.fetch()?;
 
+        let pc = self.pc;
+        let snapshot = match self.tracer {
+            Some(ref tracer) if tracer.traces(pc) => Some(Snapshot::take(self, self.run_counter, instruction)),
+            _ => None,
+        };
+        if let Err(why) = self.execute(instruction) {
+            self.pc = pc;
+            return Err(why);
+        }
         self.run_counter += 1;
-        self.process_opcode(op);
-        self.debug_info(op);
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(pc, instruction, self.run_counter);
+        }
+        if let Some(snapshot) = snapshot {
+            let record = snapshot.record(self);
+            if let Some(ref mut tracer) = self.tracer {
+                tracer.write(&record);
+            }
+        }
 
-        false
+        Ok(StepOutcome::Executed)
     }
 
-    pub fn run(&mut self) {
-        let opcode = read_word(self.memory, self.pc);
-        self.process_opcode(opcode);
+    /// Signal the start of a new 60 Hz frame to the virtual machine.
+    pub fn vblank(&mut self) {
+        self.vblank_wait = false;
     }
 
+    /// Decrement the delay and sound timers, at 60 Hz.
     pub fn decrement_timers(&mut self) {
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
+    }
+
+    /// End the current 60 Hz frame : signal the vertical blank and tick
+    /// both timers.
+    pub fn end_frame(&mut self) {
+        self.vblank();
+        self.decrement_timers();
+    }
+
+    /// Run a 60 Hz frame : execute up to 'instructions_per_frame'
+    /// instructions, fewer if the program starts waiting or finishes, then
+    /// end the frame (see 'end_frame').
+    /// The display's dirty flag is left set if it was, for the frontends
+    /// relying on it.
+    pub fn run_frame(&mut self, instructions_per_frame: u32) -> Result<FrameReport, VmError> {
+        let mut report = FrameReport::default();
+        self.run_frame_with(&mut report, instructions_per_frame, u64::MAX, Vm::step)?;
+        Ok(report)
+    }
+
+    /// Run the frame 'report' is about on, from the cycle it was left at,
+    /// for at most 'max_cycles' CPU cycles : a frame may be run in several
+    /// parts, e.g. to stop in the middle of it. The instructions are run
+    /// by 'step' (e.g. 'Vm::step' or a debugger's), the cycles left once
+    /// the program waits are skipped since nothing changes before the next
+    /// frame. The frame is ended (see 'end_frame') once all its cycles ran.
+    pub fn run_frame_with<E>(
+        &mut self,
+        report: &mut FrameReport,
+        instructions_per_frame: u32,
+        max_cycles: u64,
+        mut step: impl FnMut(&mut Vm) -> Result<StepOutcome, E>,
+    ) -> Result<(), E> {
+        let dirty = self.display.dirty;
+        self.display.dirty = false;
+        let mut cycles = 0;
+        let mut result = Ok(());
+
+        while report.cycles < instructions_per_frame && cycles < max_cycles {
+            match step(self) {
+                Ok(StepOutcome::Executed) => {
+                    report.instructions += 1;
+                    report.cycles += 1;
+                    cycles += 1;
+                }
+                Ok(StepOutcome::Waiting) => {
+                    let left = u64::from(instructions_per_frame - report.cycles).min(max_cycles - cycles);
+                    report.cycles += left as u32;
+                    cycles += left;
+                }
+                Ok(StepOutcome::Finished) => {
+                    report.finished = true;
+                    break;
+                }
+                Err(why) => {
+                    result = Err(why);
+                    break;
+                }
+            }
+        }
+
+        report.display_changed |= self.display.dirty;
+        self.display.dirty |= dirty;
+        report.sound = self.sound_timer > 0;
+        report.waiting_for_key = self.is_waiting_for_key();
+        if result.is_ok() && !report.finished && report.cycles >= instructions_per_frame {
+            self.end_frame();
+            report.ended = true;
+        }
+        result
     }
 
-    fn process_opcode(&mut self, opcode: u16) {
-        self.pc += 2;
+    /// Skip the next instruction, stepping over the 4 bytes long ones
+    /// (XO-CHIP 'F000 NNNN').
+    fn skip_next(&mut self) {
+        let len = if read_word(&self.memory, self.pc) == 0xF000 { 4 } else { 2 };
+        self.pc = self.pc.wrapping_add(len);
+    }
 
-        match opcode {
-            0x00E0 => {
-                self.display.clear()
+    /// Return the memory range of 'len' bytes starting at 'start', or an
+    /// error for the instruction at 'pc' if it goes past the address space.
+    fn memory_range(&self, pc: u16, start: usize, len: usize) -> Result<Range<usize>, VmError> {
+        if start + len > self.memory_end() {
+            return Err(VmError::MemoryOutOfBounds { pc, address: start.max(self.memory_end()) });
+        }
+        Ok(start..start + len)
+    }
+
+    /// Return the size in bytes of the sprite drawn by 'DXYN' : one N rows
+    /// sprite (16x16 if N == 0) per selected XO-CHIP bitplane.
+    fn sprite_len(&self, n: u8) -> usize {
+        let len = if n == 0 { 32 } else { n as usize };
+        len * self.display.planes.count_ones() as usize
+    }
+
+    /// Return the memory ranges the given instruction would read and write
+    /// if executed now, empty if it doesn't access the memory. The ranges
+    /// may go past the end of the memory for a faulty instruction.
+    pub fn memory_access(&self, instruction: Instruction) -> (Range<usize>, Range<usize>) {
+        use crate::disasm::Instruction::*;
+
+        let i = self.i as usize;
+        let none = i..i;
+        match instruction {
+            Draw(_, _, n) => (i..i + self.sprite_len(n), none),
+            Load(x) => (i..i + x as usize + 1, none),
+            LoadRange(x, y) => (i..i + register_range(x as usize, y as usize).count(), none),
+            Audio => (i..i + PATTERN_SIZE, none),
+            Store(x) => (none, i..i + x as usize + 1),
+            SaveRange(x, y) => (none, i..i + register_range(x as usize, y as usize).count()),
+            Bcd(_) => (none, i..i + 3),
+            _ => (none.clone(), none),
+        }
+    }
+
+    /// Decode the instruction at the program counter, reading the operand
+    /// of the 4 bytes long ones.
+    pub fn fetch(&self) -> Result<Instruction, VmError> {
+        let pc = self.pc;
+        self.memory_range(pc, pc as usize, 2)?;
+        let opcode = read_word(&self.memory, pc);
+        let instruction = match decode(opcode) {
+            Err(DecodeError::MissingOperand(_)) => {
+                // the operand wraps around the XO-CHIP 64 KiB memory only
+                if !self.quirks.extended_memory {
+                    self.memory_range(pc, pc as usize + 2, 2)?;
+                }
+                disasm::decode_long(opcode, read_word(&self.memory, pc.wrapping_add(2)))
             }
-            0x00EE => {
-                self.sp = self.sp - 1;
+            result => result,
+        };
+        instruction.map_err(|error| VmError::InvalidOpcode { pc, error })
+    }
+
+    /// Return the end of the memory the program may access : the whole
+    /// memory with the 'extended_memory' quirk, 4 KiB otherwise.
+    fn memory_end(&self) -> usize {
+        if self.quirks.extended_memory {
+            self.memory.len()
+        } else {
+            PROGRAM_END
+        }
+    }
+
+    /// Decode and execute the given opcode, as if it was at the program
+    /// counter.
+    #[cfg(test)]
+    fn process_opcode(&mut self, opcode: u16) -> Result<(), VmError> {
+        let pc = self.pc;
+        let instruction = decode(opcode).map_err(|error| VmError::InvalidOpcode { pc, error })?;
+        self.execute(instruction)
+    }
+
+    /// Execute the given instruction, as if it was at the program counter :
+    /// the program counter is first moved past it. On error the virtual
+    /// machine may be partially modified, see 'step'.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), VmError> {
+        use crate::disasm::Instruction::*;
+
+        let pc = self.pc;
+        self.pc = self.pc.wrapping_add(instruction.size() as u16);
+
+        match instruction {
+            // machine code routines aren't supported
+            Sys(_) => {}
+            Clear => self.display.clear(),
+            ScrollDown(n) => self.display.scroll_down(n as usize),
+            ScrollUp(n) => self.display.scroll_up(n as usize),
+            ScrollRight => self.display.scroll_right(4),
+            ScrollLeft => self.display.scroll_left(4),
+            Exit => self.exited = true,
+            Lores => self.display.set_hires(false),
+            Hires => self.display.set_hires(true),
+            Return => {
+                if self.sp == 0 {
+                    return Err(VmError::StackUnderflow { pc });
+                }
+                self.sp -= 1;
                 self.pc = self.stack[(self.sp) as usize] + 2
             }
-            0x1000..=0x1FFF => {
-                self.pc = arg_nnn!(opcode)
+            Jump(nnn) => self.pc = nnn,
+            Call(nnn) => {
+                if self.sp as usize >= self.stack.len() {
+                    return Err(VmError::StackOverflow { pc });
+                }
+                self.stack[self.sp as usize] = pc;
+                self.sp += 1;
+                self.pc = nnn;
             }
-            0x2000..=0x2FFF => {
-                self.stack[self.sp as usize] = self.pc - 2;
-                self.sp = self.sp + 1;
-                self.pc = arg_nnn!(opcode);
+            SkipEqImm(x, nn) => {
+                if self.v[x as usize] == nn {
+                    self.skip_next()
+                }
             }
-            0x3000..=0x3FFF => {
-                // let nn = arg_nn!(opcode);
-                // let vx = self.v[arg_x!(opcode)];
-                if self.v[arg_x!(opcode)] == arg_nn!(opcode) {
-                    self.pc += 2
+            SkipNeImm(x, nn) => {
+                if self.v[x as usize] != nn {
+                    self.skip_next()
                 }
             }
-            0x4000..=0x4FFF => {
-                if self.v[arg_x!(opcode)] != arg_nn!(opcode) {
-                    self.pc += 2
+            SkipEqReg(x, y) => {
+                if self.v[x as usize] == self.v[y as usize] {
+                    self.skip_next()
                 }
             }
-            0x5000..=0x5FFF => {
-                if self.v[arg_x!(opcode)] == self.v[arg_y!(opcode)] {
-                    self.pc += 2
+            SkipNeReg(x, y) => {
+                if self.v[x as usize] != self.v[y as usize] {
+                    self.skip_next()
                 }
             }
-            0x6000..=0x6FFF => {
-                self.v[arg_x!(opcode)] = arg_nn!(opcode);
-            }
-            0x7000..=0x7FFF => {
-                self.v[arg_x!(opcode)] += arg_nn!(opcode);
-            }
-
-            0x8000..=0x8FFF => {
-                match opcode & 0x000F {
-                    0 => {
-                        self.v[arg_x!(opcode)] = self.v[arg_y!(opcode)]
-                    }
-                    1 => {
-                        self.v[arg_x!(opcode)] |= self.v[arg_y!(opcode)]
-                    }
-                    2 => {
-                        self.v[arg_x!(opcode)] &= self.v[arg_y!(opcode)]
-                    }
-                    3 => {
-                        self.v[arg_x!(opcode)] ^= self.v[arg_y!(opcode)]
-                    }
-                    4 => {
-                        let (res, overflow) = self.v[arg_x!(opcode)].overflowing_add(self.v[arg_y!(opcode)]);
-                        match overflow {
-                            true => self.v[0xF] = 1,
-                            false => self.v[0xF] = 0,
-                        }
-                        self.v[arg_x!(opcode)] = res;
-                    }
-                    5 => {
-                        let (res, overflow) = self.v[arg_x!(opcode)].overflowing_sub(self.v[arg_y!(opcode)]);
-                        match overflow {
-                            true => self.v[0xF] = 0,
-                            false => self.v[0xF] = 1,
-                        }
-                        self.v[arg_x!(opcode)] = res;
-                    }
-                    6 => {
-                        self.v[0xF] = self.v[arg_x!(opcode)] & 0x1;
-                        self.v[arg_x!(opcode)] >>= 1;
-                    }
-                    7 => {
-                        let (res, overflow) = self.v[arg_y!(opcode)].overflowing_sub(self.v[arg_x!(opcode)]);
-                        match overflow {
-                            true => self.v[0xF] = 0,
-                            false => self.v[0xF] = 1,
-                        }
-                        self.v[arg_x!(opcode)] = res;
-                    }
-                    0xE => {
-                        self.v[0xF] = self.v[arg_x!(opcode)] & 0x80;
-                        self.v[arg_x!(opcode)] <<= 1;
-                    }
-                    _ => {}
+            SaveRange(x, y) => {
+                let (x, y) = (x as usize, y as usize);
+                let range = self.memory_range(pc, self.i as usize, register_range(x, y).count())?;
+                for (address, r) in range.zip(register_range(x, y)) {
+                    self.memory[address] = self.v[r];
                 }
             }
-            0x9000..=0x9FF0 => {
-                if self.v[arg_x!(opcode)] != self.v[arg_y!(opcode)] {
-                    self.sp += 2
+            LoadRange(x, y) => {
+                let (x, y) = (x as usize, y as usize);
+                let range = self.memory_range(pc, self.i as usize, register_range(x, y).count())?;
+                for (address, r) in range.zip(register_range(x, y)) {
+                    self.v[r] = self.memory[address];
                 }
             }
-            0xA000..=0xAFFF => {
-                self.i = arg_nnn!(opcode)
-            }
-            0xB000..=0xBFFF => {
-                self.pc = self.v[0] as u16 + arg_nnn!(opcode)
-            }
-            0xC000..=0xCFFF => {
-                let rand: u8 = rand::random::<u8>();
-                self.v[arg_x!(opcode)] = rand & arg_nn!(opcode)
-            }
-            0xD000..=0xDFFF => {
-                let collision = self.display.draw(self.v[arg_x!(opcode)] as usize, self.v[arg_y!(opcode)] as usize,
-                                                  &self.memory[self.i as usize..(self.i + arg_n!(opcode) as u16) as usize]);
-                self.v[0xF] = if collision { 1 } else { 0 };
-            }
-            0xE000..=0xEFFF => {
-                if arg_nn!(opcode) == 0x9E {
-                    self.pc += match self.keypad.get_key_state(self.v[arg_x!(opcode)] as usize) {
-                        Keystate::Pressed => 2,
-                        Keystate::Released => 0,
-                    };
-                } else if arg_nn!(opcode) == 0xA1 {
-                    self.pc += match self.keypad.get_key_state(self.v[arg_x!(opcode)] as usize) {
-                        Keystate::Pressed => 0,
-                        Keystate::Released => 2,
-                    };
+            LoadImm(x, nn) => self.v[x as usize] = nn,
+            AddImm(x, nn) => self.v[x as usize] = self.v[x as usize].wrapping_add(nn),
+            Move(x, y) => self.v[x as usize] = self.v[y as usize],
+            Or(x, y) => {
+                self.v[x as usize] |= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
                 }
             }
-
-            0xF000..=0xFFFF => {
-                match opcode & 0x00FF {
-                    0x07..=0x07 => {
-                        self.v[arg_x!(opcode)] = self.delay_timer
-                    }
-                    0x0A => {
-                        self.wait_for_key = (true, arg_x!(opcode) as u8);
-                        self.pc -= 2;
-                    }
-                    0x15 => {
-                        self.delay_timer = self.v[arg_x!(opcode)]
-                    }
-                    0x18 => {
-                        self.sound_timer = self.v[arg_x!(opcode)]
-                    }
-                    0x1E => {
-                        self.i += self.v[arg_x!(opcode)] as u16
-                    }
-                    0x29 => {
-                        self.i = self.v[arg_x!(opcode)] as u16 * 5
-                    }
-                    0x33 => {
-                        self.memory[self.i as usize] = self.v[arg_x!(opcode)] / 100;
-                        self.memory[self.i as usize + 1] = (self.v[arg_x!(opcode)] / 10) % 10;
-                        self.memory[self.i as usize + 2] = (self.v[arg_x!(opcode)] % 100) % 10;
-                    }
-                    0x55 => {
-                        self.memory[(self.i as usize)..(self.i + arg_x!(opcode) as u16 + 1) as usize]
-                            .copy_from_slice(&self.v[0..(arg_x!(opcode) as usize + 1)])
-                    }
-                    0x65 => {
-                        self.v[0..(arg_x!(opcode) as usize + 1)]
-                            .copy_from_slice(&self.memory[(self.i as usize)..(self.i + arg_x!(opcode) as u16 + 1) as usize])
-                    }
-                    _ => println!("got unknown opcode: {}", opcode)
+            And(x, y) => {
+                self.v[x as usize] &= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
+            }
+            Xor(x, y) => {
+                self.v[x as usize] ^= self.v[y as usize];
+                if self.quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
+            }
+            Add(x, y) => {
+                let (res, overflow) = self.v[x as usize].overflowing_add(self.v[y as usize]);
+                self.v[x as usize] = res;
+                self.v[0xF] = overflow as u8;
+            }
+            Sub(x, y) => {
+                let (res, borrow) = self.v[x as usize].overflowing_sub(self.v[y as usize]);
+                self.v[x as usize] = res;
+                self.v[0xF] = !borrow as u8;
+            }
+            SubReverse(x, y) => {
+                let (res, borrow) = self.v[y as usize].overflowing_sub(self.v[x as usize]);
+                self.v[x as usize] = res;
+                self.v[0xF] = !borrow as u8;
+            }
+            ShiftRight(x, y) => {
+                let src = if self.quirks.shift_uses_vy { y } else { x };
+                let value = self.v[src as usize];
+                self.v[x as usize] = value >> 1;
+                self.v[0xF] = value & 0x1;
+            }
+            ShiftLeft(x, y) => {
+                let src = if self.quirks.shift_uses_vy { y } else { x };
+                let value = self.v[src as usize];
+                self.v[x as usize] = value << 1;
+                self.v[0xF] = value >> 7;
+            }
+            LoadI(nnn) => self.i = nnn,
+            LoadILong(nnnn) => self.i = nnnn,
+            JumpOffset(nnn) => {
+                // with the quirk, BXNN jumps to XNN + VX
+                let x = (nnn >> 8) as usize;
+                let offset = if self.quirks.jump_with_vx { self.v[x] } else { self.v[0] };
+                self.pc = offset as u16 + nnn
+            }
+            Random(x, nn) => self.v[x as usize] = self.rng.next_u8() & nn,
+            Draw(x, y, n) => {
+                // DXY0 draws a 16x16 sprite
+                let (width, height) = match n {
+                    0 => (16, 16),
+                    n => (8, n as usize),
+                };
+                let x = self.v[x as usize] as usize;
+                let y = self.v[y as usize] as usize;
+                let sprite = self.memory_range(pc, self.i as usize, self.sprite_len(n))?;
+                let collided_rows = self.display.draw(x, y, &self.memory[sprite], width, self.quirks.clip_sprites);
+                self.v[0xF] = if self.display.hires && self.quirks.collision_row_count {
+                    // SUPER-CHIP counts the colliding rows, and the rows clipped at the bottom
+                    let y = y % self.display.height();
+                    let clipped_rows = (y + height).saturating_sub(self.display.height());
+                    (collided_rows + clipped_rows) as u8
+                } else if collided_rows > 0 { 1 } else { 0 };
+                self.vblank_wait = self.quirks.display_wait;
+            }
+            SkipKeyPressed(x) => {
+                if self.keypad.get_key_state(self.v[x as usize] as usize & 0xF) == Keystate::Pressed {
+                    self.skip_next()
+                }
+            }
+            SkipKeyReleased(x) => {
+                if self.keypad.get_key_state(self.v[x as usize] as usize & 0xF) == Keystate::Released {
+                    self.skip_next()
                 }
             }
-            _ => {
-                println!("got unknown opcode: {}", opcode)
+            Plane(n) => self.display.select_planes(n),
+            Audio => {
+                let pattern = self.memory_range(pc, self.i as usize, PATTERN_SIZE)?;
+                self.audio_pattern.copy_from_slice(&self.memory[pattern])
+            }
+            GetDelay(x) => self.v[x as usize] = self.delay_timer,
+            WaitKey(x) => {
+                // only the keys pressed from now on end the wait
+                self.keypad.clear_events();
+                self.wait_for_key = (true, x);
+                self.wait_for_key_pressed = None;
+                self.pc = pc;
+            }
+            SetDelay(x) => self.delay_timer = self.v[x as usize],
+            SetSound(x) => self.sound_timer = self.v[x as usize],
+            AddI(x) => self.i = self.i.wrapping_add(self.v[x as usize] as u16),
+            Font(x) => self.i = self.v[x as usize] as u16 * 5,
+            BigFont(x) => self.i = (BIG_FONT_START + self.v[x as usize] as usize * 10) as u16,
+            Pitch(x) => self.pitch = self.v[x as usize],
+            Bcd(x) => {
+                let bcd = self.memory_range(pc, self.i as usize, 3)?;
+                let vx = self.v[x as usize];
+                self.memory[bcd].copy_from_slice(&[vx / 100, (vx / 10) % 10, vx % 10]);
+            }
+            Store(x) => {
+                let x = x as usize;
+                let range = self.memory_range(pc, self.i as usize, x + 1)?;
+                self.memory[range].copy_from_slice(&self.v[0..(x + 1)]);
+                if self.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
+                }
+            }
+            Load(x) => {
+                let x = x as usize;
+                let range = self.memory_range(pc, self.i as usize, x + 1)?;
+                self.v[0..(x + 1)].copy_from_slice(&self.memory[range]);
+                if self.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
+                }
+            }
+            SaveFlags(x) => {
+                let x = x as usize;
+                self.rpl[0..(x + 1)].copy_from_slice(&self.v[0..(x + 1)])
+            }
+            LoadFlags(x) => {
+                let x = x as usize;
+                self.v[0..(x + 1)].copy_from_slice(&self.rpl[0..(x + 1)])
             }
         }
+        Ok(())
     }
 }
 
@@ -368,7 +717,266 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_arg_x() {
-        assert_eq!(arg_x!(0x0100), 0x1);
+    fn test_decode() {
+        assert_eq!(decode(0x8126), Ok(Instruction::ShiftRight(1, 2)));
+        let mut vm = Vm::new();
+        vm.memory[0x200..0x202].copy_from_slice(&[0x51, 0x21]);
+        assert!(matches!(vm.step(), Err(VmError::InvalidOpcode { pc: 0x200, .. })));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_run_frame() {
+        let mut vm = Vm::new();
+        let rom = [
+            0x60, 0x02, // v0 := 2
+            0xF0, 0x18, // buzzer := v0
+            0xF0, 0x15, // delay := v0
+            0xD0, 0x01, // sprite v0 v0 1
+            0xF1, 0x0A, // v1 := key
+            0x00, 0xFD, // exit
+        ];
+        vm.load_rom(&rom).unwrap();
+        vm.display.dirty = false;
+
+        // the display wait quirk ends the frame at the sprite
+        let report = vm.run_frame(10).unwrap();
+        assert_eq!(report, FrameReport { instructions: 4, cycles: 10, sound: true, display_changed: true, ended: true, ..Default::default() });
+        assert_eq!((vm.delay_timer, vm.sound_timer), (1, 1));
+        let report = vm.run_frame(10).unwrap();
+        assert_eq!(report.instructions, 1);
+        assert!(report.sound && report.waiting_for_key && !report.display_changed);
+        assert!(vm.display.dirty);
+        assert_eq!((vm.delay_timer, vm.sound_timer), (0, 0));
+        assert!(!vm.run_frame(10).unwrap().sound);
+
+        vm.keypad.key_event(3, Keystate::Pressed);
+        assert!(vm.run_frame(10).unwrap().waiting_for_key);
+        vm.keypad.key_event(3, Keystate::Released);
+        let report = vm.run_frame(10).unwrap();
+        assert!(report.finished);
+        assert_eq!(report.instructions, 1);
+    }
+
+    #[test]
+    fn test_flags_and_register_skip() {
+        let mut vm = Vm::new();
+        vm.v[1] = 0x81;
+        vm.process_opcode(0x811E).unwrap();
+        assert_eq!(vm.v[1], 0x02);
+        assert_eq!(vm.v[0xF], 1);
+
+        vm.v[2] = 0x03;
+        // 0x202 + 2, and the skipped instruction
+        vm.process_opcode(0x9120).unwrap();
+        assert_eq!(vm.pc, 0x206);
+        assert_eq!(vm.sp, 0);
+    }
+
+    #[test]
+    fn test_shift_quirk() {
+        let mut vm = Vm::with_quirks(Quirks::cosmac_vip());
+        vm.v[1] = 0x01;
+        vm.v[2] = 0x06;
+        vm.process_opcode(0x8126).unwrap();
+        assert_eq!(vm.v[1], 0x03);
+        assert_eq!(vm.v[0xF], 0);
+
+        let mut vm = Vm::with_quirks(Quirks::superchip());
+        vm.v[1] = 0x01;
+        vm.v[2] = 0x06;
+        vm.process_opcode(0x8126).unwrap();
+        assert_eq!(vm.v[1], 0x00);
+        assert_eq!(vm.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_jump_and_load_store_quirks() {
+        let mut vm = Vm::with_quirks(Quirks::superchip());
+        vm.v[0] = 0x10;
+        vm.v[3] = 0x20;
+        vm.process_opcode(0xB300).unwrap();
+        assert_eq!(vm.pc, 0x320);
+
+        let mut vm = Vm::with_quirks(Quirks::cosmac_vip());
+        vm.i = 0x300;
+        vm.process_opcode(0xF255).unwrap();
+        assert_eq!(vm.i, 0x303);
+    }
+
+    #[test]
+    fn test_xochip_long_load_and_ranges() {
+        let mut vm = Vm::with_quirks(Quirks::xochip());
+        vm.memory[0x200..0x206].copy_from_slice(&[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34]);
+        // the skip must step over the whole 4 bytes instruction
+        vm.step().unwrap();
+        assert_eq!(vm.pc, 0x206);
+
+        vm.pc = 0x202;
+        vm.step().unwrap();
+        assert_eq!(vm.i, 0x1234);
+        assert_eq!(vm.pc, 0x206);
+
+        vm.i = 0xE000;
+        vm.v[1..4].copy_from_slice(&[7, 8, 9]);
+        vm.process_opcode(0x5312).unwrap();
+        assert_eq!(vm.memory[0xE000..0xE003], [9, 8, 7]);
+        vm.process_opcode(0x5463).unwrap();
+        assert_eq!(vm.v[4..7], [9, 8, 7]);
+
+        // the last instruction of the 64 KiB memory is executed
+        vm.pc = 0xFFFE;
+        vm.memory[0xFFFE..].copy_from_slice(&[0x60, 0x2A]);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Executed);
+        assert_eq!(vm.v[0], 0x2A);
+    }
+
+    #[test]
+    fn test_xochip_bitplanes() {
+        let mut vm = Vm::with_quirks(Quirks::xochip());
+        // both planes selected : 1 row for the first plane, then 1 for the second
+        vm.process_opcode(0xF301).unwrap();
+        vm.i = 0x300;
+        vm.memory[0x300..0x302].copy_from_slice(&[0xC0, 0x60]);
+        vm.process_opcode(0xD001).unwrap();
+        assert_eq!(vm.display.gfx[0][..3], [0b01, 0b11, 0b10]);
+        assert_eq!(vm.v[0xF], 0);
+
+        // clearing and scrolling only affect the selected plane
+        vm.process_opcode(0xF201).unwrap();
+        vm.process_opcode(0x00FB).unwrap();
+        assert_eq!(vm.display.gfx[0][..7], [0b01, 0b01, 0, 0, 0, 0b10, 0b10]);
+        vm.process_opcode(0x00E0).unwrap();
+        assert_eq!(vm.display.gfx[0][..7], [0b01, 0b01, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_rpl_flags() {
+        let mut vm = Vm::with_quirks(Quirks::superchip());
+        vm.v[..3].copy_from_slice(&[1, 2, 3]);
+        vm.process_opcode(0xF275).unwrap();
+        let path = std::env::temp_dir().join(format!("chip8vm-test-{}.rpl", std::process::id()));
+        vm.save_rpl_flags(&path).unwrap();
+
+        let mut vm = Vm::with_quirks(Quirks::superchip());
+        vm.load_rpl_flags(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        vm.process_opcode(0xF185).unwrap();
+        assert_eq!(vm.v[..3], [1, 2, 0]);
+    }
+
+    #[test]
+    fn test_superchip_hires_sprite() {
+        let mut vm = Vm::with_quirks(Quirks::superchip());
+        vm.process_opcode(0x00FF).unwrap();
+        assert_eq!(vm.display.width(), 128);
+
+        // 16x16 filled sprite, drawn twice at the bottom edge : 4 rows collide
+        // and 12 rows are clipped
+        vm.i = 0x300;
+        for b in vm.memory[0x300..0x320].iter_mut() {
+            *b = 0xFF;
+        }
+        vm.v[1] = 120;
+        vm.v[2] = 60;
+        vm.process_opcode(0xD120).unwrap();
+        assert_eq!(vm.v[0xF], 12);
+        vm.process_opcode(0xD120).unwrap();
+        assert_eq!(vm.v[0xF], 16);
+
+        vm.process_opcode(0xD120).unwrap();
+        vm.process_opcode(0x00C2).unwrap();
+        assert_eq!(vm.display.gfx[61][120], 0);
+        assert_eq!(vm.display.gfx[62][120], 1);
+        assert_eq!(vm.display.gfx[63][127], 1);
+    }
+
+    #[test]
+    fn test_seeded_runs_are_deterministic() {
+        // draw random bytes at random positions
+        let rom = [0xA3, 0x00, 0xC0, 0xFF, 0xC1, 0x3F, 0xC2, 0x1F, 0xF0, 0x55, 0xD1, 0x21, 0x12, 0x02];
+        let run = |seed| {
+            let mut vm = Vm::with_quirks(Quirks::chip48());
+            vm.rng = Rng::new(seed);
+            vm.load_rom(&rom).unwrap();
+            for _ in 0..700 {
+                vm.step().unwrap();
+            }
+            vm.display.gfx
+        };
+        assert!(run(42)[..] == run(42)[..]);
+        assert!(run(42)[..] != run(43)[..]);
+    }
+
+    #[test]
+    fn test_malformed_programs() {
+        let mut vm = Vm::new();
+        assert!(matches!(vm.process_opcode(0x00EE), Err(VmError::StackUnderflow { pc: 0x200 })));
+
+        let mut vm = Vm::new();
+        for _ in 0..16 {
+            vm.process_opcode(0x2200).unwrap();
+        }
+        assert!(matches!(vm.process_opcode(0x2200), Err(VmError::StackOverflow { .. })));
+
+        let mut vm = Vm::new();
+        vm.memory[0x200..0x206].copy_from_slice(&[0xF0, 0x00, 0xFF, 0xFF, 0xF1, 0x55]);
+        vm.step().unwrap();
+        assert!(matches!(vm.step(), Err(VmError::MemoryOutOfBounds { pc: 0x204, .. })));
+        assert_eq!(vm.pc, 0x204);
+
+        let mut vm = Vm::new();
+        assert!(matches!(vm.load_rom(&[0; MEMORY_SIZE]), Err(VmError::RomTooLarge { .. })));
+        assert!(matches!(vm.load_rom(&[0; 0xE01]), Err(VmError::RomTooLarge { size: 0xE01, max: 0xE00 })));
+        assert!(vm.load_rom(&[0; 0xE00]).is_ok());
+        let mut vm = Vm::with_quirks(Quirks::xochip());
+        assert!(vm.load_rom(&[0; 0xE01]).is_ok());
+        // the 4 KiB memory of the CHIP 8 profiles bounds the accesses
+        let mut vm = Vm::with_quirks(Quirks::cosmac_vip());
+        vm.memory[0x200..0x202].copy_from_slice(&[0xF2, 0x55]);
+        vm.i = 0xFFE;
+        assert!(matches!(vm.step(), Err(VmError::MemoryOutOfBounds { pc: 0x200, address: 0x1000 })));
+        vm.pc = 0x1000;
+        assert!(matches!(vm.step(), Err(VmError::MemoryOutOfBounds { pc: 0x1000, .. })));
+        let mut vm = Vm::with_quirks(Quirks::xochip());
+        vm.memory[0x200..0x202].copy_from_slice(&[0xF2, 0x55]);
+        vm.i = 0xFFE;
+        assert!(vm.step().is_ok());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut vm = Vm::new();
+        // v0 += 1 ; overwrite 0x202 with v0 ; return with an empty stack
+        vm.load_rom(&[0x70, 0x01, 0xA2, 0x02, 0xF0, 0x55, 0x00, 0xEE]).unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert!(vm.step().is_err());
+        assert_eq!(vm.memory[0x202], 0x01);
+        vm.reset();
+        assert_eq!(vm.pc, 0x200);
+        assert_eq!(vm.v[0], 0);
+        assert_eq!(vm.memory[0x200..0x204], [0x70, 0x01, 0xA2, 0x02]);
+        assert_eq!(vm.step().unwrap(), StepOutcome::Executed);
+        assert_eq!(vm.v[0], 1);
+    }
+
+    #[test]
+    fn test_reset_during_key_wait() {
+        let mut vm = Vm::with_quirks(Quirks::chip48());
+        // v5 = key ; sound timer = v5
+        vm.load_rom(&[0xF5, 0x0A, 0xF5, 0x18]).unwrap();
+        vm.sound_timer = 10;
+        vm.step().unwrap();
+        assert!(vm.is_waiting_for_key());
+        vm.keypad.key_event(3, Keystate::Pressed);
+        vm.reset();
+        assert!(!vm.is_waiting_for_key());
+        assert_eq!(vm.sound_timer, 0);
+        assert_eq!(vm.keypad.pop_event(), None);
+        // the new run waits again, for a new key press
+        vm.step().unwrap();
+        assert_eq!(vm.step().unwrap(), StepOutcome::Waiting);
+        assert_eq!(vm.v[5], 0);
+    }
+}
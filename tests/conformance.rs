@@ -0,0 +1,296 @@
+//! Conformance tests : CHIP-8 programs run headlessly, their final screen
+//! compared with a golden one checked in 'tests/golden'.
+//!
+//! The self-checking programs of 'tests/roms' are assembled on the fly.
+//! The community test ROMs and their golden screens aren't checked in yet :
+//! their cases are ignored, and fail when run with '--ignored' until the ROM
+//! files are copied to 'tests/roms' and their screens saved (see
+//! 'tests/roms/README.md').
+//!
+//! Run with UPDATE_GOLDEN=1 to write the golden screens of the cases run
+//! instead of comparing them.
+
+use std::cmp;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chip8vm::asm;
+use chip8vm::headless::{KeyPress, Limit, Runner};
+use chip8vm::quirks::Quirks;
+use chip8vm::rng::Rng;
+use chip8vm::screenshot::{self, TextStyle};
+use chip8vm::vm::Vm;
+
+/// The program of a test case, in 'tests/roms'.
+enum Rom {
+    /// An Octo source file.
+    Source(&'static str),
+    /// A ROM file, which may not be checked in.
+    File(&'static str),
+}
+
+struct Case {
+    rom: Rom,
+    /// The name of the golden screen, in 'tests/golden'.
+    golden: &'static str,
+    quirks: Quirks,
+    /// The number of 60 Hz frames run.
+    frames: u64,
+    instructions_per_frame: u32,
+    /// The key presses, in the 'KeyPress::parse_script' syntax.
+    keys: &'static str,
+    /// The bytes written to the memory after loading the ROM, e.g. to choose
+    /// a menu entry beforehand.
+    pokes: &'static [(u16, u8)],
+}
+
+impl Case {
+    fn new(rom: Rom, golden: &'static str, quirks: Quirks) -> Case {
+        Case {
+            rom,
+            golden,
+            quirks,
+            frames: 120,
+            instructions_per_frame: 10,
+            keys: "",
+            pokes: &[],
+        }
+    }
+
+    /// Run the case and compare its final screen with the golden one.
+    fn check(&self) {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let rom = match self.rom {
+            Rom::Source(name) => {
+                let source = fs::read_to_string(dir.join("roms").join(name)).unwrap();
+                match asm::assemble(&source) {
+                    Ok(program) => program.rom,
+                    Err(why) => panic!("{}:{}", name, why),
+                }
+            }
+            Rom::File(name) => match fs::read(dir.join("roms").join(name)) {
+                Ok(rom) => rom,
+                Err(why) => panic!("{} : the ROM \"{}\" isn't in tests/roms ({})", self.golden, name, why),
+            },
+        };
+
+        let mut vm = Vm::with_quirks(self.quirks);
+        vm.rng = Rng::new(0);
+        vm.load_rom(&rom).unwrap();
+        for &(address, value) in self.pokes {
+            vm.memory[address as usize] = value;
+        }
+        let script = KeyPress::parse_script(self.keys).unwrap();
+        let mut runner = Runner::new(vm, self.instructions_per_frame, script);
+        if let Err(why) = runner.run(Limit::Frames(self.frames)) {
+            panic!("{} : {}", self.golden, why);
+        }
+        let screen = screenshot::to_text(&runner.vm.display, TextStyle::Ascii);
+
+        let golden_path: PathBuf = dir.join("golden").join(format!("{}.txt", self.golden));
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&golden_path, &screen).unwrap();
+            return;
+        }
+        let golden = match fs::read_to_string(&golden_path) {
+            Ok(golden) => golden,
+            Err(why) => panic!(
+                "{} : {} (check the screen below, then run with UPDATE_GOLDEN=1 to save it)\n{}",
+                golden_path.display(),
+                why,
+                screen
+            ),
+        };
+        if let Some(diff) = diff(&golden, &screen) {
+            panic!("{} : the screen differs from the golden one\n{}", self.golden, diff);
+        }
+    }
+}
+
+/// Return the rows of the 2 screens which differ, one under the other,
+/// with the differing pixels marked below them ; None if they're the same.
+fn diff(expected: &str, actual: &str) -> Option<String> {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    if expected == actual {
+        return None;
+    }
+
+    let mut rows = String::new();
+    let mut count = 0;
+    for y in 0..cmp::max(expected.len(), actual.len()) {
+        let (e, a) = (expected.get(y).cloned().unwrap_or(""), actual.get(y).cloned().unwrap_or(""));
+        if e == a {
+            continue;
+        }
+        let marks: String = (0..cmp::max(e.len(), a.len()))
+            .map(|x| {
+                if e.as_bytes().get(x) == a.as_bytes().get(x) {
+                    ' '
+                } else {
+                    count += 1;
+                    '^'
+                }
+            })
+            .collect();
+        rows += &format!("row {:2} expected {}\n       actual   {}\n                {}\n", y, e, a, marks);
+    }
+    Some(format!(
+        "{} pixels differ ('.' blank, '#' first plane, '+' second plane, '@' both) :\n{}",
+        count, rows
+    ))
+}
+
+#[test]
+fn test_diff() {
+    assert_eq!(diff("..#\n...\n", "..#\n...\n"), None);
+    let diff = diff("..#\n...\n", "..#\n.#.\n").unwrap();
+    assert!(diff.starts_with("1 pixels differ"));
+    assert!(diff.ends_with("row  1 expected ...\n       actual   .#.\n                 ^ \n"));
+}
+
+#[test]
+fn opcodes_vip() {
+    Case::new(Rom::Source("opcodes.8o"), "opcodes", Quirks::cosmac_vip()).check();
+}
+
+#[test]
+fn opcodes_chip48() {
+    Case::new(Rom::Source("opcodes.8o"), "opcodes", Quirks::chip48()).check();
+}
+
+#[test]
+fn opcodes_schip() {
+    Case::new(Rom::Source("opcodes.8o"), "opcodes", Quirks::superchip()).check();
+}
+
+#[test]
+fn opcodes_xochip() {
+    Case::new(Rom::Source("opcodes.8o"), "opcodes", Quirks::xochip()).check();
+}
+
+#[test]
+fn quirks_vip() {
+    Case {
+        instructions_per_frame: 50,
+        ..Case::new(Rom::Source("quirks.8o"), "quirks_vip", Quirks::cosmac_vip())
+    }
+    .check();
+}
+
+/// CHIP-48 and SUPER-CHIP only differ in high resolution.
+#[test]
+fn quirks_chip48() {
+    Case {
+        instructions_per_frame: 50,
+        ..Case::new(Rom::Source("quirks.8o"), "quirks_schip", Quirks::chip48())
+    }
+    .check();
+}
+
+#[test]
+fn quirks_schip() {
+    Case {
+        instructions_per_frame: 50,
+        ..Case::new(Rom::Source("quirks.8o"), "quirks_schip", Quirks::superchip())
+    }
+    .check();
+}
+
+#[test]
+fn quirks_xochip() {
+    Case {
+        instructions_per_frame: 50,
+        ..Case::new(Rom::Source("quirks.8o"), "quirks_xochip", Quirks::xochip())
+    }
+    .check();
+}
+
+#[test]
+fn keypad() {
+    Case {
+        keys: "5:5:2 30:8:30",
+        ..Case::new(Rom::Source("keypad.8o"), "keypad", Quirks::cosmac_vip())
+    }
+    .check();
+}
+
+#[test]
+fn display_vip() {
+    Case::new(Rom::Source("display.8o"), "display_vip", Quirks::cosmac_vip()).check();
+}
+
+#[test]
+fn display_xochip() {
+    Case::new(Rom::Source("display.8o"), "display_xochip", Quirks::xochip()).check();
+}
+
+#[test]
+fn hires() {
+    Case::new(Rom::Source("hires.8o"), "hires", Quirks::superchip()).check();
+}
+
+// The community test ROMs, ignored until they are checked in with their
+// golden screens
+
+#[test]
+#[ignore = "the Timendus test suite ROM and its golden screen aren't checked in"]
+fn ibm_logo() {
+    Case::new(Rom::File("2-ibm-logo.ch8"), "ibm_logo", Quirks::cosmac_vip()).check();
+}
+
+#[test]
+#[ignore = "the Timendus test suite ROM and its golden screen aren't checked in"]
+fn corax_plus() {
+    Case::new(Rom::File("3-corax+.ch8"), "corax_plus", Quirks::cosmac_vip()).check();
+}
+
+#[test]
+#[ignore = "the Timendus test suite ROM and its golden screen aren't checked in"]
+fn flags() {
+    Case::new(Rom::File("4-flags.ch8"), "flags", Quirks::cosmac_vip()).check();
+}
+
+/// The byte at 0x1FF selects the platform in the menu of the quirks test.
+#[test]
+#[ignore = "the Timendus test suite ROM and its golden screen aren't checked in"]
+fn quirks_rom_vip() {
+    Case {
+        frames: 600,
+        instructions_per_frame: 30,
+        pokes: &[(0x1FF, 1)],
+        ..Case::new(Rom::File("5-quirks.ch8"), "quirks_rom_vip", Quirks::cosmac_vip())
+    }
+    .check();
+}
+
+#[test]
+#[ignore = "the Timendus test suite ROM and its golden screen aren't checked in"]
+fn quirks_rom_schip() {
+    Case {
+        frames: 600,
+        instructions_per_frame: 30,
+        pokes: &[(0x1FF, 2)],
+        ..Case::new(Rom::File("5-quirks.ch8"), "quirks_rom_schip", Quirks::superchip())
+    }
+    .check();
+}
+
+/// The byte at 0x1FF selects the FX0A test in the menu of the keypad test.
+#[test]
+#[ignore = "the Timendus test suite ROM and its golden screen aren't checked in"]
+fn keypad_rom() {
+    Case {
+        keys: "60:5:10",
+        pokes: &[(0x1FF, 3)],
+        ..Case::new(Rom::File("6-keypad.ch8"), "keypad_rom", Quirks::cosmac_vip())
+    }
+    .check();
+}
+
+#[test]
+#[ignore = "the BC_test ROM and its golden screen aren't checked in"]
+fn bc_test() {
+    Case::new(Rom::File("BC_test.ch8"), "bc_test", Quirks::cosmac_vip()).check();
+}